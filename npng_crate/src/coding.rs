@@ -1,27 +1,235 @@
 /// `coding.rs` - internal functions for encoding and decoding
 use std::sync::Arc;
 
-use bincode::config::{legacy, standard};
-use bytes::BytesMut;
-use rayon::prelude::*;
+use crate::compression::CompressMap;
 use crate::error::NPNGError;
+use crate::types::channel::{AuxChannel, ChannelData, ChannelRecord};
+use crate::types::color::ColorTransform;
+use crate::types::document::{Layer, LayerRecord};
 use crate::types::pixel::*;
 use crate::{
-    utils::encode_pixel,
+    AlphaPolicy, Layout, PixelFormat, PixelOrder,
+    layout::{
+        decode_delta, decode_planar, decode_qoi, decode_quadtree, decode_spans, encode_delta,
+        encode_planar, encode_qoi, encode_quadtree, encode_spans,
+    },
+    progressive::encode_progressive,
+    utils::{check_duplicates, check_image_size_f, deserialize, encode_pixel, serialize},
 };
+use bincode::config::{Config, legacy, standard};
+use bytes::{Bytes, BytesMut};
+use rayon::prelude::*;
+
+/// How pixel records are written (from `Config`, recorded in the header and chunks)
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RecordFormat {
+    pub save_alpha: bool,
+    pub varint: bool,
+    pub coords: CoordWidth,
+    pub alpha_policy: AlphaPolicy,
+    pub pixel_format: PixelFormat,
+    pub layout: Layout,
+    pub pixel_order: PixelOrder,
+    pub color_transform: ColorTransform,
+    /// Colors are palette indices (`0xII0000FF`), see [`crate::Quantize`]
+    pub indexed: bool,
+    /// Near-lossless bound, colors are residual codes if non-zero (see `residual.rs`)
+    pub max_error: u8,
+}
+
+impl RecordFormat {
+    /// Whether a pixel of this color is written
+    pub(crate) fn keeps(self, color: u32) -> bool {
+        match self.pixel_format {
+            PixelFormat::Color => self.alpha_policy != AlphaPolicy::Drop || color & 0xFF != 0,
+            PixelFormat::BinaryAlpha | PixelFormat::Shape => color & 0xFF >= 0x80,
+        }
+    }
+
+    /// Color as it is decoded, `None` if the pixel is not written
+    pub(crate) fn decoded_color(self, color: u32) -> Option<u32> {
+        if !self.keeps(color) {
+            return None;
+        }
+        if self.indexed {
+            return Some(color);
+        }
+        Some(match self.pixel_format {
+            PixelFormat::Color
+                if color & 0xFF == 0 && self.alpha_policy == AlphaPolicy::KeepCoverage =>
+            {
+                0
+            }
+            PixelFormat::Color if self.save_alpha => color,
+            PixelFormat::Color | PixelFormat::BinaryAlpha => color | 0xFF,
+            PixelFormat::Shape => 0xFFFFFFFF,
+        })
+    }
+
+    /// Color as it is written (color transform applied), `None` if the pixel is not written
+    pub(crate) fn stored_color(self, color: u32) -> Option<u32> {
+        if self.max_error > 0 {
+            // residual codes of written pixels
+            return Some(color);
+        }
+        let color = self.decoded_color(color)?;
+        Some(match self.pixel_format {
+            PixelFormat::Shape => color,
+            _ => self.color_transform.forward(color),
+        })
+    }
+
+    /// Format of the body: pixels only covered with [`AlphaPolicy::KeepCoverage`] are
+    /// stored apart, without colors (see [`encode_coverage`])
+    pub(crate) fn without_coverage(self) -> RecordFormat {
+        match self.alpha_policy {
+            AlphaPolicy::KeepCoverage => RecordFormat {
+                alpha_policy: AlphaPolicy::Drop,
+                ..self
+            },
+            _ => self,
+        }
+    }
+
+    /// Stored bytes per color
+    pub(crate) fn color_bytes(self) -> usize {
+        if self.indexed {
+            return 1;
+        }
+        match self.pixel_format {
+            PixelFormat::Color if self.save_alpha => 4,
+            PixelFormat::Color | PixelFormat::BinaryAlpha => 3,
+            PixelFormat::Shape => 0,
+        }
+    }
+}
+
+/// Encode and compress the pixels of a `width` x `height` canvas in the layout of `format`.
+///
+/// [`Layout::Auto`] encodes every layout and keeps the smallest result. Layouts that
+/// reorder pixels are only tried if the pixels are sorted (`format.pixel_order`).
+///
+/// # Returns
+/// The format the body was written with (layout and coordinate width) and the body
+pub(crate) fn encode_body(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+    compress_map: &CompressMap,
+    (width, height): (u32, u32),
+) -> Result<(RecordFormat, BytesMut), NPNGError> {
+    let encode =
+        |pixels: &[WidePixel], layout: Layout| -> Result<(RecordFormat, BytesMut), NPNGError> {
+            let format = RecordFormat {
+                layout,
+                coords: match layout {
+                    Layout::Delta | Layout::Qoi => CoordWidth::fit(width, height),
+                    _ => format.coords,
+                },
+                ..format
+            };
+            let encoded = match layout {
+                Layout::Pixels | Layout::Auto => spawn_plain_workers(pixels, format)?,
+                Layout::Spans => encode_spans(pixels, format)?,
+                Layout::Quadtree => encode_quadtree(pixels, format, width, height)?,
+                Layout::Delta => encode_delta(pixels, format)?,
+                Layout::Planar => encode_planar(pixels, format)?,
+                Layout::Qoi => encode_qoi(pixels, format)?,
+                // passes are compressed separately
+                Layout::Progressive => {
+                    return Ok((
+                        format,
+                        encode_progressive(pixels, format, compress_map, (width, height))?,
+                    ));
+                }
+            };
+            Ok((format, compress_map.compress(encoded.into())?.1))
+        };
+
+    match format.layout {
+        Layout::Auto => {
+            let mut best: Option<(RecordFormat, BytesMut)> = None;
+            for layout in Layout::STORED {
+                if layout.reorders() && format.pixel_order == PixelOrder::Preserve {
+                    continue;
+                }
+                let encoded = encode(pixels, layout)?;
+                if best.as_ref().is_none_or(|(_, b)| encoded.1.len() < b.len()) {
+                    best = Some(encoded);
+                }
+            }
+            Ok(best.unwrap())
+        }
+        layout => encode(pixels, layout),
+    }
+}
+
+/// Decode an uncompressed body of a `width` x `height` canvas in the layout of `format`
+pub(crate) fn decode_body(
+    encoded_bytes: BytesMut,
+    format: RecordFormat,
+    (width, height): (u32, u32),
+) -> Result<Vec<WidePixel>, NPNGError> {
+    if format.coords == CoordWidth::U8 && !matches!(format.layout, Layout::Delta | Layout::Qoi) {
+        return Err(NPNGError::Error(
+            "8-bit coordinates are only used by the delta and QOI layouts".to_string(),
+        ));
+    }
+    let mut pixels = match format.layout {
+        Layout::Pixels => spawn_plain_decode_workers(encoded_bytes, format)?,
+        Layout::Delta => decode_delta(&encoded_bytes, format)?,
+        Layout::Planar => decode_planar(&encoded_bytes, format)?,
+        Layout::Qoi => decode_qoi(&encoded_bytes, format)?,
+        Layout::Spans => decode_spans(&encoded_bytes, format, (width, height))?,
+        Layout::Quadtree => {
+            let mut pixels = decode_quadtree(&encoded_bytes, format, width, height)?;
+            pixels.par_sort_unstable_by_key(format.pixel_order.key());
+            pixels
+        }
+        Layout::Progressive => {
+            return Err(NPNGError::Error(
+                "Progressive bodies are decoded by passes".to_string(),
+            ));
+        }
+        Layout::Auto => return Err(NPNGError::Error("Unknown body layout".to_string())),
+    };
+
+    if format.color_transform != ColorTransform::None && format.pixel_format != PixelFormat::Shape {
+        pixels
+            .par_iter_mut()
+            .for_each(|p| p.color = format.color_transform.inverse(p.color));
+    }
+    Ok(pixels)
+}
+
+/// Color transform picked on a sample of the written pixels
+/// (64 evenly spaced runs of 64 consecutive pixels)
+pub(crate) fn pick_color_transform(pixels: &[WidePixel], format: RecordFormat) -> ColorTransform {
+    const RUNS: usize = 64;
+    const RUN_LEN: usize = 64;
+    let step = (pixels.len() / RUNS).max(RUN_LEN);
+    let runs: Vec<Vec<u32>> = pixels
+        .chunks(step)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .take(RUN_LEN)
+                .filter_map(|p| format.decoded_color(p.color))
+                .collect()
+        })
+        .collect();
+    ColorTransform::pick(&runs.iter().map(Vec::as_slice).collect::<Vec<_>>())
+}
 
 pub(crate) fn spawn_plain_workers(
-    pixels: Vec<Pixel>,
-    save_alpha: bool,
-    varint: bool,
+    pixels: &[WidePixel],
+    format: RecordFormat,
 ) -> Result<BytesMut, NPNGError> {
     // 1. Encode pixels in parallel with their indices
     let mut results: Vec<(usize, Vec<u8>)> = pixels
-        .clone()
-        .into_par_iter()
+        .par_iter()
         .enumerate()
         .map(|(i, pixel)| {
-            let encoded = encode_pixel(pixel, save_alpha, varint)?;
+            let encoded = encode_pixel(pixel.clone(), format)?;
             Ok((i, encoded))
         })
         .collect::<Result<Vec<_>, NPNGError>>()?;
@@ -40,9 +248,8 @@ pub(crate) fn spawn_plain_workers(
 
 pub(crate) fn spawn_plain_decode_workers(
     encoded_bytes: BytesMut,
-    save_alpha: bool,
-    varint: bool,
-) -> Result<Vec<Pixel>, NPNGError> {
+    format: RecordFormat,
+) -> Result<Vec<WidePixel>, NPNGError> {
     let data_arc = Arc::new(encoded_bytes);
 
     let data_len = data_arc.len();
@@ -53,20 +260,10 @@ pub(crate) fn spawn_plain_decode_workers(
     while cursor < data_len {
         let slice = &data_arc[cursor..];
 
-        let (pixel, len) = if !varint {
-            if save_alpha {
-                bincode::decode_from_slice::<Pixel, _>(slice, legacy())?
-            } else {
-                let (rgb, len) = bincode::decode_from_slice::<RGBPixel, _>(slice, legacy())?;
-                (Pixel::from(rgb), len)
-            }
+        let (pixel, len) = if !format.varint {
+            decode_pixel(slice, format, legacy())?
         } else {
-            if save_alpha {
-                bincode::decode_from_slice::<Pixel, _>(slice, standard())?
-            } else {
-                let (rgb, len) = bincode::decode_from_slice::<RGBPixel, _>(slice, standard())?;
-                (Pixel::from(rgb), len)
-            }
+            decode_pixel(slice, format, standard())?
         };
 
         pixels.push(pixel);
@@ -76,3 +273,232 @@ pub(crate) fn spawn_plain_decode_workers(
 
     Ok(pixels)
 }
+
+/// Decode one pixel record
+fn decode_pixel<C: Config>(
+    slice: &[u8],
+    format: RecordFormat,
+    config: C,
+) -> Result<(WidePixel, usize), NPNGError> {
+    if format.pixel_format == PixelFormat::Shape {
+        let ((x, y), len) = match format.coords {
+            CoordWidth::U8 | CoordWidth::U16 => {
+                let ((x, y), len) = bincode::decode_from_slice::<(u16, u16), _>(slice, config)?;
+                ((x as u32, y as u32), len)
+            }
+            CoordWidth::U32 => bincode::decode_from_slice::<(u32, u32), _>(slice, config)?,
+        };
+        return Ok((WidePixel::new(x, y, 0xFFFFFFFF), len));
+    }
+    if format.indexed {
+        let ((x, y, index), len) = match format.coords {
+            CoordWidth::U8 | CoordWidth::U16 => {
+                let ((x, y, index), len) =
+                    bincode::decode_from_slice::<(u16, u16, u8), _>(slice, config)?;
+                ((x as u32, y as u32, index), len)
+            }
+            CoordWidth::U32 => bincode::decode_from_slice::<(u32, u32, u8), _>(slice, config)?,
+        };
+        return Ok((
+            WidePixel::new(x, y, u32::from_be_bytes([index, 0, 0, 0xFF])),
+            len,
+        ));
+    }
+    let save_alpha = format.save_alpha && format.pixel_format == PixelFormat::Color;
+    Ok(match (format.coords, save_alpha) {
+        (CoordWidth::U8 | CoordWidth::U16, true) => {
+            let (p, len) = bincode::decode_from_slice::<Pixel, _>(slice, config)?;
+            (WidePixel::from(p), len)
+        }
+        (CoordWidth::U8 | CoordWidth::U16, false) => {
+            let (rgb, len) = bincode::decode_from_slice::<RGBPixel, _>(slice, config)?;
+            (WidePixel::from(Pixel::from(rgb)), len)
+        }
+        (CoordWidth::U32, true) => bincode::decode_from_slice::<WidePixel, _>(slice, config)?,
+        (CoordWidth::U32, false) => {
+            let (rgb, len) = bincode::decode_from_slice::<RGBWidePixel, _>(slice, config)?;
+            (WidePixel::from(rgb), len)
+        }
+    })
+}
+
+/// Format of coverage records: coordinates only
+fn coverage_format(format: RecordFormat) -> RecordFormat {
+    RecordFormat {
+        coords: match format.coords {
+            CoordWidth::U32 => CoordWidth::U32,
+            _ => CoordWidth::U16,
+        },
+        pixel_format: PixelFormat::Shape,
+        layout: Layout::Pixels,
+        color_transform: ColorTransform::None,
+        indexed: false,
+        max_error: 0,
+        ..format
+    }
+}
+
+/// Compressed positions of the fully transparent pixels kept with
+/// [`AlphaPolicy::KeepCoverage`], `None` if there are none
+pub(crate) fn encode_coverage(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+    compress_map: &CompressMap,
+) -> Result<Option<Vec<u8>>, NPNGError> {
+    if format.alpha_policy != AlphaPolicy::KeepCoverage {
+        return Ok(None);
+    }
+    let covered: Vec<WidePixel> = pixels
+        .iter()
+        .filter(|p| p.color & 0xFF == 0)
+        .map(|p| WidePixel::new(p.x, p.y, 0xFFFFFFFF))
+        .collect();
+    if covered.is_empty() {
+        return Ok(None);
+    }
+    let encoded = spawn_plain_workers(&covered, coverage_format(format))?;
+    Ok(Some(compress_map.compress(encoded.into())?.1.to_vec()))
+}
+
+/// Decompress stored coverage, pixels are decoded as `0x00000000`
+pub(crate) fn decode_coverage(
+    data: Vec<u8>,
+    format: RecordFormat,
+    encoding_format: &str,
+    compress_map: &CompressMap,
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let uncompressed = compress_map.decompress(Bytes::from(data), encoding_format)?;
+    let mut pixels = spawn_plain_decode_workers(uncompressed, coverage_format(format))?;
+    pixels.iter_mut().for_each(|p| p.color = 0);
+    Ok(pixels)
+}
+
+/// Encode and compress the pixels of each layer (16-bit coordinates)
+pub(crate) fn encode_layers(
+    layers: &[Layer],
+    format: RecordFormat,
+    compress_map: &CompressMap,
+) -> Result<Vec<LayerRecord>, NPNGError> {
+    layers
+        .iter()
+        .map(|layer| {
+            let pixels: Vec<WidePixel> =
+                layer.pixels.iter().cloned().map(WidePixel::from).collect();
+            let (width, height) = check_image_size_f(&pixels)?;
+            check_duplicates(&pixels, width, height)?;
+
+            let format = RecordFormat {
+                coords: CoordWidth::U16,
+                layout: Layout::Pixels,
+                color_transform: ColorTransform::None,
+                indexed: false,
+                max_error: 0,
+                ..format
+            };
+            let encoded = spawn_plain_workers(&pixels, format.without_coverage())?;
+            let (_, data) = compress_map.compress(encoded.into())?;
+            Ok(LayerRecord {
+                name: layer.name.clone(),
+                offset: layer.offset,
+                visible: layer.visible,
+                opacity: layer.opacity,
+                blend_mode: layer.blend_mode,
+                data: data.to_vec(),
+                coverage: encode_coverage(&pixels, format, compress_map)?,
+            })
+        })
+        .collect()
+}
+
+/// Decompress and decode stored layers
+pub(crate) fn decode_layers(
+    records: Vec<LayerRecord>,
+    format: RecordFormat,
+    encoding_format: &str,
+    compress_map: &CompressMap,
+) -> Result<Vec<Layer>, NPNGError> {
+    records
+        .into_iter()
+        .map(|record| {
+            let uncompressed =
+                compress_map.decompress(Bytes::from(record.data), encoding_format)?;
+            let format = RecordFormat {
+                coords: CoordWidth::U16,
+                layout: Layout::Pixels,
+                color_transform: ColorTransform::None,
+                indexed: false,
+                max_error: 0,
+                ..format
+            };
+            let mut pixels = spawn_plain_decode_workers(uncompressed, format)?;
+            if let Some(coverage) = record.coverage {
+                pixels.extend(decode_coverage(
+                    coverage,
+                    format,
+                    encoding_format,
+                    compress_map,
+                )?);
+            }
+            let (width, height) = check_image_size_f(&pixels)?;
+            check_duplicates(&pixels, width, height)?;
+            Ok(Layer {
+                name: record.name,
+                pixels: pixels
+                    .into_iter()
+                    .map(Pixel::try_from)
+                    .collect::<Result<_, _>>()?,
+                offset: record.offset,
+                visible: record.visible,
+                opacity: record.opacity,
+                blend_mode: record.blend_mode,
+            })
+        })
+        .collect()
+}
+
+/// Store the channel values of the pixels at `indices` (output order)
+pub(crate) fn encode_channels(
+    channels: &[AuxChannel],
+    indices: &[usize],
+    compress_map: &CompressMap,
+) -> Result<ChannelRecord, NPNGError> {
+    let data: Vec<ChannelData> = channels.iter().map(|c| c.select(indices).data).collect();
+    let (_, data) = compress_map.compress(serialize(data, false)?.into())?;
+    Ok(ChannelRecord {
+        channels: channels
+            .iter()
+            .map(|c| (c.name.clone(), c.kind(), c.components))
+            .collect(),
+        data: data.to_vec(),
+    })
+}
+
+/// Read stored channels of an image with `pixels` pixels
+pub(crate) fn decode_channels(
+    record: ChannelRecord,
+    pixels: usize,
+    encoding_format: &str,
+    compress_map: &CompressMap,
+) -> Result<Vec<AuxChannel>, NPNGError> {
+    let uncompressed = compress_map.decompress(Bytes::from(record.data), encoding_format)?;
+    let data: Vec<ChannelData> = deserialize(uncompressed.to_vec(), false)?;
+    if data.len() != record.channels.len() {
+        return Err(NPNGError::Error("Channel count mismatch".to_string()));
+    }
+
+    record
+        .channels
+        .into_iter()
+        .zip(data)
+        .map(|((name, kind, components), data)| {
+            let channel = AuxChannel::with_data(name, components, data)?;
+            if channel.kind() != kind || channel.len() != pixels {
+                return Err(NPNGError::Error(format!(
+                    "Channel `{}` does not match its declaration",
+                    channel.name
+                )));
+            }
+            Ok(channel)
+        })
+        .collect()
+}
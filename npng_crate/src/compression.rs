@@ -6,17 +6,25 @@ use std::{
 use bytes::{Bytes, BytesMut};
 use flate2::{Compression, read::ZlibDecoder, write::ZlibEncoder};
 
-use crate::{error::NPNGCompressingError};
+use crate::error::NPNGCompressingError;
 use crate::error::NPNGError;
+use crate::rans::{rans_compress, rans_decompress};
+use crate::types::MAX_PIXELS;
+
+/// Largest rANS output: every pixel of the largest canvas with the largest per-pixel
+/// record (an `f32` channel with 4 components)
+const MAX_RANS_LEN: usize = MAX_PIXELS.saturating_mul(16);
+
+/// Compressor function: `(data, level) -> compressed data`
+pub type CompressFn = fn(Bytes, u32) -> Result<BytesMut, NPNGCompressingError>;
+
+/// Decompressor function: `(data, level) -> decompressed data`
+pub type DecompressFn = fn(Bytes, Option<u32>) -> Result<BytesMut, NPNGCompressingError>;
 
 #[derive(Clone, Debug)]
 pub struct CompressMap {
-    decompressors:
-        HashMap<String, fn(Bytes, Option<u32>) -> Result<BytesMut, NPNGCompressingError>>,
-    compressor: (
-        String,
-        fn(Bytes, u32) -> Result<BytesMut, NPNGCompressingError>,
-    ),
+    decompressors: HashMap<String, DecompressFn>,
+    compressor: (String, CompressFn),
     level: u32, // compression level
 }
 
@@ -46,7 +54,7 @@ impl CompressMap {
     pub fn set_compressor(
         &mut self,
         name: String,
-        compressor: fn(Bytes, u32) -> Result<BytesMut, NPNGCompressingError>,
+        compressor: CompressFn,
     ) -> Result<(), NPNGError> {
         if name.is_empty() || !name.is_ascii() || name.len() > 255 {
             return Err(NPNGError::Error(
@@ -60,7 +68,7 @@ impl CompressMap {
     pub fn add_decompressor(
         &mut self,
         name: String,
-        decompressor: fn(Bytes, Option<u32>) -> Result<BytesMut, NPNGCompressingError>,
+        decompressor: DecompressFn,
     ) -> Result<(), NPNGError> {
         if name.is_empty() || !name.is_ascii() || name.len() > 255 {
             return Err(NPNGError::Error(
@@ -77,11 +85,7 @@ impl CompressMap {
         Ok((name.clone(), compressed))
     }
 
-    pub fn decompress(
-        &self,
-        data: Bytes,
-        decompressor: &str,
-    ) -> Result<BytesMut, NPNGError> {
+    pub fn decompress(&self, data: Bytes, decompressor: &str) -> Result<BytesMut, NPNGError> {
         let func = self
             .decompressors
             .get(decompressor)
@@ -135,6 +139,19 @@ impl CompressMap {
             .map_err(|e| NPNGCompressingError::DecompressingError(e.to_string()))
     }
 
+    fn __rans_compress(data: Bytes, _level: u32) -> Result<BytesMut, NPNGCompressingError> {
+        Ok(BytesMut::from(rans_compress(&data).as_slice()))
+    }
+
+    fn __rans_decompress(
+        data: Bytes,
+        _level: Option<u32>,
+    ) -> Result<BytesMut, NPNGCompressingError> {
+        rans_decompress(&data, MAX_RANS_LEN)
+            .map(|d| BytesMut::from(d.as_slice()))
+            .map_err(|e| NPNGCompressingError::DecompressingError(e.to_string()))
+    }
+
     fn __xor_encoder(data: Bytes, key: u32) -> Result<BytesMut, NPNGCompressingError> {
         let key_bytes = key.to_le_bytes();
         let key_len = key_bytes.len();
@@ -194,6 +211,20 @@ impl CompressMap {
         s
     }
 
+    /// Built-in rANS coder with a byte-stride predictor for fixed-size records (no level)
+    pub fn rans() -> Self {
+        let mut s = Self {
+            decompressors: HashMap::new(),
+            compressor: ("plain".to_string(), Self::__plain_compress),
+            level: 0,
+        };
+        s.add_decompressor("rans".to_string(), Self::__rans_decompress)
+            .unwrap();
+        s.set_compressor("rans".to_string(), Self::__rans_compress)
+            .unwrap();
+        s
+    }
+
     pub fn add_zlib_decompress(&mut self) {
         let _ = self.add_decompressor("zlib".to_string(), Self::__zlib_decompress);
     }
@@ -202,6 +233,10 @@ impl CompressMap {
         let _ = self.add_decompressor("zstd".to_string(), Self::__zstd_decompress);
     }
 
+    pub fn add_rans_decompress(&mut self) {
+        let _ = self.add_decompressor("rans".to_string(), Self::__rans_decompress);
+    }
+
     pub fn set_zlib_compress(&mut self, level: u32) {
         self.set_level(level);
         let _ = self.set_compressor("zlib".to_string(), Self::__zlib_compress);
@@ -212,6 +247,11 @@ impl CompressMap {
         let _ = self.set_compressor("zstd".to_string(), Self::__zstd_compress);
     }
 
+    pub fn set_rans_compress(&mut self) {
+        self.set_level(0);
+        let _ = self.set_compressor("rans".to_string(), Self::__rans_compress);
+    }
+
     pub fn set_plain_compress(&mut self) {
         self.set_level(0);
         let _ = self.set_compressor("plain".to_string(), Self::__plain_compress);
@@ -256,6 +296,7 @@ impl CompressMap {
     pub fn add_default_decompressors(&mut self) {
         self.add_zlib_decompress();
         self.add_zstd_decompress();
+        self.add_rans_decompress();
     }
 }
 
@@ -266,7 +307,7 @@ pub(crate) fn spawn_zlib_compress(uncompressed: Bytes, level: u32) -> Result<Byt
 
     let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
     encoder
-        .write_all(&uncompressed.to_vec())
+        .write_all(&uncompressed)
         .map_err(|e| NPNGError::Error(format!("Zlib write failed: {}", e)))?;
     let compressed = encoder
         .finish()
@@ -294,7 +335,7 @@ pub(crate) fn spawn_zstd_compress(uncompressed: Bytes, level: u32) -> Result<Byt
 
     let mut encoder = zstd::Encoder::new(Vec::new(), level as i32)?;
     encoder
-        .write_all(&uncompressed.to_vec())
+        .write_all(&uncompressed)
         .map_err(|e| NPNGError::Error(format!("Zstd write failed: {}", e)))?;
     let compressed = encoder
         .finish()
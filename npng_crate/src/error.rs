@@ -22,7 +22,10 @@ pub enum NPNGError {
     Compression(#[from] NPNGCompressingError),
 
     #[error("Found pixel duplicate on x:{0} y:{1}")]
-    DuplicatePixel(u16, u16), // Position
+    DuplicatePixel(u32, u32), // Position
+
+    #[error("Pixel x:{0} y:{1} is outside the {2}x{3} canvas")]
+    PixelOutOfBounds(u32, u32, u32, u32), // Position, canvas size
 
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
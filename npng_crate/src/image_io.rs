@@ -0,0 +1,305 @@
+/// `image_io.rs` - reading and writing standard image files together with their metadata
+use std::{
+    ffi::OsStr,
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+use image::{
+    DynamicImage, ImageBuffer, ImageDecoder, ImageEncoder, ImageFormat, ImageReader, RgbImage,
+    RgbaImage, buffer::ConvertBuffer, codecs::jpeg::JpegEncoder,
+};
+use png::{BitDepth, ColorType};
+
+use crate::error::NPNGError;
+use crate::types::color::ColorSpace;
+use crate::types::metadata::{Metadata, TextEntry};
+
+/// iTXt keyword used for XMP packets in PNG files
+const XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+/// APP1 namespace used for XMP packets in JPEG files
+const XMP_JPEG_NAMESPACE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Open and decode an image file.
+///
+/// ICC profile, EXIF, XMP and PNG text chunks of the image are carried over
+/// into `metadata`, unless the caller already set them.
+pub(crate) fn read_image<P: AsRef<OsStr>>(
+    input: P,
+    metadata: &mut Metadata,
+) -> Result<DynamicImage, NPNGError> {
+    let path = Path::new(&input);
+    let reader = ImageReader::open(path)
+        .map_err(|e| NPNGError::Error(format!("Failed to open image: {}", e)))?
+        .with_guessed_format()
+        .map_err(|e| NPNGError::Error(format!("Failed to guess image format: {}", e)))?;
+
+    let (img, source) = if reader.format() == Some(ImageFormat::Png) {
+        read_png(path)?
+    } else {
+        read_other(reader)?
+    };
+    let SourceMetadata {
+        icc,
+        exif,
+        xmp,
+        text,
+    } = source;
+
+    if let Some(icc) = icc
+        && metadata.color_space == ColorSpace::Srgb
+    {
+        metadata.color_space = ColorSpace::Icc(icc);
+    }
+    if metadata.exif.is_none() {
+        metadata.exif = exif;
+    }
+    if metadata.xmp.is_none() {
+        metadata.xmp = xmp;
+    }
+    if metadata.text.is_empty() {
+        metadata.text = text;
+    }
+
+    Ok(img)
+}
+
+/// Metadata read together with the pixels of an image file
+struct SourceMetadata {
+    icc: Option<Vec<u8>>,
+    exif: Option<Vec<u8>>,
+    xmp: Option<Vec<u8>>,
+    text: Vec<TextEntry>,
+}
+
+/// Decode a non-PNG image with the `image` decoder (no text entries)
+fn read_other<R: std::io::BufRead + std::io::Seek>(
+    reader: ImageReader<R>,
+) -> Result<(DynamicImage, SourceMetadata), NPNGError> {
+    let mut decoder = reader
+        .into_decoder()
+        .map_err(|e| NPNGError::Error(format!("Failed to decode image: {}", e)))?;
+
+    let icc = decoder
+        .icc_profile()
+        .map_err(|e| NPNGError::Error(format!("Failed to read ICC profile: {}", e)))?;
+    let exif = decoder
+        .exif_metadata()
+        .map_err(|e| NPNGError::Error(format!("Failed to read EXIF: {}", e)))?;
+    let xmp = decoder
+        .xmp_metadata()
+        .map_err(|e| NPNGError::Error(format!("Failed to read XMP: {}", e)))?;
+
+    let img = DynamicImage::from_decoder(decoder)
+        .map_err(|e| NPNGError::Error(format!("Failed to decode image: {}", e)))?;
+    Ok((
+        img,
+        SourceMetadata {
+            icc,
+            exif,
+            xmp,
+            text: Vec::new(),
+        },
+    ))
+}
+
+/// Decode a PNG file in one pass: pixels, ICC profile, EXIF, XMP and
+/// tEXt/zTXt/iTXt chunks (also those after the image data)
+fn read_png(path: &Path) -> Result<(DynamicImage, SourceMetadata), NPNGError> {
+    let png_err =
+        |e: png::DecodingError| NPNGError::Error(format!("Failed to decode image: {}", e));
+
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_ignore_text_chunk(false);
+    // bit depths below 8 and palettes are expanded, 16-bit channels are kept
+    decoder.set_transformations(png::Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(png_err)?;
+    let mut buf = vec![
+        0;
+        reader
+            .output_buffer_size()
+            .ok_or_else(|| NPNGError::Error("PNG image is too large".to_string()))?
+    ];
+    let frame = reader.next_frame(&mut buf).map_err(png_err)?;
+    buf.truncate(frame.buffer_size());
+    reader.finish().map_err(png_err)?;
+
+    let (width, height) = (frame.width, frame.height);
+    let wide = |buf: &[u8]| -> Vec<u16> {
+        buf.chunks_exact(2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect()
+    };
+    let img = match (frame.color_type, frame.bit_depth) {
+        (ColorType::Grayscale, BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLuma8)
+        }
+        (ColorType::GrayscaleAlpha, BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageLumaA8)
+        }
+        (ColorType::Rgb, BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgb8)
+        }
+        (ColorType::Rgba, BitDepth::Eight) => {
+            ImageBuffer::from_raw(width, height, buf).map(DynamicImage::ImageRgba8)
+        }
+        (ColorType::Grayscale, BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, height, wide(&buf)).map(DynamicImage::ImageLuma16)
+        }
+        (ColorType::GrayscaleAlpha, BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, height, wide(&buf)).map(DynamicImage::ImageLumaA16)
+        }
+        (ColorType::Rgb, BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, height, wide(&buf)).map(DynamicImage::ImageRgb16)
+        }
+        (ColorType::Rgba, BitDepth::Sixteen) => {
+            ImageBuffer::from_raw(width, height, wide(&buf)).map(DynamicImage::ImageRgba16)
+        }
+        _ => None,
+    }
+    .ok_or_else(|| NPNGError::Error("Unsupported PNG color type".to_string()))?;
+
+    let info = reader.info();
+    let mut text = Vec::new();
+    let mut xmp = None;
+    for c in &info.uncompressed_latin1_text {
+        text.push(TextEntry::new(c.keyword.clone(), c.text.clone()));
+    }
+    for c in &info.compressed_latin1_text {
+        text.push(TextEntry::new(
+            c.keyword.clone(),
+            c.get_text().map_err(png_err)?,
+        ));
+    }
+    for c in &info.utf8_text {
+        if c.keyword == XMP_KEYWORD {
+            xmp = Some(c.get_text().map_err(png_err)?.into_bytes());
+        } else {
+            text.push(TextEntry::new(
+                c.keyword.clone(),
+                c.get_text().map_err(png_err)?,
+            ));
+        }
+    }
+
+    Ok((
+        img,
+        SourceMetadata {
+            icc: info.icc_profile.as_ref().map(|c| c.to_vec()),
+            exif: info.exif_metadata.as_ref().map(|c| c.to_vec()),
+            xmp,
+            text,
+        },
+    ))
+}
+
+/// Save an image buffer.
+///
+/// PNG outputs get the ICC profile, EXIF, XMP and text entries of `metadata`,
+/// JPEG outputs get the ICC profile, EXIF and XMP. Other formats are saved as is.
+pub(crate) fn save_image(
+    buffer: &RgbaImage,
+    path: &Path,
+    metadata: &Metadata,
+) -> Result<(), NPNGError> {
+    match ImageFormat::from_path(path).ok() {
+        Some(ImageFormat::Png) => save_png(buffer, path, metadata),
+        Some(ImageFormat::Jpeg) => save_jpeg(buffer, path, metadata),
+        _ => buffer
+            .save(path)
+            .map_err(|e| NPNGError::Error(format!("Failed to save image: {}", e))),
+    }
+}
+
+fn save_png(buffer: &RgbaImage, path: &Path, metadata: &Metadata) -> Result<(), NPNGError> {
+    let png_err = |e: png::EncodingError| NPNGError::Error(format!("Failed to save image: {}", e));
+
+    let mut info = png::Info::with_size(buffer.width(), buffer.height());
+    info.color_type = png::ColorType::Rgba;
+    info.bit_depth = png::BitDepth::Eight;
+    info.icc_profile = metadata.color_space.icc_profile()?.map(Into::into);
+    info.exif_metadata = metadata.exif.clone().map(Into::into);
+
+    let mut encoder =
+        png::Encoder::with_info(BufWriter::new(File::create(path)?), info).map_err(png_err)?;
+    for entry in &metadata.text {
+        // tEXt is Latin-1 only
+        if entry.text.chars().all(|c| (c as u32) < 0x100)
+            && entry.keyword.chars().all(|c| (c as u32) < 0x100)
+        {
+            encoder
+                .add_text_chunk(entry.keyword.clone(), entry.text.clone())
+                .map_err(png_err)?;
+        } else {
+            encoder
+                .add_itxt_chunk(entry.keyword.clone(), entry.text.clone())
+                .map_err(png_err)?;
+        }
+    }
+    if let Some(xmp) = &metadata.xmp {
+        let xmp = String::from_utf8(xmp.clone())
+            .map_err(|_| NPNGError::Error("XMP packet is not valid UTF-8".to_string()))?;
+        encoder
+            .add_itxt_chunk(XMP_KEYWORD.to_string(), xmp)
+            .map_err(png_err)?;
+    }
+
+    let mut writer = encoder.write_header().map_err(png_err)?;
+    writer.write_image_data(buffer.as_raw()).map_err(png_err)?;
+    writer.finish().map_err(png_err)
+}
+
+fn save_jpeg(buffer: &RgbaImage, path: &Path, metadata: &Metadata) -> Result<(), NPNGError> {
+    let mut jpeg = Vec::new();
+    let mut encoder = JpegEncoder::new(&mut jpeg);
+    if let Some(icc) = metadata.color_space.icc_profile()? {
+        encoder
+            .set_icc_profile(icc)
+            .map_err(|e| NPNGError::Error(format!("Failed to set ICC profile: {}", e)))?;
+    }
+    if let Some(exif) = &metadata.exif {
+        encoder
+            .set_exif_metadata(exif.clone())
+            .map_err(|e| NPNGError::Error(format!("Failed to set EXIF: {}", e)))?;
+    }
+    // JPEG has no alpha channel
+    let rgb: RgbImage = buffer.convert();
+    rgb.write_with_encoder(encoder)
+        .map_err(|e| NPNGError::Error(format!("Failed to save image: {}", e)))?;
+
+    if let Some(xmp) = &metadata.xmp {
+        jpeg = insert_jpeg_xmp(jpeg, xmp)?;
+    }
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&jpeg)?;
+    file.flush()?;
+    Ok(())
+}
+
+/// Insert an XMP APP1 segment after the JFIF (APP0) segment
+fn insert_jpeg_xmp(jpeg: Vec<u8>, xmp: &[u8]) -> Result<Vec<u8>, NPNGError> {
+    let segment_len = 2 + XMP_JPEG_NAMESPACE.len() + xmp.len();
+    if segment_len > u16::MAX as usize {
+        return Err(NPNGError::Error(
+            "XMP packet is too large for a JPEG segment".to_string(),
+        ));
+    }
+
+    // SOI, then an optional APP0 segment
+    let mut pos = 2;
+    if jpeg.len() > 6 && jpeg[2..4] == [0xFF, 0xE0] {
+        pos = 4 + u16::from_be_bytes([jpeg[4], jpeg[5]]) as usize;
+    }
+
+    let mut out = Vec::with_capacity(jpeg.len() + segment_len + 2);
+    out.extend_from_slice(&jpeg[..pos]);
+    out.extend_from_slice(&[0xFF, 0xE1]);
+    out.extend_from_slice(&(segment_len as u16).to_be_bytes());
+    out.extend_from_slice(XMP_JPEG_NAMESPACE);
+    out.extend_from_slice(xmp);
+    out.extend_from_slice(&jpeg[pos..]);
+    Ok(out)
+}
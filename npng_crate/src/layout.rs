@@ -0,0 +1,780 @@
+use crate::coding::RecordFormat;
+use crate::error::NPNGError;
+use crate::types::MAX_PIXELS;
+use crate::types::pixel::{CoordWidth, WidePixel};
+use crate::utils::{deserialize_prefix, morton, serialize};
+/// `layout.rs` - body layouts other than one record per pixel
+use bytes::BytesMut;
+
+/// Span of pixels sharing one color
+const SPAN_RUN: u8 = 0;
+/// Span of pixels with their own colors
+const SPAN_LITERAL: u8 = 1;
+
+/// Shortest run of one color written as [`SPAN_RUN`]
+const MIN_RUN: usize = 3;
+
+/// Encode pixels as horizontal spans.
+///
+/// Horizontally adjacent pixels of a row (in output order) are split into runs of
+/// one color and literal runs. Each span is `(kind, y, x_start, length)` in the record
+/// coordinate width, followed by one color (run) or `length` colors (literal) of
+/// [`RecordFormat::color_bytes`] bytes.
+pub(crate) fn encode_spans(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+) -> Result<BytesMut, NPNGError> {
+    let pixels: Vec<(u32, u32, u32)> = pixels
+        .iter()
+        .filter_map(|p| format.stored_color(p.color).map(|c| (p.x, p.y, c)))
+        .collect();
+
+    let mut buf = BytesMut::new();
+    let mut start = 0;
+    while start < pixels.len() {
+        // horizontally adjacent pixels
+        let (_, y, _) = pixels[start];
+        let mut end = start + 1;
+        while end < pixels.len()
+            && pixels[end].1 == y
+            && pixels[end - 1].0.checked_add(1) == Some(pixels[end].0)
+        {
+            end += 1;
+        }
+
+        let segment = &pixels[start..end];
+        let mut literal = 0;
+        let mut i = 0;
+        while i < segment.len() {
+            let run = segment[i..]
+                .iter()
+                .take_while(|p| p.2 == segment[i].2)
+                .count();
+            if run >= MIN_RUN {
+                write_span(&mut buf, SPAN_LITERAL, &segment[literal..i], format)?;
+                write_span(&mut buf, SPAN_RUN, &segment[i..i + run], format)?;
+                literal = i + run;
+            }
+            i += run;
+        }
+        write_span(&mut buf, SPAN_LITERAL, &segment[literal..], format)?;
+
+        start = end;
+    }
+    Ok(buf)
+}
+
+/// Write a span, split into spans of at most `u16::MAX` pixels with 16-bit coordinates
+fn write_span(
+    buf: &mut BytesMut,
+    kind: u8,
+    pixels: &[(u32, u32, u32)],
+    format: RecordFormat,
+) -> Result<(), NPNGError> {
+    let max_len = match format.coords {
+        CoordWidth::U8 | CoordWidth::U16 => u16::MAX as usize,
+        CoordWidth::U32 => u32::MAX as usize,
+    };
+    for span in pixels.chunks(max_len) {
+        let (x, y, _) = span[0];
+        let len = span.len() as u32;
+        buf.extend(match format.coords {
+            CoordWidth::U8 | CoordWidth::U16 => {
+                serialize((kind, y as u16, x as u16, len as u16), format.varint)?
+            }
+            CoordWidth::U32 => serialize((kind, y, x, len), format.varint)?,
+        });
+
+        let colors = if kind == SPAN_RUN { &span[..1] } else { span };
+        for &(_, _, color) in colors {
+            buf.extend_from_slice(&color.to_be_bytes()[..format.color_bytes()]);
+        }
+    }
+    Ok(())
+}
+
+/// Decode spans written by [`encode_spans`]
+pub(crate) fn decode_spans(
+    bytes: &[u8],
+    format: RecordFormat,
+    (width, height): (u32, u32),
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let color_bytes = format.color_bytes();
+    let mut pixels = Vec::new();
+    let mut cursor = 0;
+
+    while cursor < bytes.len() {
+        let ((kind, y, x, len), read) = match format.coords {
+            CoordWidth::U8 | CoordWidth::U16 => {
+                let ((kind, y, x, len), read): ((u8, u16, u16, u16), usize) =
+                    deserialize_prefix(&bytes[cursor..], format.varint)?;
+                ((kind, y as u32, x as u32, len as u32), read)
+            }
+            CoordWidth::U32 => {
+                deserialize_prefix::<(u8, u32, u32, u32)>(&bytes[cursor..], format.varint)?
+            }
+        };
+        cursor += read;
+
+        // checked before the pixels are pushed, a run of a few bytes can claim any length
+        if len == 0 || y >= height || x as u64 + len as u64 > width as u64 {
+            return Err(NPNGError::Error(format!(
+                "Pixel span x:{x} y:{y} len:{len} is outside the {width}x{height} canvas"
+            )));
+        }
+        let len = len as usize;
+        if pixels.len() + len > MAX_PIXELS {
+            return Err(NPNGError::Error("Invalid pixel span".to_string()));
+        }
+        let colors = match kind {
+            SPAN_RUN => 1,
+            SPAN_LITERAL => len,
+            _ => return Err(NPNGError::Error(format!("Unknown span kind {}", kind))),
+        };
+        let data = bytes
+            .get(cursor..cursor + colors * color_bytes)
+            .ok_or_else(|| NPNGError::Error("Truncated pixel span".to_string()))?;
+        cursor += data.len();
+
+        for i in 0..len {
+            let color = if kind == SPAN_RUN {
+                read_color(data, color_bytes)
+            } else {
+                read_color(&data[i * color_bytes..], color_bytes)
+            };
+            pixels.push(WidePixel::new(x + i as u32, y, color));
+        }
+    }
+    Ok(pixels)
+}
+
+/// Stored color of `color_bytes` bytes as `0xRRGGBBAA`
+fn read_color(data: &[u8], color_bytes: usize) -> u32 {
+    match color_bytes {
+        4 => u32::from_be_bytes([data[0], data[1], data[2], data[3]]),
+        3 => u32::from_be_bytes([data[0], data[1], data[2], 0xFF]),
+        1 => u32::from_be_bytes([data[0], 0, 0, 0xFF]),
+        _ => 0xFFFFFFFF,
+    }
+}
+
+/// Quadtree node without pixels
+const NODE_EMPTY: u8 = 0;
+/// Quadtree node with every pixel of the canvas part it covers
+const NODE_FULL: u8 = 1;
+/// Quadtree node split into four children
+const NODE_MIXED: u8 = 2;
+
+/// Side of the root node: the smallest power of two covering the canvas
+fn root_side(width: u32, height: u32) -> u64 {
+    (width.max(height) as u64).next_power_of_two()
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    bits: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u8, n: usize) {
+        for i in (0..n).rev() {
+            if self.bits.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            if (value >> i) & 1 == 1 {
+                *self.bytes.last_mut().unwrap() |= 0x80 >> (self.bits % 8);
+            }
+            self.bits += 1;
+        }
+    }
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    bits: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, n: usize) -> Result<u8, NPNGError> {
+        let mut value = 0;
+        for _ in 0..n {
+            let byte = self
+                .bytes
+                .get(self.bits / 8)
+                .ok_or_else(|| NPNGError::Error("Truncated quadtree".to_string()))?;
+            value = (value << 1) | ((byte >> (7 - self.bits % 8)) & 1);
+            self.bits += 1;
+        }
+        Ok(value)
+    }
+}
+
+/// Node code: 1 bit for single pixels (empty/full), 2 bits otherwise
+fn node_bits(side: u64) -> usize {
+    if side == 1 { 1 } else { 2 }
+}
+
+/// Encode pixel coverage as a quadtree over the canvas.
+///
+/// Nodes are written depth-first (children in the order (0, 0), (1, 0), (0, 1), (1, 1),
+/// nodes outside the canvas are skipped) as empty, full or mixed. The body is the node
+/// bit stream followed by the colors of the covered pixels in traversal (Morton) order.
+pub(crate) fn encode_quadtree(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+    width: u32,
+    height: u32,
+) -> Result<BytesMut, NPNGError> {
+    let mut pixels: Vec<(u64, u32)> = pixels
+        .iter()
+        .filter_map(|p| format.stored_color(p.color).map(|c| (morton(p.x, p.y), c)))
+        .collect();
+    pixels.sort_unstable_by_key(|p| p.0);
+
+    let mut nodes = BitWriter::default();
+    if width > 0 && height > 0 {
+        encode_node(
+            &pixels,
+            (0, 0),
+            root_side(width, height),
+            (width, height),
+            &mut nodes,
+        );
+    }
+
+    let mut buf = BytesMut::new();
+    buf.extend(serialize(nodes.bytes, format.varint)?);
+    for &(_, color) in &pixels {
+        buf.extend_from_slice(&color.to_be_bytes()[..format.color_bytes()]);
+    }
+    Ok(buf)
+}
+
+/// `pixels`: the pixels inside the node, in Morton order
+fn encode_node(
+    pixels: &[(u64, u32)],
+    (x, y): (u64, u64),
+    side: u64,
+    (width, height): (u32, u32),
+    nodes: &mut BitWriter,
+) {
+    let area = side.min(width as u64 - x) * side.min(height as u64 - y);
+    let bits = node_bits(side);
+    if pixels.is_empty() {
+        nodes.write(NODE_EMPTY, bits);
+    } else if pixels.len() as u64 == area {
+        nodes.write(NODE_FULL, bits);
+    } else {
+        nodes.write(NODE_MIXED, bits);
+        let half = side / 2;
+        let shift = 2 * half.trailing_zeros();
+        let mut rest = pixels;
+        for child in 0..4u64 {
+            let split = rest.partition_point(|p| (p.0 >> shift) & 3 == child);
+            let (inside, tail) = rest.split_at(split);
+            rest = tail;
+            let (cx, cy) = (x + (child & 1) * half, y + (child >> 1) * half);
+            if cx < width as u64 && cy < height as u64 {
+                encode_node(inside, (cx, cy), half, (width, height), nodes);
+            }
+        }
+    }
+}
+
+/// Decode a quadtree written by [`encode_quadtree`]. Pixels are returned in Morton order
+pub(crate) fn decode_quadtree(
+    bytes: &[u8],
+    format: RecordFormat,
+    width: u32,
+    height: u32,
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let (nodes, read): (Vec<u8>, usize) = deserialize_prefix(bytes, format.varint)?;
+    let color_bytes = format.color_bytes();
+    let colors = &bytes[read..];
+    // every covered pixel has a stored color, shapes can cover the whole canvas
+    let limit = match color_bytes {
+        0 => (width as u64 * height as u64).min(MAX_PIXELS as u64),
+        n => (colors.len() / n) as u64,
+    };
+
+    let mut positions = Vec::new();
+    if width > 0 && height > 0 {
+        let mut reader = BitReader {
+            bytes: &nodes,
+            bits: 0,
+        };
+        decode_node(
+            &mut reader,
+            (0, 0),
+            root_side(width, height),
+            (width, height),
+            limit,
+            &mut positions,
+        )?;
+    }
+
+    if colors.len() != positions.len() * color_bytes {
+        return Err(NPNGError::Error(
+            "Quadtree color count mismatch".to_string(),
+        ));
+    }
+    let pixels: Vec<WidePixel> = positions
+        .into_iter()
+        .enumerate()
+        .map(|(i, (x, y))| {
+            WidePixel::new(x, y, read_color(&colors[i * color_bytes..], color_bytes))
+        })
+        .collect();
+    Ok(pixels)
+}
+
+fn decode_node(
+    reader: &mut BitReader,
+    (x, y): (u64, u64),
+    side: u64,
+    (width, height): (u32, u32),
+    limit: u64,
+    positions: &mut Vec<(u32, u32)>,
+) -> Result<(), NPNGError> {
+    match reader.read(node_bits(side))? {
+        NODE_EMPTY => Ok(()),
+        NODE_FULL => {
+            // checked before filling, a single node can cover the whole canvas
+            let area = side.min(width as u64 - x) * side.min(height as u64 - y);
+            if positions.len() as u64 + area > limit {
+                return Err(NPNGError::Error(
+                    "Quadtree covers more pixels than stored".to_string(),
+                ));
+            }
+            positions
+                .try_reserve(area as usize)
+                .map_err(|_| NPNGError::Error("Quadtree covers too many pixels".to_string()))?;
+            fill_node((x, y), side, (width, height), positions);
+            Ok(())
+        }
+        NODE_MIXED if side > 1 => {
+            let half = side / 2;
+            for child in 0..4u64 {
+                let (cx, cy) = (x + (child & 1) * half, y + (child >> 1) * half);
+                if cx < width as u64 && cy < height as u64 {
+                    decode_node(reader, (cx, cy), half, (width, height), limit, positions)?;
+                }
+            }
+            Ok(())
+        }
+        _ => Err(NPNGError::Error("Invalid quadtree node".to_string())),
+    }
+}
+
+/// Every canvas position of a node in Morton order
+fn fill_node(
+    (x, y): (u64, u64),
+    side: u64,
+    (width, height): (u32, u32),
+    positions: &mut Vec<(u32, u32)>,
+) {
+    if side == 1 {
+        positions.push((x as u32, y as u32));
+        return;
+    }
+    let half = side / 2;
+    for child in 0..4u64 {
+        let (cx, cy) = (x + (child & 1) * half, y + (child >> 1) * half);
+        if cx < width as u64 && cy < height as u64 {
+            fill_node((cx, cy), half, (width, height), positions);
+        }
+    }
+}
+
+/// Zigzag mapping of signed values to unsigned ones (0, -1, 1, -2, ... -> 0, 1, 2, 3, ...)
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+/// LEB128 variable-length integer
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<u64, NPNGError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *bytes
+            .get(*pos)
+            .ok_or_else(|| NPNGError::Error("Truncated coordinates".to_string()))?;
+        *pos += 1;
+        v |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(NPNGError::Error("Invalid varint".to_string()))
+}
+
+/// Encode pixels with compact coordinates.
+///
+/// Every pixel is a zigzag varint token. Within a row, `token = zigzag(dx - 1) << 1`
+/// (adjacent pixels give 0). A row change is marked with `token = zigzag(dy) << 1 | 1`
+/// followed by the absolute `x` in the record coordinate width. The coordinate
+/// stream is followed by the colors of [`RecordFormat::color_bytes`] bytes.
+pub(crate) fn encode_delta(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+) -> Result<BytesMut, NPNGError> {
+    let mut colors = Vec::new();
+    let coords = write_coords(
+        pixels.iter().filter_map(|p| {
+            let color = format.stored_color(p.color)?;
+            colors.extend_from_slice(&color.to_be_bytes()[..format.color_bytes()]);
+            Some((p.x, p.y))
+        }),
+        format.coords,
+    );
+
+    let mut buf = BytesMut::new();
+    buf.extend(serialize(coords, format.varint)?);
+    buf.extend(colors);
+    Ok(buf)
+}
+
+/// Delta-coded coordinate stream of [`encode_delta`]
+fn write_coords(positions: impl Iterator<Item = (u32, u32)>, width: CoordWidth) -> Vec<u8> {
+    let mut coords = Vec::new();
+    let mut prev: Option<(u32, u32)> = None;
+    for (x, y) in positions {
+        match prev {
+            Some((px, py)) if py == y => {
+                write_varint(&mut coords, zigzag(x as i64 - px as i64 - 1) << 1);
+            }
+            _ => {
+                let py = prev.map_or(0, |(_, y)| y);
+                write_varint(&mut coords, (zigzag(y as i64 - py as i64) << 1) | 1);
+                match width {
+                    CoordWidth::U8 => coords.push(x as u8),
+                    CoordWidth::U16 => coords.extend_from_slice(&(x as u16).to_le_bytes()),
+                    CoordWidth::U32 => coords.extend_from_slice(&x.to_le_bytes()),
+                }
+            }
+        }
+        prev = Some((x, y));
+    }
+    coords
+}
+
+/// Decode pixels written by [`encode_delta`]
+pub(crate) fn decode_delta(
+    bytes: &[u8],
+    format: RecordFormat,
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let (coords, read): (Vec<u8>, usize) = deserialize_prefix(bytes, format.varint)?;
+    let positions = read_coords(&coords, format.coords)?;
+
+    let color_bytes = format.color_bytes();
+    let colors = &bytes[read..];
+    if colors.len() != positions.len() * color_bytes {
+        return Err(NPNGError::Error("Pixel color count mismatch".to_string()));
+    }
+    Ok(positions
+        .into_iter()
+        .enumerate()
+        .map(|(i, (x, y))| {
+            WidePixel::new(x, y, read_color(&colors[i * color_bytes..], color_bytes))
+        })
+        .collect())
+}
+
+/// Positions of a coordinate stream written by [`write_coords`]
+fn read_coords(coords: &[u8], width: CoordWidth) -> Result<Vec<(u32, u32)>, NPNGError> {
+    let coord_err = || NPNGError::Error("Invalid pixel coordinates".to_string());
+
+    let mut positions = Vec::new();
+    let (mut x, mut y) = (0i64, 0i64);
+    let mut pos = 0;
+    while pos < coords.len() {
+        let token = read_varint(coords, &mut pos)?;
+        if token & 1 == 0 {
+            if positions.is_empty() {
+                return Err(coord_err());
+            }
+            x = x
+                .checked_add(unzigzag(token >> 1) + 1)
+                .ok_or_else(coord_err)?;
+        } else {
+            y = y.checked_add(unzigzag(token >> 1)).ok_or_else(coord_err)?;
+            let width = coord_bytes(width);
+            let field = coords.get(pos..pos + width).ok_or_else(coord_err)?;
+            pos += width;
+            x = field.iter().rev().fold(0i64, |v, &b| (v << 8) | b as i64);
+        }
+        let px = u32::try_from(x).map_err(|_| coord_err())?;
+        let py = u32::try_from(y).map_err(|_| coord_err())?;
+        positions.push((px, py));
+    }
+    Ok(positions)
+}
+
+/// Bytes of a coordinate in the delta and planar layouts
+fn coord_bytes(coords: CoordWidth) -> usize {
+    match coords {
+        CoordWidth::U8 => 1,
+        CoordWidth::U16 => 2,
+        CoordWidth::U32 => 4,
+    }
+}
+
+/// Encode pixels channel by channel.
+///
+/// The body is the pixel count, then all `x`, all `y` (little endian, record coordinate
+/// width) and one plane per stored color channel (R, G, B and A with `save_alpha`).
+pub(crate) fn encode_planar(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+) -> Result<BytesMut, NPNGError> {
+    let pixels: Vec<(u32, u32, u32)> = pixels
+        .iter()
+        .filter_map(|p| format.stored_color(p.color).map(|c| (p.x, p.y, c)))
+        .collect();
+    let width = coord_bytes(format.coords);
+
+    let mut buf = BytesMut::new();
+    buf.extend(serialize(pixels.len() as u64, format.varint)?);
+    for plane in [0, 1] {
+        for p in &pixels {
+            let v = if plane == 0 { p.0 } else { p.1 };
+            buf.extend_from_slice(&v.to_le_bytes()[..width]);
+        }
+    }
+    for channel in 0..format.color_bytes() {
+        buf.extend(pixels.iter().map(|p| p.2.to_be_bytes()[channel]));
+    }
+    Ok(buf)
+}
+
+/// Decode pixels written by [`encode_planar`]
+pub(crate) fn decode_planar(
+    bytes: &[u8],
+    format: RecordFormat,
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let (count, read): (u64, usize) = deserialize_prefix(bytes, format.varint)?;
+    let width = coord_bytes(format.coords);
+    let color_bytes = format.color_bytes();
+
+    let data = &bytes[read..];
+    if count > MAX_PIXELS as u64 || data.len() as u64 != count * (2 * width + color_bytes) as u64 {
+        return Err(NPNGError::Error("Planar body size mismatch".to_string()));
+    }
+    let count = count as usize;
+
+    let (xs, rest) = data.split_at(count * width);
+    let (ys, channels) = rest.split_at(count * width);
+    let coord = |plane: &[u8], i: usize| {
+        plane[i * width..(i + 1) * width]
+            .iter()
+            .rev()
+            .fold(0u32, |v, &b| (v << 8) | b as u32)
+    };
+    Ok((0..count)
+        .map(|i| {
+            let mut stored = [0; 4];
+            for (c, value) in stored.iter_mut().enumerate().take(color_bytes) {
+                *value = channels[c * count + i];
+            }
+            WidePixel::new(coord(xs, i), coord(ys, i), read_color(&stored, color_bytes))
+        })
+        .collect())
+}
+
+// ===== QOI =====
+
+/// `00iiiiii` - color from the index of recently seen colors
+const QOI_OP_INDEX: u8 = 0x00;
+/// `01rrggbb` - channel differences to the previous color, `-2..=1` each
+const QOI_OP_DIFF: u8 = 0x40;
+/// `10gggggg rrrrbbbb` - green difference `-32..=31`, red and blue relative to it `-8..=7`
+const QOI_OP_LUMA: u8 = 0x80;
+/// `11rrrrrr` - repeat the previous color 1-62 times
+const QOI_OP_RUN: u8 = 0xC0;
+/// `0xFE r g b` - RGB with the previous alpha
+const QOI_OP_RGB: u8 = 0xFE;
+/// `0xFF r g b a`
+const QOI_OP_RGBA: u8 = 0xFF;
+const QOI_MASK: u8 = 0xC0;
+
+fn qoi_hash([r, g, b, a]: [u8; 4]) -> usize {
+    (r as usize * 3 + g as usize * 5 + b as usize * 7 + a as usize * 11) % 64
+}
+
+/// Encode pixels with the QOI operations in one pass.
+///
+/// The body is the coordinate stream of [`encode_delta`] followed by one QOI operation
+/// stream over the colors (runs of the previous color, index of recently seen colors,
+/// small differences, luma differences and literals). The previous color starts as
+/// opaque black. Shape bodies have no color stream.
+pub(crate) fn encode_qoi(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+) -> Result<BytesMut, NPNGError> {
+    let alpha_mask = if format.color_bytes() == 4 { 0 } else { 0xFF };
+    let mut ops = Vec::new();
+    let mut index = [[0u8; 4]; 64];
+    let mut prev = [0, 0, 0, 0xFF];
+    let mut run = 0u8;
+
+    let coords = write_coords(
+        pixels.iter().filter_map(|p| {
+            let color = format.stored_color(p.color)?;
+            if format.color_bytes() > 0 {
+                qoi_push(
+                    &mut ops,
+                    (color | alpha_mask).to_be_bytes(),
+                    &mut prev,
+                    &mut index,
+                    &mut run,
+                );
+            }
+            Some((p.x, p.y))
+        }),
+        format.coords,
+    );
+    if run > 0 {
+        ops.push(QOI_OP_RUN | (run - 1));
+    }
+
+    let mut buf = BytesMut::with_capacity(coords.len() + ops.len() + 8);
+    buf.extend(serialize(coords, format.varint)?);
+    buf.extend(ops);
+    Ok(buf)
+}
+
+fn qoi_push(
+    ops: &mut Vec<u8>,
+    px: [u8; 4],
+    prev: &mut [u8; 4],
+    index: &mut [[u8; 4]; 64],
+    run: &mut u8,
+) {
+    if px == *prev {
+        *run += 1;
+        if *run == 62 {
+            ops.push(QOI_OP_RUN | (*run - 1));
+            *run = 0;
+        }
+        return;
+    }
+    if *run > 0 {
+        ops.push(QOI_OP_RUN | (*run - 1));
+        *run = 0;
+    }
+
+    let hash = qoi_hash(px);
+    if index[hash] == px {
+        ops.push(QOI_OP_INDEX | hash as u8);
+    } else {
+        index[hash] = px;
+        if px[3] == prev[3] {
+            let dr = px[0].wrapping_sub(prev[0]) as i8;
+            let dg = px[1].wrapping_sub(prev[1]) as i8;
+            let db = px[2].wrapping_sub(prev[2]) as i8;
+            let (dr_dg, db_dg) = (dr.wrapping_sub(dg), db.wrapping_sub(dg));
+            if (-2..=1).contains(&dr) && (-2..=1).contains(&dg) && (-2..=1).contains(&db) {
+                ops.push(
+                    QOI_OP_DIFF | ((dr + 2) as u8) << 4 | ((dg + 2) as u8) << 2 | (db + 2) as u8,
+                );
+            } else if (-32..=31).contains(&dg)
+                && (-8..=7).contains(&dr_dg)
+                && (-8..=7).contains(&db_dg)
+            {
+                ops.push(QOI_OP_LUMA | (dg + 32) as u8);
+                ops.push(((dr_dg + 8) as u8) << 4 | (db_dg + 8) as u8);
+            } else {
+                ops.extend_from_slice(&[QOI_OP_RGB, px[0], px[1], px[2]]);
+            }
+        } else {
+            ops.extend_from_slice(&[QOI_OP_RGBA, px[0], px[1], px[2], px[3]]);
+        }
+    }
+    *prev = px;
+}
+
+/// Decode pixels written by [`encode_qoi`]
+pub(crate) fn decode_qoi(bytes: &[u8], format: RecordFormat) -> Result<Vec<WidePixel>, NPNGError> {
+    let (coords, read): (Vec<u8>, usize) = deserialize_prefix(bytes, format.varint)?;
+    let positions = read_coords(&coords, format.coords)?;
+    if format.color_bytes() == 0 {
+        return if read == bytes.len() {
+            Ok(positions
+                .into_iter()
+                .map(|(x, y)| WidePixel::new(x, y, 0xFFFFFFFF))
+                .collect())
+        } else {
+            Err(NPNGError::Error("Pixel color count mismatch".to_string()))
+        };
+    }
+
+    let ops = &bytes[read..];
+    let op_err = || NPNGError::Error("Truncated QOI operations".to_string());
+    let mut pixels = Vec::with_capacity(positions.len());
+    let mut index = [[0u8; 4]; 64];
+    let mut px = [0, 0, 0, 0xFF];
+    let mut run = 0usize;
+    let mut pos = 0;
+
+    for (x, y) in positions {
+        if run > 0 {
+            run -= 1;
+        } else {
+            let op = *ops.get(pos).ok_or_else(op_err)?;
+            pos += 1;
+            match op {
+                QOI_OP_RGB => {
+                    let v = ops.get(pos..pos + 3).ok_or_else(op_err)?;
+                    px[..3].copy_from_slice(v);
+                    pos += 3;
+                }
+                QOI_OP_RGBA => {
+                    let v = ops.get(pos..pos + 4).ok_or_else(op_err)?;
+                    px.copy_from_slice(v);
+                    pos += 4;
+                }
+                _ => match op & QOI_MASK {
+                    QOI_OP_INDEX => px = index[(op & 0x3F) as usize],
+                    QOI_OP_DIFF => {
+                        px[0] = px[0].wrapping_add((op >> 4) & 0x03).wrapping_sub(2);
+                        px[1] = px[1].wrapping_add((op >> 2) & 0x03).wrapping_sub(2);
+                        px[2] = px[2].wrapping_add(op & 0x03).wrapping_sub(2);
+                    }
+                    QOI_OP_LUMA => {
+                        let next = *ops.get(pos).ok_or_else(op_err)?;
+                        pos += 1;
+                        let dg = (op & 0x3F).wrapping_sub(32);
+                        px[0] = px[0]
+                            .wrapping_add(dg)
+                            .wrapping_add(next >> 4)
+                            .wrapping_sub(8);
+                        px[1] = px[1].wrapping_add(dg);
+                        px[2] = px[2]
+                            .wrapping_add(dg)
+                            .wrapping_add(next & 0x0F)
+                            .wrapping_sub(8);
+                    }
+                    _ => run = (op & 0x3F) as usize,
+                },
+            }
+            index[qoi_hash(px)] = px;
+        }
+        pixels.push(WidePixel::new(x, y, u32::from_be_bytes(px)));
+    }
+    if run > 0 || pos != ops.len() {
+        return Err(NPNGError::Error("Pixel color count mismatch".to_string()));
+    }
+    Ok(pixels)
+}
@@ -6,9 +6,28 @@ extern crate std;
 #[cfg(feature = "log")]
 use log::warn;
 
+use crate::types::CheckSum;
+use crate::ver::VERSION_METADATA;
+use crate::{
+    coding::{
+        RecordFormat, decode_body, decode_channels, decode_coverage, decode_layers, encode_body,
+        encode_channels, encode_coverage, encode_layers, pick_color_transform,
+    },
+    image_io::{read_image, save_image},
+    progressive::decode_progressive,
+    residual::{decode_residuals, encode_residuals},
+    types::mipmap::{MipLevel, encode_levels, pyramid, read_index, read_level},
+    types::thumbnail::{ThumbnailRecord, decode_thumbnail, encode_thumbnail},
+    utils::{
+        check_canvas_bounds, check_duplicates, check_image_size_f, deserialize, hilbert, morton,
+        serialize,
+    },
+    ver::{VERSION_MAJOR, VERSION_MINOR},
+};
+use bincode::{Decode, Encode};
 use bytes::Bytes;
 use crc32fast::Hasher;
-use image::{GenericImageView, ImageBuffer, ImageReader, Pixel as TraitPx, Rgba};
+use image::{GenericImageView, ImageBuffer, Pixel as TraitPx, Rgba, RgbaImage};
 use std::str::FromStr;
 #[allow(dead_code)]
 #[allow(unused)]
@@ -20,43 +39,61 @@ use std::{
     io::{Read, Write},
     path::Path,
 };
-use crate::types::{CheckSum, SIZE};
-use crate::ver::VERSION_METADATA;
-use crate::{
-    coding::{spawn_plain_decode_workers, spawn_plain_workers},
-    utils::{check_image_size_f, deserialize, serialize},
-    ver::{VERSION_MAJOR, VERSION_MINOR},
-};
 
-pub use crate::types::Img;
-pub use crate::types::VersionMetadata;
+pub use crate::progressive::ProgressiveDecoder;
 pub use crate::types::EncoderVersion;
-
-use crate::types::metadata::Metadata;
+pub use crate::types::VersionMetadata;
+pub use crate::types::channel::{AuxChannel, ChannelData, ChannelImg, ChannelType, ChannelValue};
+pub use crate::types::color::{AlphaMode, ColorSpace, ColorTransform};
+pub use crate::types::document::{BlendMode, Layer, NpngDocument};
+pub use crate::types::metrics::{ErrorReport, compare_images, compare_wide_images};
+pub use crate::types::mipmap::{MipFilter, Mipmaps};
+pub use crate::types::quantize::{Dither, Palette, Quantize, QuantizeMethod};
+pub use crate::types::thumbnail::MAX_THUMBNAIL_SIZE;
+pub use crate::types::value::{MAX_VALUE_DEPTH, MetadataValue};
+pub use crate::types::{Img, WideImg};
+
+use crate::types::chunk::{
+    Chunk, ChunkRef, FLAG_CHUNKS, TAG_ALPHA, TAG_CHANNELS, TAG_COLOR_TRANSFORM, TAG_COORDS,
+    TAG_COVERAGE, TAG_LAYERS, TAG_LAYOUT, TAG_MIPMAPS, TAG_PALETTE, TAG_PIXEL_FORMAT,
+    TAG_PIXEL_ORDER, TAG_THUMBNAIL, find_chunk, read_chunks, read_received_chunks,
+};
+use crate::types::color::convert_alpha;
 use crate::types::header::Header;
-pub use crate::types::pixel::Pixel;
+use crate::types::metadata::Metadata;
+pub use crate::types::pixel::{CoordWidth, GlobalPixel, Pixel, WidePixel};
 
 use crate::compression::CompressMap;
 
 use crate::error::*;
 use crate::types::MAX_PIXELS;
+use rayon::slice::ParallelSliceMut;
+
+/// Largest canvas side that fits into 16-bit pixel coordinates
+const MAX_U16_CANVAS: u32 = 65536;
 
 mod coding;
+mod image_io;
+mod layout;
+mod progressive;
+mod rans;
+mod residual;
 
 #[cfg(feature = "tokio_async")]
 pub mod tokio;
 
-mod utils;
-mod ver;
-pub mod types;
 pub mod compression;
 pub mod error;
+pub mod types;
+mod utils;
+mod ver;
 
 #[derive(Debug, Clone)]
 pub enum Encoding {
     Plain,    // no compressing (high file sze)
     Zlib(u8), // max - 9
     Zstd(u8), // max - 22
+    Rans,     // built-in entropy coder, no level
 }
 
 impl Default for Encoding {
@@ -71,6 +108,7 @@ impl Display for Encoding {
             Encoding::Plain => f.write_str("plain"),
             Encoding::Zlib(_) => f.write_str("zlib"),
             Encoding::Zstd(_) => f.write_str("zstd"),
+            Encoding::Rans => f.write_str("rans"),
         }
     }
 }
@@ -85,6 +123,7 @@ impl IntoCompressMap for Encoding {
             Encoding::Plain => CompressMap::plain(),
             Encoding::Zstd(l) => CompressMap::zstd(l as u32),
             Encoding::Zlib(l) => CompressMap::zlib(l as u32),
+            Encoding::Rans => CompressMap::rans(),
         })
     }
 }
@@ -104,28 +143,259 @@ impl<T: Into<String> + Sync + Send> IntoCompressMap for T {
             "none" => Ok(CompressMap::plain()),
             "zlib" => Ok(CompressMap::zlib(6)),
             "zstd" => Ok(CompressMap::zstd(16)),
+            "rans" => Ok(CompressMap::rans()),
             _ => Err(NPNGError::Error("Unknown compressing".to_string())),
         }
     }
 }
 
+/// Order in which pixels are written (recorded in the file unless `Preserve`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum PixelOrder {
+    /// Keep the order of the input vector
+    #[default]
+    Preserve,
+    /// Sort by `y`, then by `x`
+    RowMajor,
+    /// Z-order curve (bits of `x` and `y` interleaved)
+    Morton,
+    /// Hilbert curve over the 32-bit coordinate space
+    Hilbert,
+}
+
+impl PixelOrder {
+    /// Sort key of a pixel. `Preserve` sorts like `RowMajor` where an order is needed
+    pub(crate) fn key(self) -> fn(&WidePixel) -> u64 {
+        match self {
+            PixelOrder::Preserve | PixelOrder::RowMajor => |p| ((p.y as u64) << 32) | p.x as u64,
+            PixelOrder::Morton => |p| morton(p.x, p.y),
+            PixelOrder::Hilbert => |p| hilbert(p.x, p.y),
+        }
+    }
+}
+
+impl Display for PixelOrder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PixelOrder::Preserve => f.write_str("preserve"),
+            PixelOrder::RowMajor => f.write_str("row-major"),
+            PixelOrder::Morton => f.write_str("morton"),
+            PixelOrder::Hilbert => f.write_str("hilbert"),
+        }
+    }
+}
+
+/// What to do with metadata over the header limits
+/// (more than 512 `extra` entries, `created_in` longer than 512 bytes)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Keep the first 512 `extra` entries in key order and cut `created_in`
+    #[default]
+    Truncate,
+    /// Return an error
+    Reject,
+}
+
+impl Display for OverflowPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverflowPolicy::Truncate => f.write_str("truncate"),
+            OverflowPolicy::Reject => f.write_str("reject"),
+        }
+    }
+}
+
+/// What to do with fully transparent pixels (alpha = 0x00)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum AlphaPolicy {
+    /// Don't store them
+    #[default]
+    Drop,
+    /// Store that the pixel exists, the color is dropped (decoded as `0x00000000`).
+    /// Positions are stored apart from the body and decoded after the other pixels
+    /// (sorted in with a `pixel_order` other than `Preserve`)
+    KeepCoverage,
+    /// Store the RGBA value verbatim
+    KeepExact,
+}
+
+impl Display for AlphaPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AlphaPolicy::Drop => f.write_str("drop"),
+            AlphaPolicy::KeepCoverage => f.write_str("keep-coverage"),
+            AlphaPolicy::KeepExact => f.write_str("keep-exact"),
+        }
+    }
+}
+
+/// What is stored for each pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum PixelFormat {
+    /// RGBA, or RGB without `save_alpha`
+    #[default]
+    Color,
+    /// RGB with 1-bit alpha: pixels with alpha >= 0x80 are stored (decoded opaque),
+    /// the rest are dropped
+    BinaryAlpha,
+    /// Coordinates only: pixels with alpha >= 0x80 are stored (decoded as `0xFFFFFFFF`),
+    /// the rest are dropped
+    Shape,
+}
+
+impl Display for PixelFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PixelFormat::Color => f.write_str("color"),
+            PixelFormat::BinaryAlpha => f.write_str("binary-alpha"),
+            PixelFormat::Shape => f.write_str("shape"),
+        }
+    }
+}
 
+/// Arrangement of the compressed pixel data
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum Layout {
+    /// One record per pixel
+    #[default]
+    Pixels,
+    /// Horizontal spans: runs of one color and literal runs of adjacent pixels
+    Spans,
+    /// Quadtree of the canvas (empty, full and mixed nodes) with colors in traversal order.
+    /// Pixels are decoded in `pixel_order` (row-major for [`PixelOrder::Preserve`])
+    Quadtree,
+    /// Compact coordinates: delta-coded `x` within rows, row-change markers and zigzag
+    /// varints, absolute `x` in the narrowest width for the canvas (`u8`, `u16` or `u32`)
+    Delta,
+    /// Channel-separated: all `x`, all `y`, then one plane per color channel
+    Planar,
+    /// QOI-style operations (runs, recent color index, small and luma differences) after
+    /// `Delta` coordinates. Single pass and compact on its own: meant for
+    /// [`Encoding::Plain`] where zstd is too slow
+    Qoi,
+    /// Adam7 passes (1/64 of the pixels, then 1/64, 1/32, ... 1/2), each compressed on its
+    /// own, so a [`ProgressiveDecoder`] can show coarse previews while the file arrives.
+    /// Pixels are decoded in `pixel_order` (row-major for [`PixelOrder::Preserve`])
+    Progressive,
+    /// Encode every layout and keep the smallest (the chosen one is stored).
+    /// `Quadtree` is only tried if `pixel_order` is not [`PixelOrder::Preserve`],
+    /// `Progressive` is never tried
+    Auto,
+}
+
+impl Layout {
+    /// Layouts tried by [`Layout::Auto`]
+    pub(crate) const STORED: [Layout; 6] = [
+        Layout::Pixels,
+        Layout::Spans,
+        Layout::Quadtree,
+        Layout::Delta,
+        Layout::Planar,
+        Layout::Qoi,
+    ];
+
+    /// Whether decoded pixels can come back in another order than they were written
+    pub(crate) fn reorders(self) -> bool {
+        matches!(self, Layout::Quadtree | Layout::Progressive)
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layout::Pixels => f.write_str("pixels"),
+            Layout::Spans => f.write_str("spans"),
+            Layout::Quadtree => f.write_str("quadtree"),
+            Layout::Delta => f.write_str("delta"),
+            Layout::Planar => f.write_str("planar"),
+            Layout::Qoi => f.write_str("qoi"),
+            Layout::Progressive => f.write_str("progressive"),
+            Layout::Auto => f.write_str("auto"),
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Config {
     pub save_alpha: bool,
     pub varint: bool,
+    pub pixel_order: PixelOrder,
+    pub metadata_overflow: OverflowPolicy,
+    /// Fully transparent pixels. Keeping them requires `save_alpha`
+    pub alpha_policy: AlphaPolicy,
+    /// Stored pixel data. `save_alpha` and `alpha_policy` only apply to [`PixelFormat::Color`]
+    pub pixel_format: PixelFormat,
+    /// Arrangement of the pixel data
+    pub layout: Layout,
+    /// Reversible color transform before compression (not used for [`PixelFormat::Shape`])
+    pub color_transform: ColorTransform,
+    /// Lossy quantization to a palette, pixels store indices. Replaces `color_transform`,
+    /// not used for [`PixelFormat::Shape`]. For a 0-100 quality use
+    /// `Some(Quantize::with_quality(q))`
+    pub quantize: Option<Quantize>,
+    /// Near-lossless mode: largest deviation of a channel value (0 - lossless), recorded
+    /// in the header. Replaces `color_transform`, not used with `quantize` or for
+    /// [`PixelFormat::Shape`]
+    pub max_error: u8,
+    /// Downsampled levels stored after the header ([`decode_level`])
+    pub mipmaps: Option<Mipmaps>,
+    /// Store a thumbnail that fits into N x N pixels (1-[`MAX_THUMBNAIL_SIZE`]),
+    /// read with [`read_thumbnail`]
+    pub thumbnail: Option<u16>,
 }
 
 impl Display for Config {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "save_alpha={}\nvarint={}", self.save_alpha, self.varint)
+        write!(
+            f,
+            "save_alpha={}\nvarint={}\npixel_order={}\nmetadata_overflow={}\nalpha_policy={}\npixel_format={}\nlayout={}\ncolor_transform={}\nquantize={}\nmax_error={}\nmipmaps={}\nthumbnail={}",
+            self.save_alpha,
+            self.varint,
+            self.pixel_order,
+            self.metadata_overflow,
+            self.alpha_policy,
+            self.pixel_format,
+            self.layout,
+            self.color_transform,
+            self.quantize.map_or("none".to_string(), |q| q.to_string()),
+            self.max_error,
+            self.mipmaps.map_or("none".to_string(), |m| m.to_string()),
+            self.thumbnail.map_or("none".to_string(), |t| t.to_string())
+        )
     }
 }
 
 impl Config {
     pub fn new(save_alpha: bool, varint: bool) -> Self {
-        Self { save_alpha, varint }
+        Self {
+            save_alpha,
+            varint,
+            ..Default::default()
+        }
+    }
+
+    /// Pixel record format for this config
+    pub(crate) fn record_format(&self, coords: CoordWidth) -> RecordFormat {
+        RecordFormat {
+            save_alpha: self.save_alpha && self.pixel_format == PixelFormat::Color,
+            varint: self.varint,
+            coords,
+            alpha_policy: self.alpha_policy,
+            pixel_format: self.pixel_format,
+            layout: self.layout,
+            pixel_order: self.pixel_order,
+            color_transform: match self.pixel_format {
+                PixelFormat::Shape => ColorTransform::None,
+                _ if self.quantize.is_some() || self.max_error > 0 => ColorTransform::None,
+                _ => self.color_transform,
+            },
+            indexed: self.quantize.is_some() && self.pixel_format != PixelFormat::Shape,
+            max_error: match self.pixel_format {
+                PixelFormat::Shape => 0,
+                _ if self.quantize.is_some() => 0,
+                _ => self.max_error,
+            },
+        }
     }
 }
 
@@ -134,6 +404,35 @@ impl Default for Config {
         Self {
             varint: false,
             save_alpha: true,
+            pixel_order: PixelOrder::Preserve,
+            metadata_overflow: OverflowPolicy::Truncate,
+            alpha_policy: AlphaPolicy::Drop,
+            pixel_format: PixelFormat::Color,
+            layout: Layout::Pixels,
+            color_transform: ColorTransform::None,
+            quantize: None,
+            max_error: 0,
+            mipmaps: None,
+            thumbnail: None,
+        }
+    }
+}
+
+/// Decoding options
+#[derive(Debug, Clone, Default)]
+pub struct DecodeConfig {
+    /// Convert pixel colors into this color space (`None` - keep the stored one)
+    pub color_space: Option<ColorSpace>,
+    /// Convert pixel colors into this alpha mode (`None` - keep the stored one,
+    /// straight for image buffers and files)
+    pub alpha_mode: Option<AlphaMode>,
+}
+
+impl DecodeConfig {
+    pub fn new(color_space: Option<ColorSpace>) -> Self {
+        Self {
+            color_space,
+            ..Default::default()
         }
     }
 }
@@ -150,16 +449,32 @@ pub fn version() -> EncoderVersion {
 ///
 /// # Parameters
 /// - `pixels` - Vector of pixels to encode (`Vec<Pixel>`).
-/// - `metadata` - Image metadata [`Metadata`]. The width and height will be updated
-///   automatically based on the pixels.
+/// - `metadata` - Image metadata [`Metadata`]. A non-zero `width`/`height` declares the
+///   canvas size; a zero one is calculated from the pixels (max coordinate + 1).
 /// - `config` - Encoding options [`Config`]:
 ///     - `save_alpha` - Whether to include the alpha channel in the output. Fully opaque
 ///       pixels don't saving
 ///     - `varint` - Whether to use variable-length integer encoding for pixel data.
+///     - `pixel_order` - Order in which pixels are written ([`PixelOrder`]).
+///     - `metadata_overflow` - Truncate or reject metadata over the header limits ([`OverflowPolicy`]).
+///     - `alpha_policy` - Drop or keep fully transparent pixels ([`AlphaPolicy`]),
+///       recorded in the file.
+///     - `quantize` - Lossy quantization to a palette ([`Quantize`]); the palette and the
+///       error are recorded in the file ([`read_palette`]).
+///     - `max_error` - Near-lossless mode: largest deviation of a channel value (0 - lossless),
+///       check the result with [`compare_images`].
+///     - `mipmaps` - Store a resolution pyramid ([`Mipmaps`]), read with [`decode_level`].
+///     - `thumbnail` - Store a small preview (up to 256x256), read with [`read_thumbnail`].
 /// - `compress_map` - Compression map
 ///
+/// Output is deterministic: equal pixels, metadata, config and compressor give
+/// byte-identical files (`extra` is written in key order).
+///
+/// See [`encode_wide_pixel_vec_with_metadata`] for canvases larger than 65536x65536.
+///
 /// # Behavior
-/// 1. Checks the image size from the pixels and updates `metadata.width` and `metadata.height`.
+/// 1. Fills the undeclared canvas dimensions from the pixels and checks that every pixel
+///    lies inside the canvas.
 /// 2. Ensures there are no duplicate pixel coordinates; returns an error if duplicates exist.
 /// 3. Prepares a buffer, encodes the header, and checks its size.
 /// 4. Encodes pixels using plain workers, applying `save_alpha` and `varint` options.
@@ -168,10 +483,11 @@ pub fn version() -> EncoderVersion {
 ///
 /// # Returns
 /// - `Ok(Vec<u8>)` - Encoded NPNG bytes ready for storage or transmission.
-/// - `Err(NPNGError)` - If encoding fails, duplicate pixels are found, or the header is too long.
+/// - `Err(NPNGError)` - If encoding fails, duplicate pixels are found, a pixel is outside
+///   the canvas ([`NPNGError::PixelOutOfBounds`]), or the header is too long.
 pub fn encode_pixel_vec_with_metadata<C: IntoCompressMap>(
     pixels: Vec<Pixel>,
-    mut metadata: Metadata,
+    metadata: Metadata,
     config: Config,
     compress_map: C,
 ) -> Result<Vec<u8>, NPNGError> {
@@ -182,50 +498,316 @@ pub fn encode_pixel_vec_with_metadata<C: IntoCompressMap>(
             MAX_PIXELS
         )));
     }
+    encode_wide_pixel_vec_with_metadata(
+        pixels.into_iter().map(WidePixel::from).collect(),
+        metadata,
+        config,
+        compress_map,
+    )
+}
+
+/// Encodes a vector of [`WidePixel`]s (32-bit coordinates) with metadata into NPNG bytes.
+///
+/// Same as [`encode_pixel_vec_with_metadata`]. Pixel records use 16-bit coordinates
+/// while the canvas fits into 65536x65536 (the output is then identical to
+/// [`encode_pixel_vec_with_metadata`]), and 32-bit ones otherwise ([`CoordWidth`],
+/// recorded in the file).
+pub fn encode_wide_pixel_vec_with_metadata<C: IntoCompressMap>(
+    pixels: Vec<WidePixel>,
+    metadata: Metadata,
+    config: Config,
+    compress_map: C,
+) -> Result<Vec<u8>, NPNGError> {
     let compress_map = compress_map.into_compress_map()?;
-    let save_alpha = config.save_alpha;
-    let varint = config.varint;
+    encode_file(pixels, metadata, &config, &compress_map, &[], Vec::new())
+}
+
+/// Encode a complete file: header, chunks (`extra_chunks` after the metadata ones),
+/// compressed pixel records and checksum.
+///
+/// `channels` hold one value per input pixel and follow the pixels through sorting.
+fn encode_file(
+    pixels: Vec<WidePixel>,
+    mut metadata: Metadata,
+    config: &Config,
+    compress_map: &CompressMap,
+    channels: &[AuxChannel],
+    extra_chunks: Vec<Chunk>,
+) -> Result<Vec<u8>, NPNGError> {
     let mut hasher = Hasher::new();
 
+    if config.alpha_policy != AlphaPolicy::Drop
+        && (!config.save_alpha || config.pixel_format != PixelFormat::Color)
+    {
+        return Err(NPNGError::Error(format!(
+            "Alpha policy `{}` requires `save_alpha` and the color pixel format",
+            config.alpha_policy
+        )));
+    }
+
     /* ===== Calculating image size ===== */
-    let s = check_image_size_f(pixels.clone());
-    metadata.width = s.0;
-    metadata.height = s.1;
+    if metadata.width == 0 || metadata.height == 0 {
+        let s = check_image_size_f(&pixels)?;
+        if metadata.width == 0 {
+            metadata.width = s.0;
+        }
+        if metadata.height == 0 {
+            metadata.height = s.1;
+        }
+    }
+    check_canvas_bounds(&pixels, metadata.width, metadata.height)?;
+    let coords = if metadata.width <= MAX_U16_CANVAS && metadata.height <= MAX_U16_CANVAS {
+        CoordWidth::U16
+    } else {
+        CoordWidth::U32
+    };
+    let mut format = config.record_format(coords);
+    if format.color_transform == ColorTransform::Auto {
+        format.color_transform = pick_color_transform(&pixels, format.without_coverage());
+    }
 
     /* ===== Check for duplicate coordinates === */
+    check_duplicates(&pixels, metadata.width, metadata.height)?;
+
+    /* ===== Check metadata limits ===== */
+    if config.metadata_overflow == OverflowPolicy::Reject
+        && (metadata.extra.len() > 512 || metadata.created_in.len() > 512)
     {
-        let mut bitmap = vec![0u8; (MAX_PIXELS) / 8]; // 512 MB
-
-        for p in &pixels {
-            let idx = (p.y as usize) * SIZE + (p.x as usize);
-            let byte = idx / 8;
-            let bit = idx % 8;
-            let mask = 1 << bit;
-            if bitmap[byte] & mask != 0 {
-                return Err(NPNGError::DuplicatePixel(p.x, p.y));
-            }
-            bitmap[byte] |= mask;
+        return Err(NPNGError::Error(
+            "Metadata is too long (max 512 extra entries, created_in max 512 bytes)".to_string(),
+        ));
+    }
+
+    /* ===== Check channels ===== */
+    for (i, c) in channels.iter().enumerate() {
+        if c.len() != pixels.len() {
+            return Err(NPNGError::Error(format!(
+                "Channel `{}` has {} values for {} pixels",
+                c.name,
+                c.len(),
+                pixels.len()
+            )));
+        }
+        if channels[..i].iter().any(|o| o.name == c.name) {
+            return Err(NPNGError::Error(format!("Duplicate channel `{}`", c.name)));
+        }
+    }
+
+    /* ===== Downsample levels ===== */
+    // from the exact colors as they are decoded (alpha, pixel format), levels are stored lossless
+    let visible: Vec<WidePixel> = if config.mipmaps.is_some() || config.thumbnail.is_some() {
+        let exact = level_format(format);
+        pixels
+            .iter()
+            .filter_map(|p| Some(WidePixel::new(p.x, p.y, exact.decoded_color(p.color)?)))
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let levels = match config.mipmaps {
+        Some(mipmaps) => {
+            let level_format = RecordFormat {
+                layout: Layout::Auto,
+                pixel_order: PixelOrder::Preserve,
+                ..level_format(format)
+            };
+            let levels = pyramid(
+                &visible,
+                (metadata.width, metadata.height),
+                metadata.alpha_mode,
+                mipmaps,
+            )
+            .into_iter()
+            .map(|(pixels, (width, height))| {
+                let (format, data) =
+                    encode_body(&pixels, level_format, compress_map, (width, height))?;
+                let level = MipLevel {
+                    width,
+                    height,
+                    layout: format.layout,
+                    coords: format.coords,
+                    len: data.len() as u64,
+                };
+                Ok((level, data.to_vec()))
+            })
+            .collect::<Result<Vec<_>, NPNGError>>()?;
+            Some(encode_levels(mipmaps.filter, levels)?)
+        }
+        None => None,
+    };
+
+    /* ===== Thumbnail ===== */
+    let thumbnail = match config.thumbnail {
+        Some(size) => Some(encode_thumbnail(
+            &visible,
+            (metadata.width, metadata.height),
+            metadata.alpha_mode,
+            size,
+        )?),
+        None => None,
+    };
+
+    /* ===== Quantize colors ===== */
+    let mut pixels = pixels;
+    let mut palette = None;
+    if let Some(quantize) = config.quantize
+        && format.indexed
+    {
+        // covered pixels stay transparent, they are stored without colors
+        let written = format.without_coverage();
+        let kept: Vec<usize> = (0..pixels.len())
+            .filter(|&i| written.keeps(pixels[i].color))
+            .collect();
+        let exact = RecordFormat {
+            indexed: false,
+            ..format
+        };
+        let colors: Vec<WidePixel> = kept
+            .iter()
+            .map(|&i| {
+                let p = &pixels[i];
+                WidePixel::new(p.x, p.y, exact.decoded_color(p.color).unwrap_or(0))
+            })
+            .collect();
+        let (indices, quantized) = quantize.apply(&colors)?;
+        // pixels that are not written stay transparent
+        pixels.iter_mut().for_each(|p| p.color = 0);
+        for (&i, index) in kept.iter().zip(indices) {
+            pixels[i].color = u32::from_be_bytes([index, 0, 0, 0xFF]);
+        }
+        palette = Some((quantize, quantized));
+    }
+
+    /* ===== Sort pixels ===== */
+    // input index of every output pixel (only tracked for channels)
+    let mut order: Vec<usize> = Vec::new();
+    if config.pixel_order != PixelOrder::Preserve {
+        let key = config.pixel_order.key();
+        if channels.is_empty() {
+            pixels.par_sort_unstable_by_key(key);
+        } else {
+            order = (0..pixels.len()).collect();
+            order.par_sort_unstable_by_key(|&i| key(&pixels[i]));
+            pixels = order.iter().map(|&i| pixels[i].clone()).collect();
         }
+    } else if !channels.is_empty() {
+        order = (0..pixels.len()).collect();
     }
 
+    // ===== Encode pixels =====
+    let canvas = (metadata.width, metadata.height);
+    let body_format = format.without_coverage();
+    let (body_format, pixels_encoded) = if format.max_error > 0 {
+        // only written pixels are coded, residuals can look transparent
+        let written: Vec<WidePixel> = pixels
+            .iter()
+            .filter_map(|p| {
+                Some(WidePixel::new(
+                    p.x,
+                    p.y,
+                    body_format.decoded_color(p.color)?,
+                ))
+            })
+            .collect();
+        let codes = encode_residuals(&written, body_format);
+        let coded: Vec<WidePixel> = written
+            .iter()
+            .zip(codes)
+            .map(|(p, code)| WidePixel::new(p.x, p.y, code))
+            .collect();
+        encode_body(&coded, body_format, compress_map, canvas)?
+    } else {
+        encode_body(&pixels, body_format, compress_map, canvas)?
+    };
+    let coverage = encode_coverage(&pixels, format, compress_map)?;
+    let format = RecordFormat {
+        alpha_policy: format.alpha_policy,
+        ..body_format
+    };
+
     /* ===== Prepare buffer for entire image ===== */
     let mut buf = Vec::new();
 
     /* ===== Encode header ===== */
     let encoder = compress_map.encoder();
-    let header = Header::new(encoder, metadata.clone(), save_alpha, varint)?;
+    let mut header = Header::new(encoder, metadata.clone(), format.save_alpha, format.varint)?;
+    header.reserved[1] = format.max_error;
     let ser_header = serialize(&header, true)?;
     if ser_header.len() > 10_000 {
         return Err(NPNGError::Error("Header is too long".to_string()));
     }
     buf.extend(ser_header);
-
-    // ===== Encode pixels =====
-    let pixels_encoded = spawn_plain_workers(pixels, config.save_alpha, config.varint)?;
-    let pixels_encoded = compress_map.compress(pixels_encoded.into())?;
+    let mut chunks = metadata.to_chunks()?;
+    if format.coords != CoordWidth::U16 {
+        chunks.push(Chunk::new(TAG_COORDS, format.coords)?);
+    }
+    if config.alpha_policy != AlphaPolicy::Drop {
+        chunks.push(Chunk::new(TAG_ALPHA, config.alpha_policy)?);
+    }
+    if config.pixel_format != PixelFormat::Color {
+        chunks.push(Chunk::new(TAG_PIXEL_FORMAT, config.pixel_format)?);
+    }
+    if format.layout != Layout::Pixels {
+        chunks.push(Chunk::new(TAG_LAYOUT, format.layout)?);
+    }
+    if config.pixel_order != PixelOrder::Preserve {
+        chunks.push(Chunk::new(TAG_PIXEL_ORDER, config.pixel_order)?);
+    }
+    if format.color_transform != ColorTransform::None {
+        chunks.push(Chunk::new(TAG_COLOR_TRANSFORM, format.color_transform)?);
+    }
+    if let Some(palette) = palette {
+        chunks.push(Chunk::new(TAG_PALETTE, palette)?);
+    }
+    if let Some(levels) = levels {
+        chunks.push(Chunk {
+            tag: TAG_MIPMAPS,
+            data: levels,
+        });
+    }
+    if let Some(thumbnail) = thumbnail {
+        chunks.push(Chunk::new(TAG_THUMBNAIL, thumbnail)?);
+    }
+    let covered = coverage.is_some();
+    if let Some(coverage) = coverage {
+        chunks.push(Chunk::new(TAG_COVERAGE, coverage)?);
+    }
+    if !channels.is_empty() {
+        // values of pixels that are not written are dropped too,
+        // covered pixels follow the body
+        let key = config.pixel_order.key();
+        let mut kept: Vec<(usize, &WidePixel)> = order
+            .iter()
+            .copied()
+            .zip(&pixels)
+            .filter(|(_, p)| body_format.keeps(p.color))
+            .collect();
+        if format.layout.reorders() {
+            kept.sort_unstable_by_key(|(_, p)| key(p));
+        }
+        if covered {
+            kept.extend(
+                order
+                    .iter()
+                    .copied()
+                    .zip(&pixels)
+                    .filter(|(_, p)| format.keeps(p.color) && !body_format.keeps(p.color)),
+            );
+            if config.pixel_order != PixelOrder::Preserve {
+                kept.sort_unstable_by_key(|(_, p)| key(p));
+            }
+        }
+        let kept: Vec<usize> = kept.into_iter().map(|(i, _)| i).collect();
+        chunks.push(Chunk::new(
+            TAG_CHANNELS,
+            encode_channels(channels, &kept, compress_map)?,
+        )?);
+    }
+    chunks.extend(extra_chunks);
+    buf.extend(serialize(chunks, true)?);
 
     /* ===== Calculate and encode CRC32 ===== */
-    buf.extend(pixels_encoded.1);
+    buf.extend(pixels_encoded);
     hasher.update(buf.as_slice());
     let crc32 = hasher.finalize();
     buf.extend(serialize(
@@ -256,9 +838,12 @@ pub fn encode_pixel_vec_with_metadata<C: IntoCompressMap>(
 ///
 /// # Behavior
 /// 1. Opens the image file and decodes it into pixels.
-/// 2. Converts each pixel to RGBA and packs it into a `Pixel` structure.
+/// 2. Converts each pixel to RGBA and packs it into a `Pixel` structure (premultiplied
+///    if `metadata.alpha_mode` is [`AlphaMode::Premultiplied`]).
 /// 3. Updates `metadata.width` and `metadata.height` to match the image.
-/// 4. Calls `encode_pixel_vec_with_metadata` to encode pixels, applying the `config` options
+/// 4. Carries over the ICC profile, EXIF, XMP and PNG text chunks of the image into
+///    `metadata` (unless they are already set).
+/// 5. Calls `encode_pixel_vec_with_metadata` to encode pixels, applying the `config` options
 ///    and compression.
 ///
 /// # Returns
@@ -272,17 +857,12 @@ pub fn encode_image_to_npng_bytes<P: AsRef<OsStr>, C: IntoCompressMap>(
 ) -> Result<Vec<u8>, NPNGError> {
     let compress_map = compress_map.into_compress_map()?;
     /* ===== Open Image ===== */
-    let img = ImageReader::open(Path::new(&input))
-        .map_err(|e| NPNGError::Error(format!("Failed to open image: {}", e)))?
-        .with_guessed_format()
-        .map_err(|e| NPNGError::Error(format!("Failed to guess image format: {}", e)))?
-        .decode()
-        .map_err(|e| NPNGError::Error(format!("Failed to decode image: {}", e)))?;
+    let img = read_image(input, &mut metadata)?;
 
     /* Get image dimensions */
     let (width, height) = img.dimensions();
 
-    let mut pixels = Vec::with_capacity(((width * height) + 60) as usize);
+    let mut pixels = Vec::with_capacity((width as usize) * (height as usize) + 60);
 
     // Iterate over each pixel in the image
     for (x, y, p) in img.pixels() {
@@ -296,18 +876,19 @@ pub fn encode_image_to_npng_bytes<P: AsRef<OsStr>, C: IntoCompressMap>(
             | (rgba[3] as u32); // Alpha channel (ignored if !save_alpha)
 
         // Store the pixel data in the Pixels vector
-        pixels.push(Pixel {
-            x: x as u16,
-            y: y as u16,
-            color,
-        });
+        pixels.push(WidePixel { x, y, color });
     }
 
-    metadata.width = width as u16;
-    metadata.height = height as u16;
+    // decoded images have straight alpha
+    convert_alpha(
+        pixels.iter_mut().map(|p| &mut p.color),
+        AlphaMode::Straight,
+        metadata.alpha_mode,
+    );
+    metadata.width = width;
+    metadata.height = height;
 
-
-    encode_pixel_vec_with_metadata(pixels, metadata, config, compress_map)
+    encode_wide_pixel_vec_with_metadata(pixels, metadata, config, compress_map)
 }
 
 /// Encodes an image file (e.g., PNG, JPG) into an NPNG `Img` structure.
@@ -323,9 +904,12 @@ pub fn encode_image_to_npng_bytes<P: AsRef<OsStr>, C: IntoCompressMap>(
 ///
 /// # Behavior
 /// 1. Opens and decodes the image file.
-/// 2. Converts each pixel to RGBA and packs it into a `Pixel` structure.
+/// 2. Converts each pixel to RGBA and packs it into a `Pixel` structure (premultiplied
+///    if `metadata.alpha_mode` is [`AlphaMode::Premultiplied`]).
 /// 3. Updates `metadata.width` and `metadata.height`.
-/// 4. Returns an `Img` containing all pixels, encoder version, and metadata.
+/// 4. Carries over the ICC profile, EXIF, XMP and PNG text chunks of the image into
+///    `metadata` (unless they are already set).
+/// 5. Returns an `Img` containing all pixels, encoder version, and metadata.
 ///
 /// # Returns
 /// - `Ok(Img)` - Encoded image as an `Img` structure ready for further processing or encoding.
@@ -335,18 +919,19 @@ pub fn encode_image_to_npng_pixels<P: AsRef<OsStr>>(
     mut metadata: Metadata,
 ) -> Result<Img, NPNGError> {
     /* ===== Open Image ===== */
-    let img = ImageReader::open(Path::new(&input))
-        .map_err(|e| NPNGError::Error(format!("Failed to open image: {}", e)))?
-        .with_guessed_format()
-        .map_err(|e| NPNGError::Error(format!("Failed to guess image format: {}", e)))?
-        .decode()
-        .map_err(|e| NPNGError::Error(format!("Failed to decode image: {}", e)))?;
+    let img = read_image(input, &mut metadata)?;
 
     /* ===== Get image dimensions ===== */
     let (width, height) = img.dimensions();
+    if width > MAX_U16_CANVAS || height > MAX_U16_CANVAS {
+        return Err(NPNGError::Error(
+            "Image is too large for 16-bit coordinates, use `encode_image_to_npng_bytes`"
+                .to_string(),
+        ));
+    }
 
     /* ===== Create pixels buf ===== */
-    let mut pixels = Vec::with_capacity((width * height) as usize);
+    let mut pixels = Vec::with_capacity((width as usize) * (height as usize));
 
     // Iterate over each pixel in the image
     for (x, y, p) in img.pixels() {
@@ -367,8 +952,14 @@ pub fn encode_image_to_npng_pixels<P: AsRef<OsStr>>(
         });
     }
 
-    metadata.width = width as u16;
-    metadata.height = height as u16;
+    // decoded images have straight alpha
+    convert_alpha(
+        pixels.iter_mut().map(|p| &mut p.color),
+        AlphaMode::Straight,
+        metadata.alpha_mode,
+    );
+    metadata.width = width;
+    metadata.height = height;
 
     Ok(Img {
         pixels,
@@ -377,7 +968,7 @@ pub fn encode_image_to_npng_pixels<P: AsRef<OsStr>>(
             version_minor: VERSION_MINOR,
             version_metadata: VersionMetadata::from_str(VERSION_METADATA)?,
         },
-        metadata: metadata,
+        metadata,
     })
 }
 
@@ -395,6 +986,7 @@ pub fn encode_image_to_npng_pixels<P: AsRef<OsStr>>(
 ///         - [`Encoding::Plain`] - No compression.
 ///         - [`Encoding::Zlib`]  - Compress using zlib.
 ///         - [`Encoding::Zstd`]  - Compress using zstd.
+///         - [`Encoding::Rans`]  - Compress using the built-in rANS coder.
 /// - `compress_map` - Compression context used for encoding the pixel data and header.
 ///
 /// # Behavior
@@ -405,7 +997,6 @@ pub fn encode_image_to_npng_pixels<P: AsRef<OsStr>>(
 /// # Returns
 /// - `Ok(())` - Image successfully encoded and saved.
 /// - `Err(NPNGError)` - If encoding fails or writing to the file fails.
-
 pub fn encode_pixel_vec_to_npng_image<O: AsRef<OsStr>, C: IntoCompressMap>(
     output: O, // output file path
     metadata: Metadata,
@@ -498,6 +1089,140 @@ pub fn encode_img_to_npng_bytes<C: IntoCompressMap>(
     encode_pixel_vec_with_metadata(img.pixels, img.metadata, config, compress_map)
 }
 
+/// Encodes a layered [`NpngDocument`] into NPNG bytes.
+///
+/// With [`NpngDocument::composite`] the file body holds the flattened image (see
+/// [`NpngDocument::flatten`]), so every decoder can read it as a plain image, otherwise
+/// the body is empty. The layers are stored in a chunk, each compressed with `compress_map`.
+///
+/// # Returns
+/// - `Ok(Vec<u8>)` - Encoded NPNG bytes.
+/// - `Err(NPNGError)` - If flattening or encoding fails, or a layer has duplicate pixels.
+pub fn encode_document_to_npng_bytes<C: IntoCompressMap>(
+    document: NpngDocument,
+    config: Config,
+    compress_map: C,
+) -> Result<Vec<u8>, NPNGError> {
+    let compress_map = compress_map.into_compress_map()?;
+
+    let (pixels, width, height) = if document.composite {
+        document.flatten_pixels()
+    } else {
+        let (width, height) = document.canvas_size();
+        (Vec::new(), width, height)
+    };
+    let mut metadata = document.metadata.clone();
+    metadata.width = width;
+    metadata.height = height;
+
+    let format = config.record_format(CoordWidth::U16);
+    let layers = encode_layers(&document.layers, format, &compress_map)?;
+    let chunks = vec![Chunk::new(TAG_LAYERS, layers)?];
+
+    encode_file(pixels, metadata, &config, &compress_map, &[], chunks)
+}
+
+/// Decodes NPNG bytes into a layered [`NpngDocument`].
+///
+/// Files without layers decode as a document with a single `"background"` layer.
+///
+/// # Returns
+/// - `Ok(NpngDocument)` - Decoded layers and metadata.
+/// - `Err(NPNGError)` - If decoding fails.
+pub fn decode_bytes_to_document<C: IntoCompressMap>(
+    bytes: &[u8],
+    ignore_checksum: bool,
+    compress_map: C,
+) -> Result<NpngDocument, NPNGError> {
+    let compress_map = compress_map.into_compress_map()?;
+    let file = decode_file(
+        bytes,
+        false,
+        ignore_checksum,
+        &compress_map,
+        DecodeConfig::default(),
+    )?;
+    let metadata = file.img.metadata.clone();
+
+    let composite = !file.img.pixels.is_empty();
+    let layers = match find_chunk(&file.chunks, &TAG_LAYERS) {
+        Some(c) => decode_layers(
+            c.value()?,
+            file.format,
+            &file.encoding_format,
+            &compress_map,
+        )?,
+        None => vec![Layer::new("background", Img::try_from(file.img)?.pixels)],
+    };
+
+    Ok(NpngDocument {
+        layers,
+        metadata,
+        composite,
+    })
+}
+
+/// Encodes an image with auxiliary channels ([`ChannelImg`]) into NPNG bytes.
+///
+/// Channel declarations (name, type, components) and values are stored in a chunk,
+/// values compressed with `compress_map`. Values of fully transparent pixels are
+/// dropped together with the pixels. RGBA-only decoders ignore the channels.
+///
+/// # Returns
+/// - `Ok(Vec<u8>)` - Encoded NPNG bytes.
+/// - `Err(NPNGError)` - If encoding fails, a channel does not cover every pixel or
+///   channel names repeat.
+pub fn encode_channel_img_to_npng_bytes<C: IntoCompressMap>(
+    img: ChannelImg,
+    config: Config,
+    compress_map: C,
+) -> Result<Vec<u8>, NPNGError> {
+    let compress_map = compress_map.into_compress_map()?;
+    let pixels = img.img.pixels.into_iter().map(WidePixel::from).collect();
+    encode_file(
+        pixels,
+        img.img.metadata,
+        &config,
+        &compress_map,
+        &img.channels,
+        Vec::new(),
+    )
+}
+
+/// Decodes NPNG bytes into an image with its auxiliary channels ([`ChannelImg`]).
+///
+/// Files without channels decode with an empty channel list.
+/// See [`decode_bytes_to_pixel_vec`].
+pub fn decode_bytes_to_channel_img<C: IntoCompressMap>(
+    bytes: &[u8],
+    check_image_size: bool,
+    ignore_checksum: bool,
+    compress_map: C,
+) -> Result<ChannelImg, NPNGError> {
+    let compress_map = compress_map.into_compress_map()?;
+    let file = decode_file(
+        bytes,
+        check_image_size,
+        ignore_checksum,
+        &compress_map,
+        DecodeConfig::default(),
+    )?;
+
+    let channels = match find_chunk(&file.chunks, &TAG_CHANNELS) {
+        Some(c) => decode_channels(
+            c.value()?,
+            file.img.pixels.len(),
+            &file.encoding_format,
+            &compress_map,
+        )?,
+        None => Vec::new(),
+    };
+
+    let mut img = ChannelImg::new(Img::try_from(file.img)?);
+    img.channels = channels;
+    Ok(img)
+}
+
 /// Decodes NPNG bytes into a vector of [`Pixel`]s along with metadata (`Img`).
 ///
 /// # Img Structure
@@ -528,8 +1253,320 @@ pub fn decode_bytes_to_pixel_vec<C: IntoCompressMap>(
     ignore_checksum: bool,
     compress_map: C,
 ) -> Result<Img, NPNGError> {
+    decode_bytes_to_pixel_vec_with_config(
+        bytes,
+        check_image_size,
+        ignore_checksum,
+        compress_map,
+        DecodeConfig::default(),
+    )
+}
+
+/// Decodes NPNG bytes into an [`Img`], applying [`DecodeConfig`] options.
+///
+/// Same as [`decode_bytes_to_pixel_vec`], plus:
+/// - `decode_config.color_space` - if set, pixel colors are converted from the stored
+///   color space into it, and `metadata.color_space` is updated.
+/// - `decode_config.alpha_mode` - if set, pixel colors are converted into this alpha
+///   mode (straight/premultiplied), and `metadata.alpha_mode` is updated.
+///
+/// Returns an error for files with pixels outside 16-bit coordinates, use
+/// [`decode_bytes_to_wide_pixel_vec_with_config`] for those.
+pub fn decode_bytes_to_pixel_vec_with_config<C: IntoCompressMap>(
+    bytes: &[u8],
+    check_image_size: bool,
+    ignore_checksum: bool,
+    compress_map: C,
+    decode_config: DecodeConfig,
+) -> Result<Img, NPNGError> {
+    Img::try_from(decode_bytes_to_wide_pixel_vec_with_config(
+        bytes,
+        check_image_size,
+        ignore_checksum,
+        compress_map,
+        decode_config,
+    )?)
+}
+
+/// Decodes NPNG bytes into a vector of [`WidePixel`]s (32-bit coordinates) along with
+/// metadata ([`WideImg`]).
+///
+/// Reads files written with either coordinate width. See [`decode_bytes_to_pixel_vec`].
+pub fn decode_bytes_to_wide_pixel_vec<C: IntoCompressMap>(
+    bytes: &[u8],
+    check_image_size: bool,
+    ignore_checksum: bool,
+    compress_map: C,
+) -> Result<WideImg, NPNGError> {
+    decode_bytes_to_wide_pixel_vec_with_config(
+        bytes,
+        check_image_size,
+        ignore_checksum,
+        compress_map,
+        DecodeConfig::default(),
+    )
+}
+
+/// Decodes NPNG bytes into a [`WideImg`], applying [`DecodeConfig`] options.
+///
+/// See [`decode_bytes_to_pixel_vec_with_config`].
+pub fn decode_bytes_to_wide_pixel_vec_with_config<C: IntoCompressMap>(
+    bytes: &[u8],
+    check_image_size: bool,
+    ignore_checksum: bool,
+    compress_map: C,
+    decode_config: DecodeConfig,
+) -> Result<WideImg, NPNGError> {
     let compress_map = compress_map.into_compress_map()?;
+    Ok(decode_file(
+        bytes,
+        check_image_size,
+        ignore_checksum,
+        &compress_map,
+        decode_config,
+    )?
+    .img)
+}
+
+/// Reads the encoding options a file was written with, without decoding the pixels.
+///
+/// `save_alpha`, `varint`, `pixel_order`, `alpha_policy`, `pixel_format`, `layout`,
+/// `color_transform`, `quantize`, `max_error`, `mipmaps` (stored level count) and
+/// `thumbnail` (largest stored side) come from the file, other fields
+/// are left at their defaults.
+///
+/// # Returns
+/// - `Ok(Config)` - Encoding options of the file.
+/// - `Err(NPNGError)` - If the header or chunks are invalid.
+pub fn read_encoding_config(bytes: &[u8]) -> Result<Config, NPNGError> {
+    let file = parse_file(bytes, true)?;
+    let format = record_format(&file.header, &file.chunks)?;
+    Ok(Config {
+        save_alpha: format.save_alpha,
+        varint: format.varint,
+        alpha_policy: format.alpha_policy,
+        pixel_format: format.pixel_format,
+        layout: format.layout,
+        pixel_order: format.pixel_order,
+        color_transform: format.color_transform,
+        max_error: format.max_error,
+        quantize: match find_chunk(&file.chunks, &TAG_PALETTE) {
+            Some(c) => Some(c.value::<(Quantize, Palette)>()?.0),
+            None => None,
+        },
+        mipmaps: match find_chunk(&file.chunks, &TAG_MIPMAPS) {
+            Some(c) => {
+                let (filter, levels, _) = read_index(&c.data)?;
+                Some(Mipmaps::new(levels.len() as u8, filter))
+            }
+            None => None,
+        },
+        thumbnail: match find_chunk(&file.chunks, &TAG_THUMBNAIL) {
+            Some(c) => {
+                let thumbnail: ThumbnailRecord = c.value()?;
+                Some(thumbnail.width.max(thumbnail.height) as u16)
+            }
+            None => None,
+        },
+        ..Config::default()
+    })
+}
+
+/// Reads the palette and quantization error of a file encoded with `Config.quantize`.
+///
+/// # Returns
+/// - `Ok(Some(Palette))` - Palette colors and the mean squared error ([`Palette::psnr`]).
+/// - `Ok(None)` - If the colors are not quantized.
+/// - `Err(NPNGError)` - If the header or chunks are invalid.
+pub fn read_palette(bytes: &[u8]) -> Result<Option<Palette>, NPNGError> {
+    let file = parse_file(bytes, true)?;
+    match find_chunk(&file.chunks, &TAG_PALETTE) {
+        Some(c) => Ok(Some(c.value::<(Quantize, Palette)>()?.1)),
+        None => Ok(None),
+    }
+}
+
+/// Decodes one level of the resolution pyramid stored with `Config.mipmaps`.
+///
+/// Level 0 is the image itself (see [`decode_bytes_to_wide_pixel_vec`]), level `n` is
+/// downsampled `n` times by 2. Only the requested level is decompressed and decoded,
+/// the other levels and the pixel body are only read to verify the checksum.
+///
+/// # Parameters
+/// - `bytes` - Slice of bytes representing the encoded NPNG image.
+/// - `level` - Pyramid level, see [`read_encoding_config`] for the stored level count.
+/// - `ignore_checksum` - If `true`, CRC32 checksum verification will be skipped (not recommended).
+/// - `compress_map` - Compression context used to decompress the level.
+///
+/// # Returns
+/// - `Ok(WideImg)` - Pixels of the level, `metadata.width` and `metadata.height` are the
+///   level size.
+/// - `Err(NPNGError)` - If the file is invalid or has no such level.
+pub fn decode_level<C: IntoCompressMap>(
+    bytes: &[u8],
+    level: u8,
+    ignore_checksum: bool,
+    compress_map: C,
+) -> Result<WideImg, NPNGError> {
+    let compress_map = compress_map.into_compress_map()?;
+    if level == 0 {
+        return Ok(decode_file(
+            bytes,
+            false,
+            ignore_checksum,
+            &compress_map,
+            DecodeConfig::default(),
+        )?
+        .img);
+    }
+
+    if !ignore_checksum {
+        verify_checksum(bytes)?;
+    }
+    let (header, chunk_refs, _) = read_header_refs(bytes)?
+        .ok_or_else(|| NPNGError::InvalidHeader("Header is incomplete".to_string()))?;
+    check_version(&header)?;
+    let levels = chunk_refs
+        .iter()
+        .find(|c| c.tag == TAG_MIPMAPS)
+        .ok_or_else(|| NPNGError::Error("No mipmap levels stored".to_string()))?;
+    let (stored, data) = read_level(levels.data, level)?;
+    // levels are not copied with the other chunks
+    let chunks: Vec<Chunk> = chunk_refs
+        .iter()
+        .filter(|c| c.tag != TAG_MIPMAPS)
+        .map(|c| c.to_chunk())
+        .collect();
+    let format = level_format(record_format(&header, &chunks)?);
+
+    let format = RecordFormat {
+        layout: stored.layout,
+        coords: stored.coords,
+        pixel_order: PixelOrder::Preserve,
+        ..format
+    };
+    let uncompressed =
+        compress_map.decompress(Bytes::copy_from_slice(data), &header.encoding_format)?;
+    let pixels = decode_body(uncompressed, format, (stored.width, stored.height))?;
+    check_canvas_bounds(&pixels, stored.width, stored.height)?;
+    check_duplicates(&pixels, stored.width, stored.height)?;
+
+    let encoder_version = encoder_version(&header)?;
+    let mut metadata = header.metadata;
+    metadata.apply_chunks(&chunks)?;
+    metadata.width = stored.width;
+    metadata.height = stored.height;
+    Ok(WideImg {
+        pixels,
+        encoder_version,
+        metadata,
+    })
+}
+
+/// Reads the thumbnail stored with `Config.thumbnail`.
+///
+/// Only the header and chunks are parsed, the pixel body is not read and the checksum
+/// is not verified, so `bytes` can be the start of a file that is still arriving.
+///
+/// # Returns
+/// - `Ok(Some(RgbaImage))` - Thumbnail with straight alpha.
+/// - `Ok(None)` - If the file has no thumbnail.
+/// - `Err(NPNGError)` - If the header or chunks are invalid or incomplete.
+pub fn read_thumbnail(bytes: &[u8]) -> Result<Option<RgbaImage>, NPNGError> {
+    let (_, chunks, _) = read_header(bytes)?
+        .ok_or_else(|| NPNGError::InvalidHeader("Header is incomplete".to_string()))?;
+    match find_chunk(&chunks, &TAG_THUMBNAIL) {
+        Some(c) => Ok(Some(decode_thumbnail(c.value()?)?)),
+        None => Ok(None),
+    }
+}
+
+/// Read the header and chunks at the start of `bytes` without the checksum.
+///
+/// # Returns
+/// - `Ok(Some((header, chunks, body start)))`
+/// - `Ok(None)` - If the header or chunks are incomplete.
+/// - `Err(NPNGError)` - If the magic bytes, the header or the received chunks are invalid.
+fn read_header(bytes: &[u8]) -> Result<Option<(Header, Vec<Chunk>, usize)>, NPNGError> {
+    Ok(read_header_refs(bytes)?.map(|(header, chunks, body)| {
+        (
+            header,
+            chunks.into_iter().map(ChunkRef::to_chunk).collect(),
+            body,
+        )
+    }))
+}
+
+/// [`read_header`] with chunks borrowed from `bytes`
+fn read_header_refs(bytes: &[u8]) -> Result<Option<(Header, Vec<ChunkRef<'_>>, usize)>, NPNGError> {
+    let magic = [0x00, 0x4E, 0x00, 0x50, 0x00, 0x4E, 0x00, 0x47, 0x00];
+    let n = bytes.len().min(magic.len());
+    if bytes[..n] != magic[..n] {
+        return Err(NPNGError::InvalidHeader("Invalid magic bytes".to_string()));
+    }
+    let delimiter = [0xFF; 6];
+    let Some(end) = bytes
+        .windows(delimiter.len())
+        .position(|w| w == delimiter)
+        .map(|pos| pos + delimiter.len())
+    else {
+        if bytes.len() > 10_000 {
+            return Err(NPNGError::InvalidHeader("Header is too long".to_string()));
+        }
+        return Ok(None);
+    };
+    let header: Header = deserialize(bytes[..end].to_vec(), true)
+        .map_err(|e| NPNGError::InvalidHeader(format!("Header decoding error: {}", e)))?;
+
+    if header.reserved[0] & FLAG_CHUNKS == 0 {
+        return Ok(Some((header, Vec::new(), end)));
+    }
+    Ok(read_received_chunks(&bytes[end..])?.map(|(chunks, len)| (header, chunks, end + len)))
+}
 
+/// Verified file split into header, chunks and compressed body
+struct ParsedFile<'a> {
+    header: Header,
+    chunks: Vec<Chunk>,
+    body: &'a [u8],
+}
+
+/// Record format of a file (header flags and chunks)
+fn record_format(header: &Header, chunks: &[Chunk]) -> Result<RecordFormat, NPNGError> {
+    Ok(RecordFormat {
+        save_alpha: header.alpha,
+        varint: header.varint,
+        coords: match find_chunk(chunks, &TAG_COORDS) {
+            Some(c) => c.value()?,
+            None => CoordWidth::U16,
+        },
+        alpha_policy: match find_chunk(chunks, &TAG_ALPHA) {
+            Some(c) => c.value()?,
+            None => AlphaPolicy::Drop,
+        },
+        pixel_format: match find_chunk(chunks, &TAG_PIXEL_FORMAT) {
+            Some(c) => c.value()?,
+            None => PixelFormat::Color,
+        },
+        layout: match find_chunk(chunks, &TAG_LAYOUT) {
+            Some(c) => c.value()?,
+            None => Layout::Pixels,
+        },
+        pixel_order: match find_chunk(chunks, &TAG_PIXEL_ORDER) {
+            Some(c) => c.value()?,
+            None => PixelOrder::Preserve,
+        },
+        color_transform: match find_chunk(chunks, &TAG_COLOR_TRANSFORM) {
+            Some(c) => c.value()?,
+            None => ColorTransform::None,
+        },
+        indexed: find_chunk(chunks, &TAG_PALETTE).is_some(),
+        max_error: header.reserved[1],
+    })
+}
+
+/// Check magic bytes and checksum, then read the header and chunks
+fn parse_file(bytes: &[u8], ignore_checksum: bool) -> Result<ParsedFile<'_>, NPNGError> {
     /* ===== Check header len ===== */
     if bytes.len() < 9 {
         return Err(NPNGError::InvalidHeader("Header is too short".to_string()));
@@ -541,28 +1578,14 @@ pub fn decode_bytes_to_pixel_vec<C: IntoCompressMap>(
         return Err(NPNGError::InvalidHeader("Invalid magic bytes".to_string())); // Return err if magic bytes not .. N .. P .. N .. G ..
     }
 
-    /* ===== Get CRC32 Checksum stored in file ===== */
-    let check_sum = {
-        // Determine the starting index of the checksum section
-        let checksum_start = bytes.len() - 20;
-
-        // Extract the raw checksum bytes
-        let raw_checksum = bytes[checksum_start..].to_vec();
-
-        // Deserialize the checksum
-        let checksum_struct: CheckSum = match deserialize(raw_checksum.to_owned(), false) {
-            Ok(c) => c,
-            Err(_) => {
-                // Return error if checksum is bad
-                return Err(NPNGError::InvalidChecksum("broken checksum section".to_string()));
-            }
-        };
-
-        // Return the CRC32 value from the deserialized checksum
-        checksum_struct
+    /* ===== Verify the CRC32 checksum stored in the file ===== */
+    if !ignore_checksum {
+        verify_checksum(bytes)?;
+    } else if bytes.len() < 20 {
+        return Err(NPNGError::InvalidChecksum(
+            "broken checksum section".to_string(),
+        ));
     }
-    .crc32;
-    let mut hasher = Hasher::new();
 
     let delimiter = [0xFF; 6]; // FF FF FF FF FF FF
     let header_end_pos = bytes
@@ -576,14 +1599,9 @@ pub fn decode_bytes_to_pixel_vec<C: IntoCompressMap>(
             if header.len() > 10_000 {
                 return Err(NPNGError::InvalidHeader("Header is too long".to_string())); // Return Err if header is too long (>10KB)
             }
-            let body = &bytes[end..bytes.len() - 20];
-
-            hasher.update(header);
-            hasher.update(body);
-            let h = hasher.finalize();
-            if check_sum != h && !ignore_checksum {
-                return Err(NPNGError::InvalidChecksum("Image is corrupted".to_string())); // Return error if CRC32 does not match the CheckSum section
-            }
+            let body = bytes
+                .get(end..bytes.len() - 20)
+                .ok_or_else(|| NPNGError::InvalidChecksum("broken checksum section".to_string()))?;
 
             /* ===== Deserialize the header into a Header struct ===== */
             let header_decoded =
@@ -591,61 +1609,176 @@ pub fn decode_bytes_to_pixel_vec<C: IntoCompressMap>(
                     NPNGError::InvalidHeader(format!("Header decoding error: {}", e))
                 })?;
 
-            if header_decoded.version_major != VERSION_MAJOR {
-                #[cfg(feature = "log")]
-                warn!("Image version differs from crate version");
-                #[cfg(not(feature = "log"))]
-                return Err(NPNGError::Error("Image version differs from crate version".to_string()));
-            }
-            let save_alpha = header_decoded.alpha;
-            let varint = header_decoded.varint;
-            let mut result = Img {
-                pixels: Vec::new(), // Empty vec, filling after pixel decoding
-                encoder_version: EncoderVersion {
-                    version_minor: header_decoded.version_minor, //==============================================
-                    version_major: header_decoded.version_major, //=== Construct a structure with versions
-                    version_metadata: VersionMetadata::from_str( //================================================
-                        header_decoded.version_metadata.as_str(),
-                    )?,
-                },
-                metadata: header_decoded.metadata,
+            check_version(&header_decoded)?;
+
+            /* ===== Read chunks stored after the header ===== */
+            let (chunks, body) = if header_decoded.reserved[0] & FLAG_CHUNKS != 0 {
+                let (chunks, len) = read_chunks(body)?;
+                (chunks, &body[len..])
+            } else {
+                (Vec::new(), body)
             };
 
-            let format = header_decoded.encoding_format.clone();
-            let uncompressed =
-                compress_map.decompress(Bytes::copy_from_slice(body), format.as_str())?;
-            let decoded = spawn_plain_decode_workers(uncompressed, save_alpha, varint)?;
-            if decoded.len() > MAX_PIXELS {
-                return Err(NPNGError::Error("Pixel vec is too long".to_string()));
-            }
-            /* ===== Check for duplicate coordinates === */
-            {
-                let mut bitmap = vec![0u8; (MAX_PIXELS) / 8]; // 512 MB
-
-                for p in &decoded {
-                    let idx = (p.y as usize) * SIZE + (p.x as usize);
-                    let byte = idx / 8;
-                    let bit = idx % 8;
-                    let mask = 1 << bit;
-                    if bitmap[byte] & mask != 0 {
-                        return Err(NPNGError::DuplicatePixel(p.x, p.y));
-                    }
-                    bitmap[byte] |= mask;
-                }
-            }
+            Ok(ParsedFile {
+                header: header_decoded,
+                chunks,
+                body,
+            })
+        }
+        None => Err(NPNGError::Error("Invalid header".to_string())),
+    }
+}
 
-            if check_image_size {
-                let real_size = check_image_size_f(decoded.clone());
-                result.metadata.width = real_size.0;
-                result.metadata.height = real_size.1;
-            }
+/// Compare the CRC32 stored at the end of `bytes` with the one of the rest of the file
+fn verify_checksum(bytes: &[u8]) -> Result<(), NPNGError> {
+    let Some(checksum_start) = bytes.len().checked_sub(20) else {
+        return Err(NPNGError::InvalidChecksum(
+            "broken checksum section".to_string(),
+        ));
+    };
+    let check_sum: CheckSum = deserialize(bytes[checksum_start..].to_vec(), false)
+        .map_err(|_| NPNGError::InvalidChecksum("broken checksum section".to_string()))?;
+    let mut hasher = Hasher::new();
+    hasher.update(&bytes[..checksum_start]);
+    if check_sum.crc32 != hasher.finalize() {
+        return Err(NPNGError::InvalidChecksum("Image is corrupted".to_string())); // Return error if CRC32 does not match the CheckSum section
+    }
+    Ok(())
+}
 
-            result.pixels = decoded;
+/// Check that the file was written with the same major format version
+fn check_version(header: &Header) -> Result<(), NPNGError> {
+    if header.version_major != VERSION_MAJOR {
+        #[cfg(feature = "log")]
+        warn!("Image version differs from crate version");
+        #[cfg(not(feature = "log"))]
+        return Err(NPNGError::Error(
+            "Image version differs from crate version".to_string(),
+        ));
+    }
+    Ok(())
+}
 
-            Ok(result)
+/// Versions of the encoder that wrote `header`
+fn encoder_version(header: &Header) -> Result<EncoderVersion, NPNGError> {
+    Ok(EncoderVersion {
+        version_minor: header.version_minor, //==============================================
+        version_major: header.version_major, //=== Construct a structure with versions
+        version_metadata: VersionMetadata::from_str(
+            //================================================
+            header.version_metadata.as_str(),
+        )?,
+    })
+}
+
+/// Record format of stored pyramid levels: exact colors, layout and coordinates per level
+fn level_format(format: RecordFormat) -> RecordFormat {
+    RecordFormat {
+        color_transform: ColorTransform::None,
+        indexed: false,
+        max_error: 0,
+        ..format
+    }
+}
+
+/// Replace palette indices with their colors (files with a palette chunk)
+fn apply_palette(pixels: &mut [WidePixel], chunks: &[Chunk]) -> Result<(), NPNGError> {
+    if let Some(chunk) = find_chunk(chunks, &TAG_PALETTE) {
+        let (_, palette): (Quantize, Palette) = chunk.value()?;
+        for p in pixels.iter_mut() {
+            p.color = *palette
+                .colors
+                .get((p.color >> 24) as usize)
+                .ok_or_else(|| NPNGError::Error("Palette index out of range".to_string()))?;
         }
-        None => Err(NPNGError::Error("Invalid header".to_string())),
     }
+    Ok(())
+}
+
+/// Decoded file together with the parts needed to read extension chunks
+struct DecodedFile {
+    img: WideImg,
+    chunks: Vec<Chunk>,
+    format: RecordFormat,
+    encoding_format: String,
+}
+
+/// Verify and decode a complete file
+fn decode_file(
+    bytes: &[u8],
+    check_image_size: bool,
+    ignore_checksum: bool,
+    compress_map: &CompressMap,
+    decode_config: DecodeConfig,
+) -> Result<DecodedFile, NPNGError> {
+    let ParsedFile {
+        header,
+        chunks,
+        body,
+    } = parse_file(bytes, ignore_checksum)?;
+    let format = record_format(&header, &chunks)?;
+
+    let mut result = WideImg {
+        pixels: Vec::new(), // Empty vec, filling after pixel decoding
+        encoder_version: encoder_version(&header)?,
+        metadata: header.metadata,
+    };
+    result.metadata.apply_chunks(&chunks)?;
+
+    let encoding_format = header.encoding_format;
+    let canvas = (result.metadata.width, result.metadata.height);
+    let mut decoded = if format.layout == Layout::Progressive {
+        decode_progressive(body, format, compress_map, &encoding_format, canvas)?
+    } else {
+        let uncompressed =
+            compress_map.decompress(Bytes::copy_from_slice(body), &encoding_format)?;
+        decode_body(uncompressed, format, canvas)?
+    };
+    if format.max_error > 0 {
+        decode_residuals(&mut decoded, format);
+    }
+    apply_palette(&mut decoded, &chunks)?;
+    if let Some(c) = find_chunk(&chunks, &TAG_COVERAGE) {
+        decoded.extend(decode_coverage(
+            c.value()?,
+            format,
+            &encoding_format,
+            compress_map,
+        )?);
+        if format.pixel_order != PixelOrder::Preserve {
+            decoded.par_sort_unstable_by_key(format.pixel_order.key());
+        }
+    }
+    if format.coords == CoordWidth::U16 && decoded.len() > MAX_PIXELS {
+        return Err(NPNGError::Error("Pixel vec is too long".to_string()));
+    }
+
+    if check_image_size || result.metadata.width == 0 || result.metadata.height == 0 {
+        let real_size = check_image_size_f(&decoded)?;
+        result.metadata.width = real_size.0;
+        result.metadata.height = real_size.1;
+    } else {
+        check_canvas_bounds(&decoded, result.metadata.width, result.metadata.height)?;
+    }
+
+    /* ===== Check for duplicate coordinates === */
+    check_duplicates(&decoded, result.metadata.width, result.metadata.height)?;
+
+    result.pixels = decoded;
+
+    if let Some(color_space) = decode_config.color_space {
+        result.convert_color_space(color_space)?;
+    }
+    if let Some(alpha_mode) = decode_config.alpha_mode {
+        result.convert_alpha_mode(alpha_mode);
+    }
+
+    Ok(DecodedFile {
+        img: result,
+        chunks,
+        format,
+        encoding_format,
+    })
 }
 
 /// Decodes NPNG bytes into a standard image file (e.g., PNG, JPG) and saves it.
@@ -658,8 +1791,10 @@ pub fn decode_bytes_to_pixel_vec<C: IntoCompressMap>(
 ///
 /// # Behavior
 /// 1. Decodes NPNG bytes into pixels and metadata using `decode_bytes_to_pixel_vec`.
-/// 2. Creates an `ImageBuffer` and populates it with decoded RGBA pixel data.
-/// 3. Saves the buffer to the specified output file path.
+/// 2. Creates an `ImageBuffer` of the declared canvas size and populates it with decoded
+///    RGBA pixel data.
+/// 3. Saves the buffer to the specified output file path. The ICC profile, EXIF and XMP
+///    are written for PNG and JPEG outputs, text entries for PNG outputs.
 ///
 /// # Returns
 /// - `Ok((EncoderVersion, Metadata))` - Tuple containing the encoder version and image metadata.
@@ -669,22 +1804,49 @@ pub fn decode_bytes_to_image<O: AsRef<OsStr>, C: IntoCompressMap>(
     output: O,
     ignore_checksum: bool,
     compress_map: C,
+) -> Result<(EncoderVersion, Metadata), NPNGError> {
+    decode_bytes_to_image_with_config(
+        bytes,
+        output,
+        ignore_checksum,
+        compress_map,
+        DecodeConfig::default(),
+    )
+}
+
+/// Decodes NPNG bytes into a standard image file, applying [`DecodeConfig`] options.
+///
+/// See [`decode_bytes_to_image`] and [`decode_bytes_to_pixel_vec_with_config`].
+pub fn decode_bytes_to_image_with_config<O: AsRef<OsStr>, C: IntoCompressMap>(
+    bytes: &[u8],
+    output: O,
+    ignore_checksum: bool,
+    compress_map: C,
+    mut decode_config: DecodeConfig,
 ) -> Result<(EncoderVersion, Metadata), NPNGError> {
     let compress_map = compress_map.into_compress_map()?;
+    // image files use straight alpha
+    decode_config.alpha_mode.get_or_insert(AlphaMode::Straight);
 
-    let img = decode_bytes_to_pixel_vec(bytes, true, ignore_checksum, compress_map)?;
+    let img = decode_bytes_to_wide_pixel_vec_with_config(
+        bytes,
+        false,
+        ignore_checksum,
+        compress_map,
+        decode_config,
+    )?;
     let metadata = img.metadata.clone();
     let version = img.encoder_version.clone();
 
-    let width = img.metadata.width as u32;
-    let height = img.metadata.height as u32;
+    let width = img.metadata.width;
+    let height = img.metadata.height;
 
     let mut buffer = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
 
     // === Adding Pixels ===
     for pixel in &img.pixels {
-        let x = pixel.x as u32;
-        let y = pixel.y as u32;
+        let x = pixel.x;
+        let y = pixel.y;
 
         let r = ((pixel.color >> 24) & 0xFF) as u8;
         let g = ((pixel.color >> 16) & 0xFF) as u8;
@@ -695,10 +1857,7 @@ pub fn decode_bytes_to_image<O: AsRef<OsStr>, C: IntoCompressMap>(
     }
 
     // === Saving Image ===
-    let path = Path::new(&output);
-    buffer
-        .save(path)
-        .map_err(|e| NPNGError::Error(format!("Failed to save image: {}", e)))?;
+    save_image(&buffer, Path::new(&output), &metadata)?;
 
     Ok((version, metadata))
 }
@@ -767,7 +1926,8 @@ pub fn decode_npng_file_to_pixels<I: AsRef<OsStr>, C: IntoCompressMap>(
 ///
 /// # Behavior
 /// 1. Decodes the NPNG bytes into pixels and metadata using `decode_bytes_to_pixel_vec`.
-/// 2. Creates an `ImageBuffer<Rgba<u8>, Vec<u8>>` and populates it with decoded pixel data.
+/// 2. Creates an `ImageBuffer<Rgba<u8>, Vec<u8>>` of the declared canvas size and populates
+///    it with decoded pixel data.
 /// 3. Returns the image buffer along with the metadata.
 ///
 /// # Returns
@@ -777,19 +1937,44 @@ pub fn decode_npng_bytes_to_image_buffer<C: IntoCompressMap>(
     bytes: &[u8],
     ignore_checksum: bool,
     compress_map: C,
-) -> Result<(ImageBuffer<Rgba<u8>, Vec<u8>>, Metadata), NPNGError> {
+) -> Result<(RgbaImage, Metadata), NPNGError> {
+    decode_npng_bytes_to_image_buffer_with_config(
+        bytes,
+        ignore_checksum,
+        compress_map,
+        DecodeConfig::default(),
+    )
+}
+
+/// Decodes NPNG bytes into an `ImageBuffer`, applying [`DecodeConfig`] options.
+///
+/// See [`decode_npng_bytes_to_image_buffer`] and [`decode_bytes_to_pixel_vec_with_config`].
+pub fn decode_npng_bytes_to_image_buffer_with_config<C: IntoCompressMap>(
+    bytes: &[u8],
+    ignore_checksum: bool,
+    compress_map: C,
+    mut decode_config: DecodeConfig,
+) -> Result<(RgbaImage, Metadata), NPNGError> {
     let compress_map = compress_map.into_compress_map()?;
+    // image buffers use straight alpha unless asked otherwise
+    decode_config.alpha_mode.get_or_insert(AlphaMode::Straight);
 
-    let img = decode_bytes_to_pixel_vec(bytes, true, ignore_checksum, compress_map)?;
+    let img = decode_bytes_to_wide_pixel_vec_with_config(
+        bytes,
+        false,
+        ignore_checksum,
+        compress_map,
+        decode_config,
+    )?;
 
-    let width = img.metadata.width as u32;
-    let height = img.metadata.height as u32;
+    let width = img.metadata.width;
+    let height = img.metadata.height;
 
     let mut buffer = ImageBuffer::<Rgba<u8>, Vec<u8>>::new(width, height);
 
     for pixel in &img.pixels {
-        let x = pixel.x as u32;
-        let y = pixel.y as u32;
+        let x = pixel.x;
+        let y = pixel.y;
 
         let r = ((pixel.color >> 24) & 0xFF) as u8;
         let g = ((pixel.color >> 16) & 0xFF) as u8;
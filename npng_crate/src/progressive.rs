@@ -0,0 +1,292 @@
+use crate::coding::{RecordFormat, decode_body, encode_body};
+use crate::compression::CompressMap;
+use crate::error::NPNGError;
+use crate::types::WideImg;
+use crate::types::chunk::Chunk;
+use crate::types::color::convert_alpha;
+use crate::types::metadata::Metadata;
+use crate::types::pixel::WidePixel;
+use crate::{
+    AlphaMode, DecodeConfig, IntoCompressMap, Layout, apply_palette, decode_file, read_header,
+    record_format,
+};
+/// `progressive.rs` - Adam7 passes and the streaming decoder
+use bytes::{Bytes, BytesMut};
+use image::{Rgba, RgbaImage};
+
+/// Adam7 passes: first pixel and step in an 8x8 tile, then the preview block size
+const ADAM7: [(u32, u32, u32, u32, u32, u32); 7] = [
+    (0, 0, 8, 8, 8, 8),
+    (4, 0, 8, 8, 4, 8),
+    (0, 4, 4, 8, 4, 4),
+    (2, 0, 4, 4, 2, 4),
+    (0, 2, 2, 4, 2, 2),
+    (1, 0, 2, 2, 1, 2),
+    (0, 1, 1, 2, 1, 1),
+];
+
+/// Largest canvas (pixels) with previews, larger files only decode with
+/// [`ProgressiveDecoder::finish`]
+const MAX_PREVIEW_PIXELS: u64 = 1 << 26;
+
+/// Adam7 pass of a pixel
+fn pass_of(x: u32, y: u32) -> usize {
+    let (x, y) = (x % 8, y % 8);
+    ADAM7
+        .iter()
+        .position(|&(x0, y0, dx, dy, _, _)| x % dx == x0 && y % dy == y0)
+        .unwrap()
+}
+
+/// Pass records of every pass (`Pixels` layout), each compressed on its own
+/// and prefixed with its length (`u32`, little endian)
+pub(crate) fn encode_progressive(
+    pixels: &[WidePixel],
+    format: RecordFormat,
+    compress_map: &CompressMap,
+    canvas: (u32, u32),
+) -> Result<BytesMut, NPNGError> {
+    let mut passes: [Vec<WidePixel>; 7] = Default::default();
+    for p in pixels {
+        passes[pass_of(p.x, p.y)].push(p.clone());
+    }
+    let format = RecordFormat {
+        layout: Layout::Pixels,
+        ..format
+    };
+
+    let mut buf = BytesMut::new();
+    for pass in &passes {
+        let (_, encoded) = encode_body(pass, format, compress_map, canvas)?;
+        let len = u32::try_from(encoded.len())
+            .map_err(|_| NPNGError::Error("Progressive pass is too long".to_string()))?;
+        buf.extend_from_slice(&len.to_le_bytes());
+        buf.extend(encoded);
+    }
+    Ok(buf)
+}
+
+/// Next complete pass at `pos`: its data and the position after it
+fn read_pass(body: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let len = body.get(pos..pos + 4)?;
+    let len = u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    let start = pos + 4;
+    Some((body.get(start..start.checked_add(len)?)?, start + len))
+}
+
+/// Decompress and decode one pass
+fn decode_pass(
+    data: &[u8],
+    format: RecordFormat,
+    compress_map: &CompressMap,
+    encoding_format: &str,
+    canvas: (u32, u32),
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let uncompressed = compress_map.decompress(Bytes::copy_from_slice(data), encoding_format)?;
+    decode_body(
+        uncompressed,
+        RecordFormat {
+            layout: Layout::Pixels,
+            ..format
+        },
+        canvas,
+    )
+}
+
+/// Decode every pass of a progressive body (pixels in `pixel_order`)
+pub(crate) fn decode_progressive(
+    body: &[u8],
+    format: RecordFormat,
+    compress_map: &CompressMap,
+    encoding_format: &str,
+    canvas: (u32, u32),
+) -> Result<Vec<WidePixel>, NPNGError> {
+    let mut pixels = Vec::new();
+    let mut pos = 0;
+    for _ in 0..ADAM7.len() {
+        let (data, next) = read_pass(body, pos)
+            .ok_or_else(|| NPNGError::Error("Truncated progressive pass".to_string()))?;
+        pixels.extend(decode_pass(
+            data,
+            format,
+            compress_map,
+            encoding_format,
+            canvas,
+        )?);
+        pos = next;
+    }
+    if pos != body.len() {
+        return Err(NPNGError::Error(
+            "Data after the last progressive pass".to_string(),
+        ));
+    }
+    pixels.sort_unstable_by_key(format.pixel_order.key());
+    Ok(pixels)
+}
+
+/// Transparent image, an error if it can't be allocated
+fn blank_image(width: u32, height: u32) -> Result<RgbaImage, NPNGError> {
+    let len = width as usize * height as usize * 4;
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len)
+        .map_err(|_| NPNGError::Error(format!("Can't allocate a {width}x{height} preview")))?;
+    buf.resize(len, 0);
+    Ok(RgbaImage::from_raw(width, height, buf).unwrap())
+}
+
+/// Header and chunks of the received file
+struct Stream {
+    metadata: Metadata,
+    chunks: Vec<Chunk>,
+    format: RecordFormat,
+    encoding_format: String,
+    /// Whether passes are decoded into previews
+    previews: bool,
+}
+
+/// Streaming decoder for files with [`Layout::Progressive`].
+///
+/// Bytes are fed as they arrive with [`ProgressiveDecoder::push`], which returns a preview
+/// for every completed pass: pixels of a pass are drawn as blocks covering the pixels of
+/// the later passes, the last preview is exact (straight alpha). The checksum can only be
+/// verified on the complete file, see [`ProgressiveDecoder::finish`].
+///
+/// Files with another layout, `max_error` or a canvas over 2^26 pixels (8192x8192) have no
+/// previews (they still decode with [`ProgressiveDecoder::finish`]).
+pub struct ProgressiveDecoder {
+    compress_map: CompressMap,
+    data: Vec<u8>,
+    stream: Option<Stream>,
+    passes: usize,
+    /// Position of the next pass in `data`
+    next: usize,
+    pixels: Vec<WidePixel>,
+    preview: RgbaImage,
+}
+
+impl ProgressiveDecoder {
+    pub fn new<C: IntoCompressMap>(compress_map: C) -> Result<Self, NPNGError> {
+        Ok(ProgressiveDecoder {
+            compress_map: compress_map.into_compress_map()?,
+            data: Vec::new(),
+            stream: None,
+            passes: 0,
+            next: 0,
+            pixels: Vec::new(),
+            preview: RgbaImage::new(0, 0),
+        })
+    }
+
+    /// Metadata of the file, once the header and chunks have arrived
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.stream.as_ref().map(|s| &s.metadata)
+    }
+
+    /// Number of decoded passes (7 when complete)
+    pub fn passes(&self) -> usize {
+        self.passes
+    }
+
+    /// Add received bytes.
+    ///
+    /// # Returns
+    /// - `Ok(Vec<RgbaImage>)` - A refined preview for every pass completed by these bytes.
+    /// - `Err(NPNGError)` - If the magic bytes, header or a pass are invalid.
+    pub fn push(&mut self, bytes: &[u8]) -> Result<Vec<RgbaImage>, NPNGError> {
+        self.data.extend_from_slice(bytes);
+        if self.stream.is_none() {
+            self.stream = self.read_start()?;
+        }
+        let Some(stream) = &self.stream else {
+            return Ok(Vec::new());
+        };
+        if !stream.previews {
+            return Ok(Vec::new());
+        }
+
+        let mut previews = Vec::new();
+        let canvas = (stream.metadata.width, stream.metadata.height);
+        while self.passes < ADAM7.len() {
+            let Some((data, next)) = read_pass(&self.data, self.next) else {
+                break;
+            };
+            let mut pixels = decode_pass(
+                data,
+                stream.format,
+                &self.compress_map,
+                &stream.encoding_format,
+                canvas,
+            )?;
+            apply_palette(&mut pixels, &stream.chunks)?;
+            convert_alpha(
+                pixels.iter_mut().map(|p| &mut p.color),
+                stream.metadata.alpha_mode,
+                AlphaMode::Straight,
+            );
+
+            let (_, _, _, _, bw, bh) = ADAM7[self.passes];
+            for p in &pixels {
+                let color = Rgba(p.color.to_be_bytes());
+                for y in p.y..p.y.saturating_add(bh).min(canvas.1) {
+                    for x in p.x..p.x.saturating_add(bw).min(canvas.0) {
+                        self.preview.put_pixel(x, y, color);
+                    }
+                }
+            }
+            self.pixels.extend(pixels);
+            self.passes += 1;
+            self.next = next;
+
+            if self.passes == ADAM7.len() {
+                // blocks of earlier passes can cover pixels that are not in the image
+                self.preview.pixels_mut().for_each(|p| *p = Rgba([0; 4]));
+                for p in &self.pixels {
+                    if p.x < canvas.0 && p.y < canvas.1 {
+                        self.preview
+                            .put_pixel(p.x, p.y, Rgba(p.color.to_be_bytes()));
+                    }
+                }
+            }
+            previews.push(self.preview.clone());
+        }
+        Ok(previews)
+    }
+
+    /// Header and chunks, `None` until they are complete
+    fn read_start(&mut self) -> Result<Option<Stream>, NPNGError> {
+        let Some((header, chunks, body)) = read_header(&self.data)? else {
+            return Ok(None);
+        };
+        let format = record_format(&header, &chunks)?;
+        let mut metadata = header.metadata.clone();
+        metadata.apply_chunks(&chunks)?;
+        // the canvas size is not verified until the checksum arrives
+        let previews = format.layout == Layout::Progressive
+            && format.max_error == 0
+            && metadata.width as u64 * metadata.height as u64 <= MAX_PREVIEW_PIXELS;
+        if previews {
+            self.preview = blank_image(metadata.width, metadata.height)?;
+        }
+        self.next = body;
+
+        Ok(Some(Stream {
+            metadata,
+            chunks,
+            format,
+            encoding_format: header.encoding_format,
+            previews,
+        }))
+    }
+
+    /// Verify and decode the complete file (same as [`crate::decode_bytes_to_wide_pixel_vec`])
+    pub fn finish(self) -> Result<WideImg, NPNGError> {
+        Ok(decode_file(
+            &self.data,
+            false,
+            false,
+            &self.compress_map,
+            DecodeConfig::default(),
+        )?
+        .img)
+    }
+}
@@ -0,0 +1,294 @@
+/// `rans.rs` - built-in entropy coder for pixel streams
+///
+/// Byte-stride predictor: every byte is predicted from the byte `stride` positions
+/// earlier, the stride is picked from the data (0 - no prediction). With fixed-size
+/// records this is the same byte of the previous record, so coordinates and colors turn
+/// into deltas. Varint records (`Config.varint`) have no fixed size and are mostly coded
+/// without prediction. Residuals are coded with static rANS, one frequency table per
+/// context: the byte position modulo the stride and the size of the previous residual.
+///
+/// Stream: `len` (varint), `stride` (u8), tables, rANS state (u32 BE), rANS bytes.
+/// No symbol has the full probability, so every symbol takes stream bits and `len`
+/// is bounded by the stream size.
+use crate::error::NPNGError;
+
+const PROB_BITS: u32 = 12;
+const PROB_SCALE: u32 = 1 << PROB_BITS;
+/// Lower bound of the rANS state
+const RANS_L: u32 = 1 << 23;
+
+/// Largest record size tried
+const MAX_STRIDE: usize = 16;
+/// Buckets of the previous residual
+const BUCKETS: usize = 3;
+/// Bytes checked when picking the stride
+const STRIDE_SAMPLE: usize = 1 << 20;
+/// Most symbols decoded per stream bit: a symbol has a frequency of at most
+/// `PROB_SCALE - 1` and takes at least `-log2(1 - 1 / PROB_SCALE)` bits
+const MAX_SYMBOLS_PER_BIT: usize = 2840;
+
+fn err(msg: &str) -> NPNGError {
+    NPNGError::Error(format!("rANS: {}", msg))
+}
+
+/// Bytes minus the byte `stride` positions earlier (no prediction for `stride == 0`)
+fn residuals(data: &[u8], stride: usize) -> Vec<u8> {
+    data.iter()
+        .enumerate()
+        .map(|(i, &b)| {
+            if stride > 0 && i >= stride {
+                b.wrapping_sub(data[i - stride])
+            } else {
+                b
+            }
+        })
+        .collect()
+}
+
+fn bucket(residual: u8) -> usize {
+    match residual as i8 {
+        0 => 0,
+        -4..=4 => 1,
+        _ => 2,
+    }
+}
+
+/// Context of residual `i`
+fn context(res: &[u8], i: usize, stride: usize) -> usize {
+    let field = if stride > 0 { i % stride } else { 0 };
+    let prev = if i > 0 { bucket(res[i - 1]) } else { 0 };
+    field * BUCKETS + prev
+}
+
+/// Order-0 entropy (bits) of the residuals, per context
+fn estimate(res: &[u8], stride: usize) -> f64 {
+    let contexts = stride.max(1) * BUCKETS;
+    let mut counts = vec![[0u32; 256]; contexts];
+    for i in 0..res.len() {
+        counts[context(res, i, stride)][res[i] as usize] += 1;
+    }
+    counts
+        .iter()
+        .map(|c| {
+            let total: u32 = c.iter().sum();
+            c.iter()
+                .filter(|&&n| n > 0)
+                .map(|&n| -(n as f64) * (n as f64 / total as f64).log2())
+                .sum::<f64>()
+        })
+        .sum()
+}
+
+/// Scale symbol counts to frequencies summing to [`PROB_SCALE`] (empty stays empty).
+/// A single symbol gets an unused neighbour with frequency 1
+fn normalize(counts: &[u32; 256]) -> [u32; 256] {
+    let total: u64 = counts.iter().map(|&c| c as u64).sum();
+    let mut freqs = [0u32; 256];
+    if total == 0 {
+        return freqs;
+    }
+    let mut used = counts.iter().enumerate().filter(|&(_, &c)| c > 0);
+    if let (Some((symbol, _)), None) = (used.next(), used.next()) {
+        freqs[symbol] = PROB_SCALE - 1;
+        freqs[(symbol + 1) % 256] = 1;
+        return freqs;
+    }
+    for (f, &c) in freqs.iter_mut().zip(counts) {
+        if c > 0 {
+            *f = ((c as u64 * PROB_SCALE as u64 / total) as u32).max(1);
+        }
+    }
+    // fix the sum on the largest symbols
+    let mut sum: i64 = freqs.iter().map(|&f| f as i64).sum();
+    while sum != PROB_SCALE as i64 {
+        let (i, _) = freqs.iter().enumerate().max_by_key(|&(_, &f)| f).unwrap();
+        if sum > PROB_SCALE as i64 {
+            let cut = (sum - PROB_SCALE as i64).min(freqs[i] as i64 - 1);
+            if cut == 0 {
+                // every symbol is at 1, take from the next ones
+                let (j, _) = freqs.iter().enumerate().find(|&(_, &f)| f > 1).unwrap();
+                freqs[j] -= 1;
+                sum -= 1;
+                continue;
+            }
+            freqs[i] -= cut as u32;
+            sum -= cut;
+        } else {
+            freqs[i] += (PROB_SCALE as i64 - sum) as u32;
+            sum = PROB_SCALE as i64;
+        }
+    }
+    freqs
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> Result<u64, NPNGError> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = *data.get(*pos).ok_or_else(|| err("truncated stream"))?;
+        *pos += 1;
+        v |= ((byte & 0x7F) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(err("invalid varint"))
+}
+
+/// Compress bytes
+pub(crate) fn rans_compress(data: &[u8]) -> Vec<u8> {
+    let sample = &data[..data.len().min(STRIDE_SAMPLE)];
+    let stride = (0..=MAX_STRIDE)
+        .map(|s| (s, estimate(&residuals(sample, s), s)))
+        .fold(
+            (0, f64::INFINITY),
+            |best, s| if s.1 < best.1 { s } else { best },
+        )
+        .0;
+
+    let res = residuals(data, stride);
+    let contexts = stride.max(1) * BUCKETS;
+    let mut counts = vec![[0u32; 256]; contexts];
+    for i in 0..res.len() {
+        counts[context(&res, i, stride)][res[i] as usize] += 1;
+    }
+    let freqs: Vec<[u32; 256]> = counts.iter().map(normalize).collect();
+    let starts: Vec<[u32; 256]> = freqs
+        .iter()
+        .map(|f| {
+            let mut start = [0u32; 256];
+            let mut acc = 0;
+            for (s, &f) in start.iter_mut().zip(f) {
+                *s = acc;
+                acc += f;
+            }
+            start
+        })
+        .collect();
+
+    let mut out = Vec::new();
+    write_varint(&mut out, data.len() as u64);
+    out.push(stride as u8);
+    for f in &freqs {
+        let used: Vec<(usize, u32)> = f
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|&(_, f)| f > 0)
+            .collect();
+        write_varint(&mut out, used.len() as u64);
+        for (symbol, freq) in used {
+            out.push(symbol as u8);
+            write_varint(&mut out, freq as u64 - 1);
+        }
+    }
+
+    // rANS encodes backwards
+    let mut stream = Vec::new();
+    let mut x = RANS_L;
+    for i in (0..res.len()).rev() {
+        let ctx = context(&res, i, stride);
+        let symbol = res[i] as usize;
+        let (freq, start) = (freqs[ctx][symbol], starts[ctx][symbol]);
+        let x_max = ((RANS_L >> PROB_BITS) << 8) * freq;
+        while x >= x_max {
+            stream.push(x as u8);
+            x >>= 8;
+        }
+        x = ((x / freq) << PROB_BITS) + (x % freq) + start;
+    }
+    out.extend_from_slice(&x.to_be_bytes());
+    out.extend(stream.iter().rev());
+    out
+}
+
+/// Decompress bytes written by [`rans_compress`], at most `max_len` bytes
+pub(crate) fn rans_decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>, NPNGError> {
+    let mut pos = 0;
+    let len = read_varint(data, &mut pos)?;
+    if len > max_len as u64 {
+        return Err(err("stream is longer than expected"));
+    }
+    let len = len as usize;
+    let stride = *data.get(pos).ok_or_else(|| err("truncated stream"))? as usize;
+    pos += 1;
+    if stride > MAX_STRIDE {
+        return Err(err("invalid stride"));
+    }
+
+    let contexts = stride.max(1) * BUCKETS;
+    let mut freqs = vec![[0u32; 256]; contexts];
+    let mut starts = vec![[0u32; 256]; contexts];
+    // contexts without symbols can't be decoded
+    let mut empty = vec![true; contexts];
+    // slot -> symbol
+    let mut lookup = vec![[0u8; PROB_SCALE as usize]; contexts];
+    for ctx in 0..contexts {
+        let used = read_varint(data, &mut pos)?;
+        if used > 256 {
+            return Err(err("invalid table"));
+        }
+        let mut acc = 0u32;
+        for _ in 0..used {
+            let symbol = *data.get(pos).ok_or_else(|| err("truncated table"))? as usize;
+            pos += 1;
+            let freq = read_varint(data, &mut pos)? + 1;
+            if freq >= PROB_SCALE as u64
+                || freq > (PROB_SCALE - acc) as u64
+                || freqs[ctx][symbol] != 0
+            {
+                return Err(err("invalid table"));
+            }
+            freqs[ctx][symbol] = freq as u32;
+            starts[ctx][symbol] = acc;
+            lookup[ctx][acc as usize..(acc + freq as u32) as usize].fill(symbol as u8);
+            acc += freq as u32;
+        }
+        if used > 0 && acc != PROB_SCALE {
+            return Err(err("invalid table"));
+        }
+        empty[ctx] = used == 0;
+    }
+
+    if len / MAX_SYMBOLS_PER_BIT / 8 >= data.len() - pos {
+        return Err(err("stream is too short for its length"));
+    }
+    let state = data
+        .get(pos..pos + 4)
+        .ok_or_else(|| err("truncated stream"))?;
+    let mut x = u32::from_be_bytes([state[0], state[1], state[2], state[3]]);
+    pos += 4;
+
+    let mut res = Vec::with_capacity(len.min(1 << 24));
+    for i in 0..len {
+        let ctx = context(&res, i, stride);
+        if empty[ctx] {
+            return Err(err("empty context"));
+        }
+        let slot = x & (PROB_SCALE - 1);
+        let symbol = lookup[ctx][slot as usize];
+        let (freq, start) = (freqs[ctx][symbol as usize], starts[ctx][symbol as usize]);
+        x = freq * (x >> PROB_BITS) + slot - start;
+        while x < RANS_L {
+            let byte = *data.get(pos).ok_or_else(|| err("truncated stream"))?;
+            pos += 1;
+            x = (x << 8) | byte as u32;
+        }
+        res.push(symbol);
+    }
+
+    // undo the prediction
+    if stride > 0 {
+        for i in stride..res.len() {
+            res[i] = res[i].wrapping_add(res[i - stride]);
+        }
+    }
+    Ok(res)
+}
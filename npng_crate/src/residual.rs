@@ -0,0 +1,116 @@
+use crate::coding::RecordFormat;
+use crate::types::pixel::WidePixel;
+/// `residual.rs` - near-lossless coding of prediction residuals
+///
+/// Pixels are visited in `pixel_order` (row-major for [`crate::PixelOrder::Preserve`]),
+/// independent of the body layout. Every channel is predicted from the reconstructed
+/// neighbors (LOCO-I median edge predictor with the left, upper and upper-left pixels,
+/// falling back to the left, upper or previous pixel) and the residual is quantized
+/// with a step of `2 * max_error + 1`, so no channel deviates by more than `max_error`.
+/// The stored color holds the quantized residuals (`i8`) instead of channel values.
+use std::collections::HashMap;
+
+/// Reconstructed colors of the visited pixels
+struct Predictor {
+    colors: HashMap<(u32, u32), [u8; 4]>,
+    prev: [u8; 4],
+}
+
+impl Predictor {
+    fn new(len: usize) -> Self {
+        Predictor {
+            colors: HashMap::with_capacity(len),
+            prev: [0, 0, 0, 0xFF],
+        }
+    }
+
+    fn predict(&self, x: u32, y: u32) -> [u8; 4] {
+        let get = |x: Option<u32>, y: Option<u32>| self.colors.get(&(x?, y?)).copied();
+        let left = get(x.checked_sub(1), Some(y));
+        let up = get(Some(x), y.checked_sub(1));
+        let up_left = get(x.checked_sub(1), y.checked_sub(1));
+        match (left, up, up_left) {
+            (Some(a), Some(b), Some(c)) => std::array::from_fn(|i| med(a[i], b[i], c[i])),
+            (Some(a), _, _) | (None, Some(a), _) => a,
+            _ => self.prev,
+        }
+    }
+
+    fn push(&mut self, x: u32, y: u32, color: [u8; 4]) {
+        self.colors.insert((x, y), color);
+        self.prev = color;
+    }
+}
+
+/// LOCO-I median edge predictor
+fn med(a: u8, b: u8, c: u8) -> u8 {
+    let (lo, hi) = (a.min(b), a.max(b));
+    if c >= hi {
+        lo
+    } else if c <= lo {
+        hi
+    } else {
+        (a as i16 + b as i16 - c as i16) as u8
+    }
+}
+
+fn reconstruct(prediction: u8, code: u8, step: i32) -> u8 {
+    (prediction as i32 + code as i8 as i32 * step).clamp(0, 255) as u8
+}
+
+/// Visiting order of `pixels`
+fn visit_order(pixels: &[WidePixel], format: RecordFormat) -> Vec<usize> {
+    let key = format.pixel_order.key();
+    let mut order: Vec<usize> = (0..pixels.len()).collect();
+    order.sort_unstable_by_key(|&i| key(&pixels[i]));
+    order
+}
+
+/// Residual codes of the (written) `pixels`, in the same order
+pub(crate) fn encode_residuals(pixels: &[WidePixel], format: RecordFormat) -> Vec<u32> {
+    let n = format.max_error as i32;
+    let step = 2 * n + 1;
+    let channels = format.color_bytes();
+    let mut predictor = Predictor::new(pixels.len());
+    let mut codes = vec![0u32; pixels.len()];
+
+    for i in visit_order(pixels, format) {
+        let p = &pixels[i];
+        let prediction = predictor.predict(p.x, p.y);
+        let value = p.color.to_be_bytes();
+        let mut code = [0xFF; 4];
+        let mut color = [0xFF; 4];
+        for c in 0..channels {
+            let r = value[c] as i32 - prediction[c] as i32;
+            let q = if r >= 0 {
+                (r + n) / step
+            } else {
+                -((n - r) / step)
+            };
+            code[c] = q as i8 as u8;
+            color[c] = reconstruct(prediction[c], code[c], step);
+        }
+        predictor.push(p.x, p.y, color);
+        codes[i] = u32::from_be_bytes(code);
+    }
+    codes
+}
+
+/// Replace the residual codes of decoded pixels with their colors
+pub(crate) fn decode_residuals(pixels: &mut [WidePixel], format: RecordFormat) {
+    let step = 2 * format.max_error as i32 + 1;
+    let channels = format.color_bytes();
+    let mut predictor = Predictor::new(pixels.len());
+
+    for i in visit_order(pixels, format) {
+        let p = &mut pixels[i];
+        let prediction = predictor.predict(p.x, p.y);
+        let code = p.color.to_be_bytes();
+        let mut color = [0xFF; 4];
+        for c in 0..channels {
+            color[c] = reconstruct(prediction[c], code[c], step);
+        }
+        predictor.push(p.x, p.y, color);
+        p.color = u32::from_be_bytes(color);
+    }
+}
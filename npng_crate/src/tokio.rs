@@ -5,12 +5,10 @@ use std::ffi::OsStr;
 use tokio::task;
 
 use crate::{
-    Config, NPNGError,
-    EncoderVersion,
-    IntoCompressMap,
-    decode_bytes_to_image, decode_bytes_to_pixel_vec, decode_npng_image_to_image,
-    encode_image_to_npng_bytes, encode_image_to_npng_image, encode_image_to_npng_pixels,
-    encode_pixel_vec_to_npng_image, encode_pixel_vec_with_metadata,
+    Config, EncoderVersion, IntoCompressMap, NPNGError, decode_bytes_to_image,
+    decode_bytes_to_pixel_vec, decode_npng_image_to_image, encode_image_to_npng_bytes,
+    encode_image_to_npng_image, encode_image_to_npng_pixels, encode_pixel_vec_to_npng_image,
+    encode_pixel_vec_with_metadata,
     types::{Img, metadata::Metadata, pixel::Pixel},
 };
 
@@ -0,0 +1,337 @@
+use crate::error::NPNGError;
+use crate::types::Img;
+use bincode::{Decode, Encode};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::OnceLock;
+
+/// Value type of an auxiliary channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub enum ChannelType {
+    U8,
+    U16,
+    U32,
+    F32,
+}
+
+impl Display for ChannelType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ChannelType::U8 => f.write_str("u8"),
+            ChannelType::U16 => f.write_str("u16"),
+            ChannelType::U32 => f.write_str("u32"),
+            ChannelType::F32 => f.write_str("f32"),
+        }
+    }
+}
+
+/// Channel values, `components` per pixel in pixel order
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub enum ChannelData {
+    U8(Vec<u8>),
+    U16(Vec<u16>),
+    U32(Vec<u32>),
+    F32(Vec<f32>),
+}
+
+impl ChannelData {
+    fn zeroed(kind: ChannelType, len: usize) -> Self {
+        match kind {
+            ChannelType::U8 => ChannelData::U8(vec![0; len]),
+            ChannelType::U16 => ChannelData::U16(vec![0; len]),
+            ChannelType::U32 => ChannelData::U32(vec![0; len]),
+            ChannelType::F32 => ChannelData::F32(vec![0.0; len]),
+        }
+    }
+
+    fn kind(&self) -> ChannelType {
+        match self {
+            ChannelData::U8(_) => ChannelType::U8,
+            ChannelData::U16(_) => ChannelType::U16,
+            ChannelData::U32(_) => ChannelType::U32,
+            ChannelData::F32(_) => ChannelType::F32,
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            ChannelData::U8(v) => v.len(),
+            ChannelData::U16(v) => v.len(),
+            ChannelData::U32(v) => v.len(),
+            ChannelData::F32(v) => v.len(),
+        }
+    }
+
+    /// Values of the pixels at `indices` (in that order)
+    fn select(&self, indices: &[usize], components: usize) -> Self {
+        fn pick<T: Copy>(v: &[T], indices: &[usize], components: usize) -> Vec<T> {
+            indices
+                .iter()
+                .flat_map(|&i| &v[i * components..(i + 1) * components])
+                .copied()
+                .collect()
+        }
+        match self {
+            ChannelData::U8(v) => ChannelData::U8(pick(v, indices, components)),
+            ChannelData::U16(v) => ChannelData::U16(pick(v, indices, components)),
+            ChannelData::U32(v) => ChannelData::U32(pick(v, indices, components)),
+            ChannelData::F32(v) => ChannelData::F32(pick(v, indices, components)),
+        }
+    }
+}
+
+/// Component type usable with [`AuxChannel::get`] / [`AuxChannel::set`]
+pub trait ChannelValue: Copy {
+    const TYPE: ChannelType;
+    fn values(data: &ChannelData) -> Option<&[Self]>;
+    fn values_mut(data: &mut ChannelData) -> Option<&mut Vec<Self>>;
+}
+
+macro_rules! impl_channel_value {
+    ($t:ty, $variant:ident) => {
+        impl ChannelValue for $t {
+            const TYPE: ChannelType = ChannelType::$variant;
+            fn values(data: &ChannelData) -> Option<&[Self]> {
+                match data {
+                    ChannelData::$variant(v) => Some(v),
+                    _ => None,
+                }
+            }
+            fn values_mut(data: &mut ChannelData) -> Option<&mut Vec<Self>> {
+                match data {
+                    ChannelData::$variant(v) => Some(v),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl_channel_value!(u8, U8);
+impl_channel_value!(u16, U16);
+impl_channel_value!(u32, U32);
+impl_channel_value!(f32, F32);
+
+/// Named per-pixel channel stored next to `Pixel.color` (depth, object ID, normals, ...).
+///
+/// Values follow the order of the image pixels, `components` (1-4) per pixel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuxChannel {
+    pub name: String,
+    pub components: u8,
+    pub data: ChannelData,
+}
+
+impl AuxChannel {
+    /// Zero-filled channel for `len` pixels
+    pub fn new<S: Into<String>>(
+        name: S,
+        kind: ChannelType,
+        components: u8,
+        len: usize,
+    ) -> Result<Self, NPNGError> {
+        Self::with_data(
+            name,
+            components,
+            ChannelData::zeroed(kind, len * components as usize),
+        )
+    }
+
+    /// Channel with existing values (`components` per pixel)
+    pub fn with_data<S: Into<String>>(
+        name: S,
+        components: u8,
+        data: ChannelData,
+    ) -> Result<Self, NPNGError> {
+        if !(1..=4).contains(&components) {
+            return Err(NPNGError::Error(
+                "Channel must have 1-4 components".to_string(),
+            ));
+        }
+        if !data.len().is_multiple_of(components as usize) {
+            return Err(NPNGError::Error(
+                "Channel data length is not a multiple of the component count".to_string(),
+            ));
+        }
+        Ok(AuxChannel {
+            name: name.into(),
+            components,
+            data,
+        })
+    }
+
+    pub fn kind(&self) -> ChannelType {
+        self.data.kind()
+    }
+
+    /// Number of pixels
+    pub fn len(&self) -> usize {
+        self.data.len() / self.components.max(1) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Components of the pixel at `index`. `None` if out of range or of another type
+    pub fn get<T: ChannelValue>(&self, index: usize) -> Option<&[T]> {
+        let c = self.components as usize;
+        T::values(&self.data)?.get(index * c..(index + 1) * c)
+    }
+
+    /// Set the components of the pixel at `index`
+    pub fn set<T: ChannelValue>(&mut self, index: usize, value: &[T]) -> Result<(), NPNGError> {
+        let c = self.components as usize;
+        if value.len() != c {
+            return Err(NPNGError::Error(format!(
+                "Channel `{}` has {} components, got {}",
+                self.name,
+                c,
+                value.len()
+            )));
+        }
+        let kind = self.kind();
+        let values = T::values_mut(&mut self.data).ok_or_else(|| {
+            NPNGError::Error(format!("Channel `{}` holds {} values", self.name, kind))
+        })?;
+        match values.get_mut(index * c..(index + 1) * c) {
+            Some(dst) => {
+                dst.copy_from_slice(value);
+                Ok(())
+            }
+            None => Err(NPNGError::Error(format!(
+                "Pixel index {} is out of range",
+                index
+            ))),
+        }
+    }
+
+    /// Channel with the values of the pixels at `indices`
+    pub(crate) fn select(&self, indices: &[usize]) -> Self {
+        AuxChannel {
+            name: self.name.clone(),
+            components: self.components,
+            data: self.data.select(indices, self.components as usize),
+        }
+    }
+}
+
+/// Stored channel declarations and the compressed values
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct ChannelRecord {
+    /// (name, type, components)
+    pub channels: Vec<(String, ChannelType, u8)>,
+    /// `Vec<ChannelData>`, compressed with the file compressor
+    pub data: Vec<u8>,
+}
+
+/// Image with auxiliary per-pixel channels.
+///
+/// Values of a channel follow `img.pixels`, value `i` belongs to pixel `i`.
+#[derive(Debug, Clone)]
+pub struct ChannelImg {
+    pub(crate) img: Img,
+    pub channels: Vec<AuxChannel>,
+    /// Pixel positions -> indices, built on first use and cleared by [`ChannelImg::img_mut`]
+    index: OnceLock<HashMap<(u16, u16), usize>>,
+}
+
+impl ChannelImg {
+    pub fn new(img: Img) -> Self {
+        ChannelImg {
+            img,
+            channels: Vec::new(),
+            index: OnceLock::new(),
+        }
+    }
+
+    pub fn img(&self) -> &Img {
+        &self.img
+    }
+
+    /// Mutable image, the pixel index is rebuilt on the next lookup
+    pub fn img_mut(&mut self) -> &mut Img {
+        self.index = OnceLock::new();
+        &mut self.img
+    }
+
+    pub fn into_img(self) -> Img {
+        self.img
+    }
+
+    /// Add a zero-filled channel covering every pixel
+    pub fn add_channel<S: Into<String>>(
+        &mut self,
+        name: S,
+        kind: ChannelType,
+        components: u8,
+    ) -> Result<&mut AuxChannel, NPNGError> {
+        let name = name.into();
+        if self.channel(&name).is_some() {
+            return Err(NPNGError::Error(format!(
+                "Channel `{}` already exists",
+                name
+            )));
+        }
+        let channel = AuxChannel::new(name, kind, components, self.img.pixels.len())?;
+        self.channels.push(channel);
+        Ok(self.channels.last_mut().unwrap())
+    }
+
+    pub fn channel(&self, name: &str) -> Option<&AuxChannel> {
+        self.channels.iter().find(|c| c.name == name)
+    }
+
+    pub fn channel_mut(&mut self, name: &str) -> Option<&mut AuxChannel> {
+        self.channels.iter_mut().find(|c| c.name == name)
+    }
+
+    /// Index of the pixel at (x, y) in `img.pixels`
+    pub fn pixel_index(&self, x: u16, y: u16) -> Option<usize> {
+        let index = self.index.get_or_init(|| {
+            self.img
+                .pixels
+                .iter()
+                .enumerate()
+                .map(|(i, p)| ((p.x, p.y), i))
+                .collect()
+        });
+        index.get(&(x, y)).copied()
+    }
+
+    /// Channel value of the pixel at `index` in `img.pixels`
+    pub fn value<T: ChannelValue>(&self, name: &str, index: usize) -> Option<&[T]> {
+        self.channel(name)?.get(index)
+    }
+
+    /// Set the channel value of the pixel at `index` in `img.pixels`
+    pub fn set_value<T: ChannelValue>(
+        &mut self,
+        name: &str,
+        index: usize,
+        value: &[T],
+    ) -> Result<(), NPNGError> {
+        self.channel_mut(name)
+            .ok_or_else(|| NPNGError::Error(format!("No channel `{}`", name)))?
+            .set(index, value)
+    }
+
+    /// Channel value of the pixel at (x, y)
+    pub fn value_at<T: ChannelValue>(&self, name: &str, x: u16, y: u16) -> Option<&[T]> {
+        self.value(name, self.pixel_index(x, y)?)
+    }
+
+    /// Set the channel value of the pixel at (x, y)
+    pub fn set_value_at<T: ChannelValue>(
+        &mut self,
+        name: &str,
+        x: u16,
+        y: u16,
+        value: &[T],
+    ) -> Result<(), NPNGError> {
+        let index = self
+            .pixel_index(x, y)
+            .ok_or_else(|| NPNGError::Error(format!("No pixel at x:{} y:{}", x, y)))?;
+        self.set_value(name, index, value)
+    }
+}
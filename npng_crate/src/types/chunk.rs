@@ -0,0 +1,111 @@
+use crate::error::NPNGError;
+use crate::utils::{deserialize, serialize};
+use bincode::error::DecodeError;
+use bincode::{BorrowDecode, Decode, Encode};
+
+/// `Header.reserved[0]` flag: a chunk list follows the header delimiter.
+pub(crate) const FLAG_CHUNKS: u8 = 0b0000_0001;
+
+/// Chunk tags
+pub(crate) const TAG_COLOR_SPACE: [u8; 4] = *b"colr";
+pub(crate) const TAG_EXIF: [u8; 4] = *b"exif";
+pub(crate) const TAG_XMP: [u8; 4] = *b"xmp ";
+pub(crate) const TAG_TEXT: [u8; 4] = *b"text";
+pub(crate) const TAG_VALUES: [u8; 4] = *b"vals";
+pub(crate) const TAG_ORIGIN: [u8; 4] = *b"orig";
+pub(crate) const TAG_SIZE: [u8; 4] = *b"size";
+pub(crate) const TAG_COORDS: [u8; 4] = *b"crds";
+pub(crate) const TAG_LAYERS: [u8; 4] = *b"layr";
+pub(crate) const TAG_CHANNELS: [u8; 4] = *b"chan";
+pub(crate) const TAG_ALPHA: [u8; 4] = *b"alph";
+pub(crate) const TAG_ALPHA_MODE: [u8; 4] = *b"amod";
+pub(crate) const TAG_PIXEL_FORMAT: [u8; 4] = *b"pfmt";
+pub(crate) const TAG_LAYOUT: [u8; 4] = *b"layt";
+pub(crate) const TAG_PIXEL_ORDER: [u8; 4] = *b"pord";
+pub(crate) const TAG_COLOR_TRANSFORM: [u8; 4] = *b"ctfm";
+pub(crate) const TAG_PALETTE: [u8; 4] = *b"pltt";
+pub(crate) const TAG_MIPMAPS: [u8; 4] = *b"mips";
+pub(crate) const TAG_THUMBNAIL: [u8; 4] = *b"thmb";
+pub(crate) const TAG_COVERAGE: [u8; 4] = *b"covr";
+
+/// Tagged extension block.
+///
+/// Chunks are stored right after the header delimiter, so files written
+/// before a chunk kind existed keep decoding. Unknown tags are ignored.
+#[derive(Debug, Clone, Encode, Decode)]
+pub struct Chunk {
+    pub tag: [u8; 4],
+    pub data: Vec<u8>,
+}
+
+impl Chunk {
+    /// Serialize `value` into a new chunk
+    pub fn new<T: Encode>(tag: [u8; 4], value: T) -> Result<Self, NPNGError> {
+        Ok(Chunk {
+            tag,
+            data: serialize(value, true)?,
+        })
+    }
+
+    /// Deserialize chunk data
+    pub fn value<T: Decode<()>>(&self) -> Result<T, NPNGError> {
+        deserialize(self.data.clone(), true)
+    }
+}
+
+/// Chunk borrowed from the file bytes (same encoding as [`Chunk`])
+#[derive(Debug, Clone, Copy, BorrowDecode)]
+pub(crate) struct ChunkRef<'a> {
+    pub tag: [u8; 4],
+    pub data: &'a [u8],
+}
+
+impl ChunkRef<'_> {
+    pub(crate) fn to_chunk(self) -> Chunk {
+        Chunk {
+            tag: self.tag,
+            data: self.data.to_vec(),
+        }
+    }
+}
+
+/// Find the first chunk with `tag`
+pub(crate) fn find_chunk<'a>(chunks: &'a [Chunk], tag: &[u8; 4]) -> Option<&'a Chunk> {
+    chunks.iter().find(|c| &c.tag == tag)
+}
+
+/// Read a chunk list from the start of `data`.
+///
+/// # Returns
+/// tuple with `(chunks, bytes read)`
+pub(crate) fn read_chunks(data: &[u8]) -> Result<(Vec<Chunk>, usize), NPNGError> {
+    let (chunks, len) = read_chunk_refs(data)?;
+    Ok((chunks.into_iter().map(ChunkRef::to_chunk).collect(), len))
+}
+
+/// Read a chunk list from the start of `data` without copying the chunk data.
+///
+/// # Returns
+/// tuple with `(chunks, bytes read)`
+pub(crate) fn read_chunk_refs(data: &[u8]) -> Result<(Vec<ChunkRef<'_>>, usize), NPNGError> {
+    bincode::borrow_decode_from_slice(data, bincode::config::standard())
+        .map_err(|e| NPNGError::InvalidHeader(format!("Chunk decoding error: {}", e)))
+}
+
+/// [`read_chunk_refs`] of a file that is still arriving.
+///
+/// # Returns
+/// `None` if the chunk list is truncated, an error if the received bytes are invalid
+pub(crate) fn read_received_chunks(
+    data: &[u8],
+) -> Result<Option<(Vec<ChunkRef<'_>>, usize)>, NPNGError> {
+    match bincode::borrow_decode_from_slice(data, bincode::config::standard()) {
+        Ok(chunks) => Ok(Some(chunks)),
+        // not received yet
+        Err(DecodeError::UnexpectedEnd { .. }) => Ok(None),
+        Err(e) => Err(NPNGError::InvalidHeader(format!(
+            "Chunk decoding error: {}",
+            e
+        ))),
+    }
+}
@@ -0,0 +1,253 @@
+use crate::error::NPNGError;
+use bincode::{Decode, Encode};
+use moxcms::{ColorProfile, Layout, ToneReprCurve, TransformOptions};
+use std::fmt::Display;
+
+/// Color space of `Pixel.color`
+///
+/// `Srgb` is assumed for files that don't record a color space.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Encode, Decode)]
+pub enum ColorSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+    LinearSrgb,
+    Rec2020,
+    /// Embedded ICC profile
+    Icc(Vec<u8>),
+}
+
+impl ColorSpace {
+    /// Build a color profile for the color space
+    pub(crate) fn profile(&self) -> Result<ColorProfile, NPNGError> {
+        Ok(match self {
+            ColorSpace::Srgb => ColorProfile::new_srgb(),
+            ColorSpace::DisplayP3 => ColorProfile::new_display_p3(),
+            ColorSpace::Rec2020 => ColorProfile::new_bt2020(),
+            ColorSpace::LinearSrgb => {
+                let mut profile = ColorProfile::new_srgb();
+                let linear = ToneReprCurve::Lut(Vec::new());
+                profile.red_trc = Some(linear.clone());
+                profile.green_trc = Some(linear.clone());
+                profile.blue_trc = Some(linear);
+                profile.cicp = None;
+                profile
+            }
+            ColorSpace::Icc(icc) => ColorProfile::new_from_slice(icc)
+                .map_err(|e| NPNGError::Error(format!("Invalid ICC profile: {}", e)))?,
+        })
+    }
+
+    /// ICC profile to embed when exporting.
+    ///
+    /// `None` for sRGB (the default for most formats)
+    pub fn icc_profile(&self) -> Result<Option<Vec<u8>>, NPNGError> {
+        match self {
+            ColorSpace::Srgb => Ok(None),
+            ColorSpace::Icc(icc) => Ok(Some(icc.clone())),
+            _ => self
+                .profile()?
+                .encode()
+                .map(Some)
+                .map_err(|e| NPNGError::Error(format!("Failed to encode ICC profile: {}", e))),
+        }
+    }
+}
+
+/// Alpha representation of `Pixel.color`
+///
+/// `Straight` is assumed for files that don't record an alpha mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum AlphaMode {
+    /// Color channels are independent of alpha
+    #[default]
+    Straight,
+    /// Color channels are multiplied by alpha
+    Premultiplied,
+}
+
+/// Multiply the color channels of an RGBA color by its alpha
+pub(crate) fn premultiply_color(color: u32) -> u32 {
+    let [r, g, b, a] = color.to_be_bytes();
+    let mul = |c: u8| ((c as u32 * a as u32 + 127) / 255) as u8;
+    u32::from_be_bytes([mul(r), mul(g), mul(b), a])
+}
+
+/// Divide the color channels of a premultiplied RGBA color by its alpha.
+/// Fully transparent colors are kept as is
+pub(crate) fn unpremultiply_color(color: u32) -> u32 {
+    let [r, g, b, a] = color.to_be_bytes();
+    if a == 0 {
+        return color;
+    }
+    let div = |c: u8| ((c as u32 * 255 + a as u32 / 2) / a as u32).min(255) as u8;
+    u32::from_be_bytes([div(r), div(g), div(b), a])
+}
+
+/// Convert packed RGBA colors between alpha modes
+pub(crate) fn convert_alpha<'a>(
+    colors: impl IntoIterator<Item = &'a mut u32>,
+    from: AlphaMode,
+    to: AlphaMode,
+) {
+    let f = match (from, to) {
+        (AlphaMode::Straight, AlphaMode::Premultiplied) => premultiply_color,
+        (AlphaMode::Premultiplied, AlphaMode::Straight) => unpremultiply_color,
+        _ => return,
+    };
+    for c in colors {
+        *c = f(*c);
+    }
+}
+
+/// Convert RGBA bytes (`[r, g, b, a, ...]`) between color spaces. Alpha is kept as is.
+pub(crate) fn convert_rgba(
+    data: &mut [u8],
+    from: &ColorSpace,
+    to: &ColorSpace,
+) -> Result<(), NPNGError> {
+    if from == to || data.is_empty() {
+        return Ok(());
+    }
+    let transform = from
+        .profile()?
+        .create_transform_8bit(
+            Layout::Rgba,
+            &to.profile()?,
+            Layout::Rgba,
+            TransformOptions::default(),
+        )
+        .map_err(|e| NPNGError::Error(format!("Color conversion failed: {}", e)))?;
+
+    let src = data.to_vec();
+    transform
+        .transform(&src, data)
+        .map_err(|e| NPNGError::Error(format!("Color conversion failed: {}", e)))
+}
+
+/// Convert packed RGBA colors between color spaces. Alpha is kept as is.
+pub(crate) fn convert_colors<'a>(
+    colors: impl IntoIterator<Item = &'a mut u32>,
+    from: &ColorSpace,
+    to: &ColorSpace,
+) -> Result<(), NPNGError> {
+    if from == to {
+        return Ok(());
+    }
+    let mut colors: Vec<&mut u32> = colors.into_iter().collect();
+    let mut data: Vec<u8> = colors.iter().flat_map(|c| c.to_be_bytes()).collect();
+    convert_rgba(&mut data, from, to)?;
+    for (color, c) in colors.iter_mut().zip(data.chunks_exact(4)) {
+        **color = u32::from_be_bytes([c[0], c[1], c[2], c[3]]);
+    }
+    Ok(())
+}
+
+/// Reversible transform of the RGB channels applied before compression
+/// (alpha is kept as is). Arithmetic wraps, so every transform is lossless.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum ColorTransform {
+    #[default]
+    None,
+    /// WebP-style: green is subtracted from red and blue
+    SubtractGreen,
+    /// Lifting-based YCoCg-R (luma, orange and green chroma)
+    YCoCgR,
+    /// Pick the transform with the lowest channel entropy on a sample of pixels
+    /// (the chosen one is stored)
+    Auto,
+}
+
+impl Display for ColorTransform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ColorTransform::None => f.write_str("none"),
+            ColorTransform::SubtractGreen => f.write_str("subtract-green"),
+            ColorTransform::YCoCgR => f.write_str("ycocg-r"),
+            ColorTransform::Auto => f.write_str("auto"),
+        }
+    }
+}
+
+/// Signed half of a wrapped channel difference
+fn half(v: u8) -> u8 {
+    ((v as i8) >> 1) as u8
+}
+
+impl ColorTransform {
+    /// Transforms that can be stored in a file
+    pub(crate) const STORED: [ColorTransform; 3] = [
+        ColorTransform::None,
+        ColorTransform::SubtractGreen,
+        ColorTransform::YCoCgR,
+    ];
+
+    /// Transform an RGBA color (`0xRRGGBBAA`)
+    pub(crate) fn forward(self, color: u32) -> u32 {
+        let [r, g, b, a] = color.to_be_bytes();
+        let rgb = match self {
+            ColorTransform::None | ColorTransform::Auto => return color,
+            ColorTransform::SubtractGreen => [r.wrapping_sub(g), g, b.wrapping_sub(g)],
+            ColorTransform::YCoCgR => {
+                let co = r.wrapping_sub(b);
+                let t = b.wrapping_add(half(co));
+                let cg = g.wrapping_sub(t);
+                [t.wrapping_add(half(cg)), co, cg]
+            }
+        };
+        u32::from_be_bytes([rgb[0], rgb[1], rgb[2], a])
+    }
+
+    /// Undo [`ColorTransform::forward`]
+    pub(crate) fn inverse(self, color: u32) -> u32 {
+        let [c0, c1, c2, a] = color.to_be_bytes();
+        let rgb = match self {
+            ColorTransform::None | ColorTransform::Auto => return color,
+            ColorTransform::SubtractGreen => [c0.wrapping_add(c1), c1, c2.wrapping_add(c1)],
+            ColorTransform::YCoCgR => {
+                let (y, co, cg) = (c0, c1, c2);
+                let t = y.wrapping_sub(half(cg));
+                let g = cg.wrapping_add(t);
+                let b = t.wrapping_sub(half(co));
+                [b.wrapping_add(co), g, b]
+            }
+        };
+        u32::from_be_bytes([rgb[0], rgb[1], rgb[2], a])
+    }
+
+    /// Transform with the lowest summed entropy of the RGB channel differences between
+    /// neighboring colors of each run in `runs`
+    pub(crate) fn pick(runs: &[&[u32]]) -> Self {
+        let entropy = |transform: ColorTransform| -> f64 {
+            let mut histograms = [[0u32; 256]; 3];
+            let mut n = 0;
+            for run in runs {
+                for pair in run.windows(2) {
+                    let prev = transform.forward(pair[0]).to_be_bytes();
+                    let next = transform.forward(pair[1]).to_be_bytes();
+                    for (i, h) in histograms.iter_mut().enumerate() {
+                        h[next[i].wrapping_sub(prev[i]) as usize] += 1;
+                    }
+                    n += 1;
+                }
+            }
+            let n = n.max(1) as f64;
+            histograms
+                .iter()
+                .flatten()
+                .filter(|&&count| count > 0)
+                .map(|&count| {
+                    let p = count as f64 / n;
+                    -p * p.log2()
+                })
+                .sum()
+        };
+        ColorTransform::STORED
+            .into_iter()
+            .map(|t| (t, entropy(t)))
+            .fold((ColorTransform::None, f64::INFINITY), |best, t| {
+                if t.1 < best.1 { t } else { best }
+            })
+            .0
+    }
+}
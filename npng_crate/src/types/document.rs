@@ -0,0 +1,235 @@
+use crate::error::NPNGError;
+use crate::types::Img;
+use crate::types::metadata::Metadata;
+use crate::types::pixel::{Pixel, WidePixel};
+use bincode::{Decode, Encode};
+use image::{Rgba, RgbaImage};
+use std::fmt::Display;
+
+/// How a layer is combined with the layers below it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum BlendMode {
+    #[default]
+    Normal,
+    Multiply,
+    Screen,
+    Overlay,
+    /// Linear dodge, clamped to white
+    Add,
+}
+
+impl Display for BlendMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BlendMode::Normal => f.write_str("normal"),
+            BlendMode::Multiply => f.write_str("multiply"),
+            BlendMode::Screen => f.write_str("screen"),
+            BlendMode::Overlay => f.write_str("overlay"),
+            BlendMode::Add => f.write_str("add"),
+        }
+    }
+}
+
+impl BlendMode {
+    /// Blend one channel (`0.0..=1.0`) of the source over the backdrop
+    fn blend(self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::Screen => backdrop + source - backdrop * source,
+            BlendMode::Overlay => {
+                if backdrop <= 0.5 {
+                    2.0 * backdrop * source
+                } else {
+                    1.0 - 2.0 * (1.0 - backdrop) * (1.0 - source)
+                }
+            }
+            BlendMode::Add => (backdrop + source).min(1.0),
+        }
+    }
+}
+
+/// Layer of an [`NpngDocument`]
+#[derive(Debug, Clone)]
+pub struct Layer {
+    pub name: String,
+    /// Sparse pixels in layer coordinates
+    pub pixels: Vec<Pixel>,
+    /// Position of the layer (0, 0) on the document canvas
+    pub offset: (i32, i32),
+    pub visible: bool,
+    /// `0.0..=1.0`, multiplied with pixel alpha
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+}
+
+impl Layer {
+    /// Visible, fully opaque layer with normal blending at (0, 0)
+    pub fn new<S: Into<String>>(name: S, pixels: Vec<Pixel>) -> Self {
+        Layer {
+            name: name.into(),
+            pixels,
+            offset: (0, 0),
+            visible: true,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
+        }
+    }
+
+    /// Largest canvas position covered by the layer (exclusive)
+    fn extent(&self) -> (i64, i64) {
+        let max_x = self
+            .pixels
+            .iter()
+            .map(|p| p.x as i64 + 1)
+            .max()
+            .unwrap_or(0);
+        let max_y = self
+            .pixels
+            .iter()
+            .map(|p| p.y as i64 + 1)
+            .max()
+            .unwrap_or(0);
+        (self.offset.0 as i64 + max_x, self.offset.1 as i64 + max_y)
+    }
+}
+
+/// Stored layer: settings and the compressed pixel records
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct LayerRecord {
+    pub name: String,
+    pub offset: (i32, i32),
+    pub visible: bool,
+    pub opacity: f32,
+    pub blend_mode: BlendMode,
+    pub data: Vec<u8>,
+    /// Pixels kept without colors (`AlphaPolicy::KeepCoverage`)
+    pub coverage: Option<Vec<u8>>,
+}
+
+/// Ordered stack of layers (bottom first) sharing one canvas.
+///
+/// `metadata.width`/`metadata.height` declare the canvas, zero dimensions are
+/// calculated from the layers. Parts of layers outside the canvas are clipped.
+#[derive(Debug, Clone)]
+pub struct NpngDocument {
+    pub layers: Vec<Layer>,
+    pub metadata: Metadata,
+    /// Store the flattened image as the file body, so plain decoders can read it.
+    /// Without it the body is empty and the file is about half the size
+    pub composite: bool,
+}
+
+impl NpngDocument {
+    /// Empty document, stored with the flattened image
+    pub fn new(metadata: Metadata) -> Self {
+        NpngDocument {
+            layers: Vec::new(),
+            metadata,
+            composite: true,
+        }
+    }
+
+    /// Add a layer on top
+    pub fn add_layer(&mut self, layer: Layer) {
+        self.layers.push(layer);
+    }
+
+    /// First layer with the given name
+    pub fn layer(&self, name: &str) -> Option<&Layer> {
+        self.layers.iter().find(|l| l.name == name)
+    }
+
+    pub fn layer_mut(&mut self, name: &str) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|l| l.name == name)
+    }
+
+    /// Canvas size: the declared one, or the extent of all layers
+    pub fn canvas_size(&self) -> (u32, u32) {
+        let (mut width, mut height) = (self.metadata.width, self.metadata.height);
+        if width == 0 || height == 0 {
+            let (w, h) = self
+                .layers
+                .iter()
+                .map(Layer::extent)
+                .fold((0, 0), |a, e| (a.0.max(e.0), a.1.max(e.1)));
+            if width == 0 {
+                width = w.clamp(0, u32::MAX as i64) as u32;
+            }
+            if height == 0 {
+                height = h.clamp(0, u32::MAX as i64) as u32;
+            }
+        }
+        (width, height)
+    }
+
+    /// Composite visible layers into an image buffer of the canvas size
+    pub fn flatten_to_buffer(&self) -> RgbaImage {
+        let (width, height) = self.canvas_size();
+        let mut buffer = RgbaImage::new(width, height);
+
+        for layer in self.layers.iter().filter(|l| l.visible) {
+            let opacity = layer.opacity.clamp(0.0, 1.0);
+            for p in &layer.pixels {
+                let x = layer.offset.0 as i64 + p.x as i64;
+                let y = layer.offset.1 as i64 + p.y as i64;
+                if x < 0 || y < 0 || x >= width as i64 || y >= height as i64 {
+                    continue;
+                }
+                let dst = buffer.get_pixel_mut(x as u32, y as u32);
+                *dst = composite(*dst, p.color, opacity, layer.blend_mode);
+            }
+        }
+        buffer
+    }
+
+    /// Composite visible layers into an [`Img`]. Fully transparent pixels are left out
+    pub fn flatten(&self) -> Result<Img, NPNGError> {
+        let (pixels, width, height) = self.flatten_pixels();
+        let mut metadata = self.metadata.clone();
+        metadata.width = width;
+        metadata.height = height;
+        Ok(Img {
+            pixels: pixels
+                .into_iter()
+                .map(Pixel::try_from)
+                .collect::<Result<_, _>>()?,
+            encoder_version: crate::version(),
+            metadata,
+        })
+    }
+
+    /// Composited pixels and canvas size
+    pub(crate) fn flatten_pixels(&self) -> (Vec<WidePixel>, u32, u32) {
+        let buffer = self.flatten_to_buffer();
+        let pixels = buffer
+            .enumerate_pixels()
+            .filter(|(_, _, c)| c[3] != 0)
+            .map(|(x, y, c)| WidePixel::new(x, y, u32::from_be_bytes(c.0)))
+            .collect();
+        (pixels, buffer.width(), buffer.height())
+    }
+}
+
+/// Composite an RGBA color (`0xRRGGBBAA`) over a backdrop pixel (straight alpha)
+fn composite(backdrop: Rgba<u8>, color: u32, opacity: f32, mode: BlendMode) -> Rgba<u8> {
+    let src = color.to_be_bytes();
+    let alpha_s = src[3] as f32 / 255.0 * opacity;
+    if alpha_s == 0.0 {
+        return backdrop;
+    }
+    let alpha_b = backdrop[3] as f32 / 255.0;
+    let alpha_o = alpha_s + alpha_b * (1.0 - alpha_s);
+
+    let mut out = [0u8; 4];
+    for i in 0..3 {
+        let cs = src[i] as f32 / 255.0;
+        let cb = backdrop[i] as f32 / 255.0;
+        // blended color is only used where the backdrop is opaque
+        let mixed = (1.0 - alpha_b) * cs + alpha_b * mode.blend(cb, cs);
+        let co = (alpha_s * mixed + alpha_b * cb * (1.0 - alpha_s)) / alpha_o;
+        out[i] = (co * 255.0).round().clamp(0.0, 255.0) as u8;
+    }
+    out[3] = (alpha_o * 255.0).round().clamp(0.0, 255.0) as u8;
+    Rgba(out)
+}
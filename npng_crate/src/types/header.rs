@@ -1,7 +1,8 @@
-use bincode::{Decode, Encode};
 use crate::error::NPNGError;
+use crate::types::chunk::FLAG_CHUNKS;
 use crate::types::metadata::Metadata;
 use crate::ver::{VERSION_MAJOR, VERSION_METADATA, VERSION_MINOR};
+use bincode::{Decode, Encode};
 
 #[repr(C)]
 #[derive(Debug, Clone, Encode, Decode)]
@@ -10,7 +11,7 @@ pub struct Header {
     pub version_major: u16,
     pub version_minor: u16,
     pub version_metadata: String,
-    pub reserved: [u8; 8], // reserved for future use
+    pub reserved: [u8; 8], // [0] - flags (see `types::chunk`), [1] - near-lossless max error, rest reserved for future use
     pub alpha: bool,
     pub varint: bool,
     pub encoding_format: String,
@@ -45,11 +46,14 @@ impl Header {
             metadata.created_in = metadata.created_in.split_at(512).0.to_string();
         }
         if metadata.extra.len() > 512 {
-            metadata.extra = metadata
-                .extra
-                .iter()
+            // keep the first 512 entries in key order, so the result does not
+            // depend on the map's iteration order
+            let mut extra: Vec<_> = metadata.extra.into_iter().collect();
+            extra.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+            metadata.extra = extra
+                .into_iter()
                 .take(512)
-                .map(|(k, v)| (k.clone().trim().to_string(), v.clone().trim().to_string()))
+                .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
                 .collect();
         }
         Ok(Header {
@@ -57,7 +61,7 @@ impl Header {
             version_major: VERSION_MAJOR,
             version_minor: VERSION_MINOR,
             version_metadata: VERSION_METADATA.to_string(),
-            reserved: [0x00; 8], // reserved for future use
+            reserved: [FLAG_CHUNKS, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
             alpha,
             varint,
             encoding_format: encoding_format.trim().to_string(),
@@ -65,4 +69,4 @@ impl Header {
             del: [0xff; 6],
         })
     }
-}
\ No newline at end of file
+}
@@ -1,15 +1,101 @@
+use crate::error::NPNGError;
+use crate::types::chunk::{
+    Chunk, TAG_ALPHA_MODE, TAG_COLOR_SPACE, TAG_EXIF, TAG_ORIGIN, TAG_SIZE, TAG_TEXT, TAG_VALUES,
+    TAG_XMP, find_chunk,
+};
+use crate::types::color::{AlphaMode, ColorSpace};
+use crate::types::value::MetadataValue;
+use bincode::{
+    Decode, Encode,
+    de::Decoder,
+    enc::Encoder,
+    error::{DecodeError, EncodeError},
+};
 use std::collections::{BTreeMap, HashMap};
-use bincode::{Decode, Encode};
+use std::time::SystemTime;
 
 #[repr(C)]
-#[derive(Debug, Clone, Encode, Decode)]
+#[derive(Debug, Clone)]
 pub struct Metadata {
     pub created_in: String,
-    pub width: u16,
-    pub height: u16,
+    /// Canvas width. Sizes over 65535 are stored as a chunk
+    pub width: u32,
+    /// Canvas height. Sizes over 65535 are stored as a chunk
+    pub height: u32,
     pub extra: HashMap<String, String>,
+    /// Color space of pixel colors (stored as a chunk)
+    pub color_space: ColorSpace,
+    /// Raw EXIF data, starting at the TIFF header (stored as a chunk)
+    pub exif: Option<Vec<u8>>,
+    /// XMP packet (stored as a chunk)
+    pub xmp: Option<Vec<u8>>,
+    /// Text entries, e.g. PNG tEXt/zTXt/iTXt (stored as a chunk)
+    pub text: Vec<TextEntry>,
+    /// Typed values (stored as a chunk). See [`Metadata::set_value`] and the `get_*` getters
+    pub values: BTreeMap<String, MetadataValue>,
+    /// Position of the canvas (0, 0) in global coordinates (stored as a chunk)
+    pub origin: (i32, i32),
+    /// Straight or premultiplied `Pixel.color` (stored as a chunk)
+    pub alpha_mode: AlphaMode,
 }
 
+/// Keyword/text pair (PNG text chunk)
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct TextEntry {
+    pub keyword: String,
+    pub text: String,
+}
+
+impl TextEntry {
+    pub fn new<K: Into<String>, T: Into<String>>(keyword: K, text: T) -> Self {
+        TextEntry {
+            keyword: keyword.into(),
+            text: text.into(),
+        }
+    }
+}
+
+// Only the original fields are part of the header struct itself,
+// everything added later is stored in chunks (see `to_chunks`).
+impl Encode for Metadata {
+    fn encode<E: Encoder>(&self, encoder: &mut E) -> Result<(), EncodeError> {
+        self.created_in.encode(encoder)?;
+        // the header keeps 16-bit sizes, larger ones are written as 0 (see `to_chunks`)
+        u16::try_from(self.width).unwrap_or(0).encode(encoder)?;
+        u16::try_from(self.height).unwrap_or(0).encode(encoder)?;
+
+        // same layout as `HashMap::encode`, but in key order (deterministic output)
+        let mut extra: Vec<_> = self.extra.iter().collect();
+        extra.sort_unstable_by(|a, b| a.0.cmp(b.0));
+        (extra.len() as u64).encode(encoder)?;
+        for (k, v) in extra {
+            k.encode(encoder)?;
+            v.encode(encoder)?;
+        }
+        Ok(())
+    }
+}
+
+impl<Context> Decode<Context> for Metadata {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        Ok(Metadata {
+            created_in: Decode::decode(decoder)?,
+            width: u16::decode(decoder)? as u32,
+            height: u16::decode(decoder)? as u32,
+            extra: Decode::decode(decoder)?,
+            color_space: ColorSpace::default(),
+            exif: None,
+            xmp: None,
+            text: Vec::new(),
+            values: BTreeMap::new(),
+            origin: (0, 0),
+            alpha_mode: AlphaMode::Straight,
+        })
+    }
+}
+
+bincode::impl_borrow_decode!(Metadata);
+
 impl Metadata {
     pub fn new_string(created_in: String, extra: HashMap<String, String>) -> Self {
         Metadata {
@@ -17,6 +103,13 @@ impl Metadata {
             width: 0,
             height: 0,
             extra,
+            color_space: ColorSpace::default(),
+            exif: None,
+            xmp: None,
+            text: Vec::new(),
+            values: BTreeMap::new(),
+            origin: (0, 0),
+            alpha_mode: AlphaMode::Straight,
         }
     }
 
@@ -34,6 +127,13 @@ impl Metadata {
                 .into_iter()
                 .map(|(k, v)| (k.into(), v.into()))
                 .collect(),
+            color_space: ColorSpace::default(),
+            exif: None,
+            xmp: None,
+            text: Vec::new(),
+            values: BTreeMap::new(),
+            origin: (0, 0),
+            alpha_mode: AlphaMode::Straight,
         }
     }
 
@@ -51,6 +151,13 @@ impl Metadata {
                 .into_iter()
                 .map(|(k, v)| (k.into(), v.into()))
                 .collect(),
+            color_space: ColorSpace::default(),
+            exif: None,
+            xmp: None,
+            text: Vec::new(),
+            values: BTreeMap::new(),
+            origin: (0, 0),
+            alpha_mode: AlphaMode::Straight,
         }
     }
 
@@ -63,6 +170,141 @@ impl Metadata {
                 .into_iter()
                 .map(|(k, v)| (k.to_string(), v.to_string()))
                 .collect(),
+            color_space: ColorSpace::default(),
+            exif: None,
+            xmp: None,
+            text: Vec::new(),
+            values: BTreeMap::new(),
+            origin: (0, 0),
+            alpha_mode: AlphaMode::Straight,
         }
     }
-}
\ No newline at end of file
+
+    /// Set a typed value
+    pub fn set_value<K: Into<String>, V: Into<MetadataValue>>(&mut self, key: K, value: V) {
+        self.values.insert(key.into(), value.into());
+    }
+
+    /// Remove a typed value
+    pub fn remove_value(&mut self, key: &str) -> Option<MetadataValue> {
+        self.values.remove(key)
+    }
+
+    /// Typed value for `key`.
+    ///
+    /// Falls back to the string in `extra` (mapped with [`MetadataValue::parse`])
+    /// for files that only have string values.
+    pub fn value(&self, key: &str) -> Option<MetadataValue> {
+        self.values
+            .get(key)
+            .cloned()
+            .or_else(|| self.extra.get(key).map(|s| MetadataValue::parse(s)))
+    }
+
+    /// All typed values, including the ones mapped from `extra`
+    /// (typed values win on key conflicts)
+    pub fn typed_values(&self) -> BTreeMap<String, MetadataValue> {
+        let mut values: BTreeMap<String, MetadataValue> = self
+            .extra
+            .iter()
+            .map(|(k, v)| (k.clone(), MetadataValue::parse(v)))
+            .collect();
+        values.extend(self.values.iter().map(|(k, v)| (k.clone(), v.clone())));
+        values
+    }
+
+    pub fn get_int(&self, key: &str) -> Option<i64> {
+        self.value(key)?.as_int()
+    }
+
+    pub fn get_float(&self, key: &str) -> Option<f64> {
+        self.value(key)?.as_float()
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.value(key)?.as_bool()
+    }
+
+    pub fn get_string(&self, key: &str) -> Option<String> {
+        match self.values.get(key) {
+            Some(MetadataValue::String(s)) => Some(s.clone()),
+            Some(_) => None,
+            None => self.extra.get(key).cloned(),
+        }
+    }
+
+    pub fn get_bytes(&self, key: &str) -> Option<&[u8]> {
+        self.values.get(key)?.as_bytes()
+    }
+
+    pub fn get_list(&self, key: &str) -> Option<&[MetadataValue]> {
+        self.values.get(key)?.as_list()
+    }
+
+    pub fn get_map(&self, key: &str) -> Option<&BTreeMap<String, MetadataValue>> {
+        self.values.get(key)?.as_map()
+    }
+
+    pub fn get_timestamp(&self, key: &str) -> Option<SystemTime> {
+        self.value(key)?.as_timestamp()
+    }
+
+    /// Metadata stored outside the header struct
+    pub(crate) fn to_chunks(&self) -> Result<Vec<Chunk>, NPNGError> {
+        let mut chunks = Vec::new();
+        if self.color_space != ColorSpace::Srgb {
+            chunks.push(Chunk::new(TAG_COLOR_SPACE, &self.color_space)?);
+        }
+        if let Some(exif) = &self.exif {
+            chunks.push(Chunk::new(TAG_EXIF, exif)?);
+        }
+        if let Some(xmp) = &self.xmp {
+            chunks.push(Chunk::new(TAG_XMP, xmp)?);
+        }
+        if !self.text.is_empty() {
+            chunks.push(Chunk::new(TAG_TEXT, &self.text)?);
+        }
+        if !self.values.is_empty() {
+            chunks.push(Chunk::new(TAG_VALUES, &self.values)?);
+        }
+        if self.origin != (0, 0) {
+            chunks.push(Chunk::new(TAG_ORIGIN, self.origin)?);
+        }
+        if self.alpha_mode != AlphaMode::Straight {
+            chunks.push(Chunk::new(TAG_ALPHA_MODE, self.alpha_mode)?);
+        }
+        if self.width > u16::MAX as u32 || self.height > u16::MAX as u32 {
+            chunks.push(Chunk::new(TAG_SIZE, (self.width, self.height))?);
+        }
+        Ok(chunks)
+    }
+
+    /// Fill metadata from the chunks read after the header
+    pub(crate) fn apply_chunks(&mut self, chunks: &[Chunk]) -> Result<(), NPNGError> {
+        if let Some(c) = find_chunk(chunks, &TAG_COLOR_SPACE) {
+            self.color_space = c.value()?;
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_EXIF) {
+            self.exif = Some(c.value()?);
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_XMP) {
+            self.xmp = Some(c.value()?);
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_TEXT) {
+            self.text = c.value()?;
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_VALUES) {
+            self.values = c.value()?;
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_ORIGIN) {
+            self.origin = c.value()?;
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_ALPHA_MODE) {
+            self.alpha_mode = c.value()?;
+        }
+        if let Some(c) = find_chunk(chunks, &TAG_SIZE) {
+            (self.width, self.height) = c.value()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+use crate::error::NPNGError;
+use crate::types::pixel::WidePixel;
+use crate::types::{Img, WideImg};
+use std::collections::{HashMap, HashSet};
+
+/// Difference between an original and a decoded image, see [`compare_images`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ErrorReport {
+    /// Largest absolute difference of a channel value (RGBA)
+    pub max_error: u8,
+    /// Mean squared error per channel
+    pub mse: f64,
+    /// Peak signal-to-noise ratio in dB (infinite if equal)
+    pub psnr: f64,
+    /// Mean structural similarity of 8x8 windows (stride 4) over the RGBA channels,
+    /// `1.0` if equal
+    pub ssim: f64,
+}
+
+impl ErrorReport {
+    /// Whether no channel deviates by more than `max_error`
+    pub fn within(&self, max_error: u8) -> bool {
+        self.max_error <= max_error
+    }
+}
+
+/// Original and decoded RGBA by position
+type Pairs = HashMap<(u32, u32), ([u8; 4], [u8; 4])>;
+
+const SSIM_WINDOW: usize = 8;
+const SSIM_STRIDE: usize = 4;
+const SSIM_C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+const SSIM_C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+
+/// Compare a decoded image with the original (e.g. for `Config.max_error`).
+///
+/// Pixels are matched by coordinates. Fully transparent pixels of the original that are
+/// missing in the decoded image (dropped by the alpha policy) are skipped; for SSIM
+/// missing pixels are transparent black in both images.
+///
+/// # Returns
+/// - `Ok(ErrorReport)` - Max error, MSE, PSNR and SSIM.
+/// - `Err(NPNGError)` - If a visible pixel is missing on one side.
+pub fn compare_images(original: &Img, decoded: &Img) -> Result<ErrorReport, NPNGError> {
+    let original: Vec<WidePixel> = original
+        .pixels
+        .iter()
+        .cloned()
+        .map(WidePixel::from)
+        .collect();
+    let decoded: Vec<WidePixel> = decoded
+        .pixels
+        .iter()
+        .cloned()
+        .map(WidePixel::from)
+        .collect();
+    compare_pixels(&original, &decoded)
+}
+
+/// [`compare_images`] for images with 32-bit coordinates
+pub fn compare_wide_images(
+    original: &WideImg,
+    decoded: &WideImg,
+) -> Result<ErrorReport, NPNGError> {
+    compare_pixels(&original.pixels, &decoded.pixels)
+}
+
+fn compare_pixels(original: &[WidePixel], decoded: &[WidePixel]) -> Result<ErrorReport, NPNGError> {
+    let decoded_colors: HashMap<(u32, u32), u32> =
+        decoded.iter().map(|p| ((p.x, p.y), p.color)).collect();
+    let original_colors: HashMap<(u32, u32), u32> =
+        original.iter().map(|p| ((p.x, p.y), p.color)).collect();
+    if let Some(p) = decoded
+        .iter()
+        .find(|p| !original_colors.contains_key(&(p.x, p.y)))
+    {
+        return Err(NPNGError::Error(format!(
+            "No original pixel at x:{} y:{}",
+            p.x, p.y
+        )));
+    }
+
+    let mut pairs = Pairs::with_capacity(original.len());
+    for p in original {
+        match decoded_colors.get(&(p.x, p.y)) {
+            Some(&color) => {
+                pairs.insert((p.x, p.y), (p.color.to_be_bytes(), color.to_be_bytes()));
+            }
+            None if p.color & 0xFF == 0 => {}
+            None => {
+                return Err(NPNGError::Error(format!(
+                    "No decoded pixel at x:{} y:{}",
+                    p.x, p.y
+                )));
+            }
+        }
+    }
+
+    let mut max_error = 0;
+    let mut error = 0.0;
+    for (a, b) in pairs.values() {
+        for c in 0..4 {
+            let d = a[c].abs_diff(b[c]);
+            max_error = max_error.max(d);
+            error += (d as f64) * (d as f64);
+        }
+    }
+    let mse = error / (pairs.len().max(1) * 4) as f64;
+    let psnr = if mse == 0.0 {
+        f64::INFINITY
+    } else {
+        10.0 * (255.0 * 255.0 / mse).log10()
+    };
+
+    Ok(ErrorReport {
+        max_error,
+        mse,
+        psnr,
+        ssim: ssim(&pairs),
+    })
+}
+
+/// Window starts along an axis of `len` pixels: every [`SSIM_STRIDE`] pixels, and the
+/// last window ends at the edge (windows cover the whole axis even if it is shorter
+/// than one window)
+struct Windows {
+    len: u64,
+}
+
+impl Windows {
+    fn last(&self) -> u64 {
+        self.len.saturating_sub(SSIM_WINDOW as u64)
+    }
+
+    fn count(&self) -> u64 {
+        let last = self.last();
+        last / SSIM_STRIDE as u64 + 1 + u64::from(!last.is_multiple_of(SSIM_STRIDE as u64))
+    }
+
+    fn start(&self, i: u64) -> u64 {
+        (i * SSIM_STRIDE as u64).min(self.last())
+    }
+
+    /// Windows covering position `p`
+    fn covering(&self, p: u64) -> impl Iterator<Item = u64> + '_ {
+        let first = p.saturating_sub(SSIM_WINDOW as u64 - 1) / SSIM_STRIDE as u64;
+        (first..=p / SSIM_STRIDE as u64)
+            .chain(std::iter::once(self.count() - 1))
+            .filter(move |&i| {
+                i < self.count() && (self.start(i)..self.start(i) + SSIM_WINDOW as u64).contains(&p)
+            })
+    }
+}
+
+/// Mean SSIM over windows of the bounding box of `pairs`. Only windows with pixels are
+/// computed, the others are transparent black in both images (SSIM 1)
+fn ssim(pairs: &Pairs) -> f64 {
+    if pairs.values().all(|(a, b)| a == b) {
+        return 1.0;
+    }
+    let min_x = pairs.keys().map(|p| p.0).min().unwrap_or(0);
+    let min_y = pairs.keys().map(|p| p.1).min().unwrap_or(0);
+    let columns = Windows {
+        len: pairs
+            .keys()
+            .map(|p| (p.0 - min_x) as u64 + 1)
+            .max()
+            .unwrap_or(0),
+    };
+    let rows = Windows {
+        len: pairs
+            .keys()
+            .map(|p| (p.1 - min_y) as u64 + 1)
+            .max()
+            .unwrap_or(0),
+    };
+
+    let mut windows = HashSet::new();
+    for &(x, y) in pairs.keys() {
+        for wy in rows.covering((y - min_y) as u64) {
+            windows.extend(columns.covering((x - min_x) as u64).map(|wx| (wx, wy)));
+        }
+    }
+
+    let mut total = 0.0;
+    for &(wx, wy) in &windows {
+        let (x0, y0) = (columns.start(wx), rows.start(wy));
+        for c in 0..4 {
+            let (mut sa, mut sb, mut saa, mut sbb, mut sab, mut n) = (0.0, 0.0, 0.0, 0.0, 0.0, 0.0);
+            for y in y0..(y0 + SSIM_WINDOW as u64).min(rows.len) {
+                for x in x0..(x0 + SSIM_WINDOW as u64).min(columns.len) {
+                    let position = ((x + min_x as u64) as u32, (y + min_y as u64) as u32);
+                    let (va, vb) = match pairs.get(&position) {
+                        Some((a, b)) => (a[c] as f64, b[c] as f64),
+                        None => (0.0, 0.0),
+                    };
+                    sa += va;
+                    sb += vb;
+                    saa += va * va;
+                    sbb += vb * vb;
+                    sab += va * vb;
+                    n += 1.0;
+                }
+            }
+            let (ma, mb) = (sa / n, sb / n);
+            let (va, vb) = (saa / n - ma * ma, sbb / n - mb * mb);
+            let cov = sab / n - ma * mb;
+            total += ((2.0 * ma * mb + SSIM_C1) * (2.0 * cov + SSIM_C2))
+                / ((ma * ma + mb * mb + SSIM_C1) * (va + vb + SSIM_C2));
+        }
+    }
+    let count = columns.count() as f64 * rows.count() as f64 * 4.0;
+    let empty = count - windows.len() as f64 * 4.0;
+    (total + empty) / count
+}
@@ -0,0 +1,297 @@
+use crate::Layout;
+use crate::error::NPNGError;
+use crate::types::color::AlphaMode;
+use crate::types::pixel::{CoordWidth, WidePixel};
+use crate::utils::{deserialize_prefix, serialize};
+use bincode::{Decode, Encode};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+/// Downsampling filter of [`Mipmaps`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum MipFilter {
+    /// Area average of the covered pixels
+    #[default]
+    Box,
+    /// Lanczos kernel with 3 lobes (sharper, can ring at hard edges)
+    Lanczos,
+}
+
+impl Display for MipFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MipFilter::Box => f.write_str("box"),
+            MipFilter::Lanczos => f.write_str("lanczos"),
+        }
+    }
+}
+
+/// Resolution pyramid stored in the file (`Config.mipmaps`).
+///
+/// Level `n` has `ceil(width / 2^n)` x `ceil(height / 2^n)` pixels and is filtered from
+/// level `n - 1` with premultiplied colors, so transparent pixels do not darken edges.
+/// Level 0 is the image itself, see [`crate::decode_level`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Mipmaps {
+    /// Largest number of downsampled levels, the pyramid stops at 1x1
+    pub levels: u8,
+    pub filter: MipFilter,
+}
+
+impl Default for Mipmaps {
+    fn default() -> Self {
+        Mipmaps {
+            levels: u8::MAX,
+            filter: MipFilter::Box,
+        }
+    }
+}
+
+impl Display for Mipmaps {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.filter, self.levels)
+    }
+}
+
+impl Mipmaps {
+    pub fn new(levels: u8, filter: MipFilter) -> Self {
+        Mipmaps { levels, filter }
+    }
+}
+
+/// Index entry of a stored pyramid level: canvas, body layout and compressed body length.
+///
+/// The "mips" chunk holds the filter and the index, then the level bodies in order,
+/// so one level is read without decoding the others
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct MipLevel {
+    pub width: u32,
+    pub height: u32,
+    pub layout: Layout,
+    pub coords: CoordWidth,
+    pub len: u64,
+}
+
+/// Data of the "mips" chunk: index, then the compressed level bodies
+pub(crate) fn encode_levels(
+    filter: MipFilter,
+    levels: Vec<(MipLevel, Vec<u8>)>,
+) -> Result<Vec<u8>, NPNGError> {
+    let (index, bodies): (Vec<MipLevel>, Vec<Vec<u8>>) = levels.into_iter().unzip();
+    let mut data = serialize((filter, index), true)?;
+    bodies.into_iter().for_each(|body| data.extend(body));
+    Ok(data)
+}
+
+/// Filter and index of the "mips" chunk, with the position of the first level body
+pub(crate) fn read_index(data: &[u8]) -> Result<(MipFilter, Vec<MipLevel>, usize), NPNGError> {
+    let ((filter, index), len): ((MipFilter, Vec<MipLevel>), usize) =
+        deserialize_prefix(data, true)?;
+    Ok((filter, index, len))
+}
+
+/// Index entry and compressed body of level `n` (1 - the first downsampled level)
+pub(crate) fn read_level(data: &[u8], n: u8) -> Result<(MipLevel, &[u8]), NPNGError> {
+    let (_, index, mut pos) = read_index(data)?;
+    let Some(level) = (n as usize).checked_sub(1).and_then(|i| index.get(i)) else {
+        return Err(NPNGError::Error(format!("No mipmap level {}", n)));
+    };
+    for l in &index[..n as usize - 1] {
+        pos = pos.saturating_add(usize::try_from(l.len).unwrap_or(usize::MAX));
+    }
+    let body = usize::try_from(level.len)
+        .ok()
+        .and_then(|len| data.get(pos..pos.checked_add(len)?))
+        .ok_or_else(|| NPNGError::Error(format!("Truncated mipmap level {}", n)))?;
+    Ok((level.clone(), body))
+}
+
+pub(crate) type Rgba = [f32; 4];
+
+/// Premultiplied color at `(x, y)`
+pub(crate) type Sample = (u32, u32, Rgba);
+
+/// Resampling of one axis from `src` to `dst` pixels (positions in `f64`, wide canvases
+/// don't fit into the `f32` mantissa)
+struct Axis {
+    src: u32,
+    dst: u32,
+    scale: f64,
+    filter: MipFilter,
+    /// Weight sums of destination pixels (normalization)
+    sums: HashMap<u32, f64>,
+}
+
+impl Axis {
+    fn new(src: u32, dst: u32, filter: MipFilter) -> Self {
+        Axis {
+            src,
+            dst,
+            scale: src as f64 / dst as f64,
+            filter,
+            sums: HashMap::new(),
+        }
+    }
+
+    /// Source pixels read by destination pixel `i`
+    fn taps(&self, i: u32) -> std::ops::Range<u32> {
+        let (start, end) = (i as f64 * self.scale, (i + 1) as f64 * self.scale);
+        match self.filter {
+            MipFilter::Box => start.floor() as u32..(end.ceil() as u32).min(self.src),
+            MipFilter::Lanczos => {
+                let center = (start + end) / 2.0;
+                let radius = 3.0 * self.scale;
+                (center - radius).floor().max(0.0) as u32
+                    ..((center + radius).ceil() as u32).min(self.src)
+            }
+        }
+    }
+
+    /// Unnormalized weight of source pixel `j` in destination pixel `i`
+    fn weight(&self, i: u32, j: u32) -> f64 {
+        let (start, end) = (i as f64 * self.scale, (i + 1) as f64 * self.scale);
+        match self.filter {
+            // overlap of the source pixel with the destination pixel
+            MipFilter::Box => (end.min(j as f64 + 1.0) - start.max(j as f64)).max(0.0),
+            MipFilter::Lanczos => lanczos((j as f64 + 0.5 - (start + end) / 2.0) / self.scale),
+        }
+    }
+
+    /// Destination pixels reading source pixel `j`, with normalized weights
+    fn targets(&mut self, j: u32) -> Vec<(u32, f32)> {
+        let reach = match self.filter {
+            MipFilter::Box => 1.0,
+            MipFilter::Lanczos => 3.0 * self.scale + 1.0,
+        };
+        let first = ((j as f64 - reach) / self.scale - 1.0).floor().max(0.0) as u32;
+        let last = ((j as f64 + 1.0 + reach) / self.scale + 1.0)
+            .ceil()
+            .min(self.dst as f64) as u32;
+        let mut targets = Vec::new();
+        for i in first..last {
+            let taps = self.taps(i);
+            if !taps.contains(&j) {
+                continue;
+            }
+            let sum = match self.sums.get(&i) {
+                Some(&sum) => sum,
+                None => {
+                    let sum = match self.filter {
+                        // the taps cover the destination pixel (thumbnails can have millions)
+                        MipFilter::Box => {
+                            ((i + 1) as f64 * self.scale).min(self.src as f64)
+                                - i as f64 * self.scale
+                        }
+                        MipFilter::Lanczos => taps.map(|t| self.weight(i, t)).sum(),
+                    };
+                    self.sums.insert(i, sum);
+                    sum
+                }
+            };
+            targets.push((i, (self.weight(i, j) / sum) as f32));
+        }
+        targets
+    }
+}
+
+fn lanczos(x: f64) -> f64 {
+    if x.abs() >= 3.0 {
+        return 0.0;
+    }
+    if x == 0.0 {
+        return 1.0;
+    }
+    let px = std::f64::consts::PI * x;
+    3.0 * px.sin() * (px / 3.0).sin() / (px * px)
+}
+
+/// Resample premultiplied colors of a `width` x `height` canvas to `w` x `h`.
+///
+/// Only the given samples (sorted by `y`, then `x`) are read, missing pixels are
+/// transparent. The result holds the covered destination pixels in the same order.
+pub(crate) fn resize(
+    src: &[Sample],
+    (width, height): (u32, u32),
+    (w, h): (u32, u32),
+    filter: MipFilter,
+) -> Vec<Sample> {
+    let mut horizontal = Axis::new(width, w, filter);
+    let mut vertical = Axis::new(height, h, filter);
+
+    let mut rows: BTreeMap<(u32, u32), Rgba> = BTreeMap::new();
+    for &(x, y, c) in src {
+        for (i, weight) in horizontal.targets(x) {
+            let out = rows.entry((y, i)).or_insert([0.0; 4]);
+            (0..4).for_each(|k| out[k] += c[k] * weight);
+        }
+    }
+    let mut dst: BTreeMap<(u32, u32), Rgba> = BTreeMap::new();
+    for (&(y, x), &c) in &rows {
+        for (i, weight) in vertical.targets(y) {
+            let out = dst.entry((i, x)).or_insert([0.0; 4]);
+            (0..4).for_each(|k| out[k] += c[k] * weight);
+        }
+    }
+    dst.into_iter().map(|((y, x), c)| (x, y, c)).collect()
+}
+
+/// Premultiplied colors of the pixels, sorted by `y`, then `x`
+pub(crate) fn premultiplied(pixels: &[WidePixel], alpha_mode: AlphaMode) -> Vec<Sample> {
+    let mut samples: Vec<Sample> = pixels
+        .iter()
+        .map(|p| {
+            let [r, g, b, a] = p.color.to_be_bytes().map(|c| c as f32);
+            let c = match alpha_mode {
+                AlphaMode::Straight => [r * a / 255.0, g * a / 255.0, b * a / 255.0, a],
+                AlphaMode::Premultiplied => [r, g, b, a],
+            };
+            (p.x, p.y, c)
+        })
+        .collect();
+    samples.sort_unstable_by_key(|&(x, y, _)| (y, x));
+    samples
+}
+
+/// Straight (`AlphaMode::Straight`) or premultiplied RGBA of a filtered color
+pub(crate) fn to_rgba(c: Rgba, alpha_mode: AlphaMode) -> [u8; 4] {
+    let alpha = c[3].clamp(0.0, 255.0);
+    let a = alpha.round();
+    if a == 0.0 {
+        return [0; 4];
+    }
+    let channel = |v: f32| match alpha_mode {
+        AlphaMode::Straight => (v.clamp(0.0, alpha) * 255.0 / alpha).round() as u8,
+        AlphaMode::Premultiplied => v.clamp(0.0, a).round() as u8,
+    };
+    [channel(c[0]), channel(c[1]), channel(c[2]), a as u8]
+}
+
+/// Downsampled levels of the pixels of a `width` x `height` canvas, colors in `alpha_mode`.
+/// Fully transparent pixels are not written
+pub(crate) fn pyramid(
+    pixels: &[WidePixel],
+    (width, height): (u32, u32),
+    alpha_mode: AlphaMode,
+    mipmaps: Mipmaps,
+) -> Vec<(Vec<WidePixel>, (u32, u32))> {
+    if width == 0 || height == 0 {
+        return Vec::new();
+    }
+    let mut samples = premultiplied(pixels, alpha_mode);
+    let mut levels = Vec::new();
+    let mut size = (width, height);
+    while levels.len() < mipmaps.levels as usize && size != (1, 1) {
+        let half = (size.0.div_ceil(2), size.1.div_ceil(2));
+        samples = resize(&samples, size, half, mipmaps.filter);
+        size = half;
+        let level = samples
+            .iter()
+            .filter_map(|&(x, y, c)| {
+                let color = u32::from_be_bytes(to_rgba(c, alpha_mode));
+                (color & 0xFF != 0).then(|| WidePixel::new(x, y, color))
+            })
+            .collect();
+        levels.push((level, size));
+    }
+    levels
+}
@@ -1,12 +1,23 @@
-use std::str::FromStr;
-use bincode::{Decode, Encode};
-use crate::error::NPNGError;
 use crate::Pixel;
+use crate::error::NPNGError;
+use crate::types::color::{AlphaMode, ColorSpace, convert_alpha, convert_colors};
 use crate::types::metadata::Metadata;
+use crate::types::pixel::{GlobalPixel, WidePixel};
+use bincode::{Decode, Encode};
+use std::str::FromStr;
 
-pub mod metadata;
+pub mod channel;
+pub mod chunk;
+pub mod color;
+pub mod document;
 pub mod header;
+pub mod metadata;
+pub mod metrics;
+pub mod mipmap;
 pub mod pixel;
+pub mod quantize;
+pub mod thumbnail;
+pub mod value;
 
 #[derive(Debug, Clone)]
 pub struct EncoderVersion {
@@ -34,9 +45,9 @@ impl FromStr for VersionMetadata {
     }
 }
 
-impl Into<String> for VersionMetadata {
-    fn into(self) -> String {
-        match self {
+impl From<VersionMetadata> for String {
+    fn from(v: VersionMetadata) -> Self {
+        match v {
             VersionMetadata::Experimental => "experimental".to_string(),
             VersionMetadata::Beta => "beta".to_string(),
             VersionMetadata::Stable => "stable".to_string(),
@@ -83,6 +94,7 @@ impl Img {
         self.metadata.clone()
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn as_ref(&self) -> &Img {
         self
     }
@@ -98,6 +110,209 @@ impl Img {
     pub fn metadata_ref(&self) -> &Metadata {
         &self.metadata
     }
+
+    /// Pixels in global coordinates (`metadata.origin` + local position)
+    pub fn global_pixels(&self) -> Vec<GlobalPixel> {
+        self.pixels
+            .iter()
+            .map(|p| p.global(self.metadata.origin))
+            .collect()
+    }
+
+    /// Build an image from pixels in global coordinates.
+    ///
+    /// The origin is placed at the top-left pixel and the canvas is shrunk to the
+    /// bounding box, so tiles can be stored compactly and recombined with
+    /// [`Img::global_pixels`].
+    pub fn from_global_pixels(
+        pixels: Vec<GlobalPixel>,
+        mut metadata: Metadata,
+    ) -> Result<Img, NPNGError> {
+        let min_x = pixels.iter().map(|p| p.x).min().unwrap_or(0);
+        let min_y = pixels.iter().map(|p| p.y).min().unwrap_or(0);
+        let max_x = pixels.iter().map(|p| p.x).max().unwrap_or(0);
+        let max_y = pixels.iter().map(|p| p.y).max().unwrap_or(0);
+        // local coordinates are u16, a span of 65535 is a 65536-wide canvas
+        if max_x.abs_diff(min_x) > u16::MAX as u64 || max_y.abs_diff(min_y) > u16::MAX as u64 {
+            return Err(NPNGError::Error(
+                "Pixels do not fit into one canvas".to_string(),
+            ));
+        }
+        metadata.origin = (
+            i32::try_from(min_x).map_err(|_| NPNGError::Error("Origin overflow".to_string()))?,
+            i32::try_from(min_y).map_err(|_| NPNGError::Error("Origin overflow".to_string()))?,
+        );
+        metadata.width = (max_x - min_x + 1) as u32;
+        metadata.height = (max_y - min_y + 1) as u32;
+
+        Ok(Img {
+            pixels: pixels
+                .into_iter()
+                .map(|p| Pixel::new((p.x - min_x) as u16, (p.y - min_y) as u16, p.color))
+                .collect(),
+            encoder_version: crate::version(),
+            metadata,
+        })
+    }
+
+    /// Move the canvas by `(dx, dy)` in global coordinates. Local coordinates are unchanged.
+    pub fn translate(&mut self, dx: i32, dy: i32) -> Result<(), NPNGError> {
+        let (x, y) = self.metadata.origin;
+        self.metadata.origin = (
+            x.checked_add(dx)
+                .ok_or(NPNGError::Error("Origin overflow".to_string()))?,
+            y.checked_add(dy)
+                .ok_or(NPNGError::Error("Origin overflow".to_string()))?,
+        );
+        Ok(())
+    }
+
+    /// Shift pixels so the top-left pixel is at local (0, 0) and shrink the canvas,
+    /// keeping global coordinates unchanged
+    pub fn normalize(&mut self) -> Result<(), NPNGError> {
+        let min_x = self.pixels.iter().map(|p| p.x).min().unwrap_or(0);
+        let min_y = self.pixels.iter().map(|p| p.y).min().unwrap_or(0);
+        self.translate(min_x as i32, min_y as i32)?;
+        for p in self.pixels.iter_mut() {
+            p.x -= min_x;
+            p.y -= min_y;
+        }
+        self.metadata.width = self.metadata.width.saturating_sub(min_x as u32);
+        self.metadata.height = self.metadata.height.saturating_sub(min_y as u32);
+        Ok(())
+    }
+
+    /// Convert pixel colors from `metadata.color_space` into `color_space`.
+    /// Premultiplied colors are converted unpremultiplied
+    pub fn convert_color_space(&mut self, color_space: ColorSpace) -> Result<(), NPNGError> {
+        convert_color_space(
+            self.pixels.iter_mut().map(|p| &mut p.color),
+            &mut self.metadata,
+            color_space,
+        )
+    }
+
+    /// Convert pixel colors from `metadata.alpha_mode` into `alpha_mode`
+    pub fn convert_alpha_mode(&mut self, alpha_mode: AlphaMode) {
+        convert_alpha(
+            self.pixels.iter_mut().map(|p| &mut p.color),
+            self.metadata.alpha_mode,
+            alpha_mode,
+        );
+        self.metadata.alpha_mode = alpha_mode;
+    }
+
+    /// Multiply colors by alpha (no-op if already premultiplied)
+    pub fn premultiply(&mut self) {
+        self.convert_alpha_mode(AlphaMode::Premultiplied);
+    }
+
+    /// Divide colors by alpha (no-op if already straight)
+    pub fn unpremultiply(&mut self) {
+        self.convert_alpha_mode(AlphaMode::Straight);
+    }
+}
+
+/// Convert colors between color spaces, unpremultiplying around the conversion
+fn convert_color_space<'a>(
+    colors: impl Iterator<Item = &'a mut u32>,
+    metadata: &mut Metadata,
+    color_space: ColorSpace,
+) -> Result<(), NPNGError> {
+    if metadata.color_space == color_space {
+        return Ok(());
+    }
+    let mut colors: Vec<&mut u32> = colors.collect();
+    convert_alpha(
+        colors.iter_mut().map(|c| &mut **c),
+        metadata.alpha_mode,
+        AlphaMode::Straight,
+    );
+    convert_colors(
+        colors.iter_mut().map(|c| &mut **c),
+        &metadata.color_space,
+        &color_space,
+    )?;
+    convert_alpha(
+        colors.iter_mut().map(|c| &mut **c),
+        AlphaMode::Straight,
+        metadata.alpha_mode,
+    );
+    metadata.color_space = color_space;
+    Ok(())
+}
+
+/// Image with 32-bit pixel coordinates, for canvases larger than 65535x65535
+#[derive(Debug, Clone)]
+pub struct WideImg {
+    pub pixels: Vec<WidePixel>,
+    pub encoder_version: EncoderVersion,
+    pub metadata: Metadata,
+}
+
+impl WideImg {
+    pub fn pixels_ref(&self) -> &Vec<WidePixel> {
+        &self.pixels
+    }
+
+    pub fn metadata_ref(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Convert pixel colors from `metadata.color_space` into `color_space`
+    pub fn convert_color_space(&mut self, color_space: ColorSpace) -> Result<(), NPNGError> {
+        convert_color_space(
+            self.pixels.iter_mut().map(|p| &mut p.color),
+            &mut self.metadata,
+            color_space,
+        )
+    }
+
+    /// Convert pixel colors from `metadata.alpha_mode` into `alpha_mode`
+    pub fn convert_alpha_mode(&mut self, alpha_mode: AlphaMode) {
+        convert_alpha(
+            self.pixels.iter_mut().map(|p| &mut p.color),
+            self.metadata.alpha_mode,
+            alpha_mode,
+        );
+        self.metadata.alpha_mode = alpha_mode;
+    }
+
+    /// Multiply colors by alpha (no-op if already premultiplied)
+    pub fn premultiply(&mut self) {
+        self.convert_alpha_mode(AlphaMode::Premultiplied);
+    }
+
+    /// Divide colors by alpha (no-op if already straight)
+    pub fn unpremultiply(&mut self) {
+        self.convert_alpha_mode(AlphaMode::Straight);
+    }
+}
+
+impl From<Img> for WideImg {
+    fn from(img: Img) -> Self {
+        WideImg {
+            pixels: img.pixels.into_iter().map(WidePixel::from).collect(),
+            encoder_version: img.encoder_version,
+            metadata: img.metadata,
+        }
+    }
+}
+
+/// Fails if a pixel does not fit into 16-bit coordinates
+impl TryFrom<WideImg> for Img {
+    type Error = NPNGError;
+    fn try_from(img: WideImg) -> Result<Self, Self::Error> {
+        Ok(Img {
+            pixels: img
+                .pixels
+                .into_iter()
+                .map(Pixel::try_from)
+                .collect::<Result<_, _>>()?,
+            encoder_version: img.encoder_version,
+            metadata: img.metadata,
+        })
+    }
 }
 
 #[repr(C)]
@@ -107,5 +322,5 @@ pub(crate) struct CheckSum {
     pub crc32: u32,
 }
 
-pub(crate) const MAX_PIXELS: usize = SIZE * SIZE; // 4_294_967_296
-pub(crate) const SIZE: usize = 65536;
+/// Maximum pixel count of a file with 16-bit coordinates
+pub(crate) const MAX_PIXELS: usize = 65536 * 65536; // 4_294_967_296
@@ -1,7 +1,8 @@
-use bincode::{Decode, Encode};
+use crate::error::NPNGError;
 use crate::utils::set_byte;
+use bincode::{Decode, Encode};
 
-#[derive(Debug, Clone, Encode, Decode)]
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
 pub struct Pixel {
     pub x: u16,
     pub y: u16,
@@ -12,6 +13,93 @@ impl Pixel {
     pub fn new(x: u16, y: u16, color: u32) -> Self {
         Pixel { x, y, color }
     }
+
+    /// Global position of the pixel on a canvas placed at `origin`
+    pub fn global(&self, origin: (i32, i32)) -> GlobalPixel {
+        GlobalPixel {
+            x: origin.0 as i64 + self.x as i64,
+            y: origin.1 as i64 + self.y as i64,
+            color: self.color,
+        }
+    }
+}
+
+/// Pixel in global (signed) coordinates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GlobalPixel {
+    pub x: i64,
+    pub y: i64,
+    pub color: u32, //rgba
+}
+
+/// Pixel with 32-bit coordinates, for canvases larger than 65535x65535
+#[derive(Debug, Clone, PartialEq, Eq, Encode, Decode)]
+pub struct WidePixel {
+    pub x: u32,
+    pub y: u32,
+    pub color: u32, //rgba
+}
+
+impl WidePixel {
+    pub fn new(x: u32, y: u32, color: u32) -> Self {
+        WidePixel { x, y, color }
+    }
+}
+
+impl From<Pixel> for WidePixel {
+    fn from(p: Pixel) -> Self {
+        WidePixel {
+            x: p.x as u32,
+            y: p.y as u32,
+            color: p.color,
+        }
+    }
+}
+
+impl TryFrom<WidePixel> for Pixel {
+    type Error = NPNGError;
+    fn try_from(p: WidePixel) -> Result<Self, Self::Error> {
+        match (u16::try_from(p.x), u16::try_from(p.y)) {
+            (Ok(x), Ok(y)) => Ok(Pixel {
+                x,
+                y,
+                color: p.color,
+            }),
+            _ => Err(NPNGError::Error(format!(
+                "Pixel x:{} y:{} does not fit into 16-bit coordinates",
+                p.x, p.y
+            ))),
+        }
+    }
+}
+
+/// Coordinate width of stored pixel records (selected in the header).
+///
+/// New widths can be added, match with a wildcard arm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+#[non_exhaustive]
+pub enum CoordWidth {
+    /// `Pixel` records, canvases up to 65536x65536
+    #[default]
+    U16,
+    /// `WidePixel` records
+    U32,
+    /// Canvases up to 256x256.
+    ///
+    /// Only written by the delta and QOI layouts, which pick it by themselves. Bodies of
+    /// other layouts with 8-bit coordinates are rejected when decoding.
+    U8,
+}
+
+impl CoordWidth {
+    /// Narrowest width for a canvas
+    pub(crate) fn fit(width: u32, height: u32) -> Self {
+        match width.max(height) {
+            0..=256 => CoordWidth::U8,
+            257..=65536 => CoordWidth::U16,
+            _ => CoordWidth::U32,
+        }
+    }
 }
 
 /// Pixel without alpha channel
@@ -35,3 +123,25 @@ impl From<RGBPixel> for Pixel {
         }
     }
 }
+
+/// Wide pixel without alpha channel
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct RGBWidePixel {
+    pub x: u32,
+    pub y: u32,
+    pub color: [u8; 3],
+}
+
+impl From<RGBWidePixel> for WidePixel {
+    fn from(rgb: RGBWidePixel) -> Self {
+        let col: u32 = set_byte(0, 3, rgb.color[0]);
+        let col: u32 = set_byte(col, 2, rgb.color[1]);
+        let col: u32 = set_byte(col, 1, rgb.color[2]);
+        let col: u32 = set_byte(col, 0, 0xFF);
+        Self {
+            x: rgb.x,
+            y: rgb.y,
+            color: col,
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use crate::error::NPNGError;
+use crate::types::pixel::WidePixel;
+use bincode::{Decode, Encode};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Display;
+
+/// How the palette of [`Quantize`] is built
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum QuantizeMethod {
+    /// Split the color box with the widest channel range at the median
+    #[default]
+    MedianCut,
+    /// Merge the least used octree nodes (16 children, RGBA)
+    Octree,
+    /// K-means refinement of the median-cut palette
+    KMeans,
+}
+
+impl Display for QuantizeMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QuantizeMethod::MedianCut => f.write_str("median-cut"),
+            QuantizeMethod::Octree => f.write_str("octree"),
+            QuantizeMethod::KMeans => f.write_str("k-means"),
+        }
+    }
+}
+
+/// Dithering of [`Quantize`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Encode, Decode)]
+pub enum Dither {
+    /// Nearest palette color
+    #[default]
+    None,
+    /// Error diffusion to the right and lower neighbors (row-major)
+    FloydSteinberg,
+    /// 4x4 Bayer threshold matrix
+    Ordered,
+}
+
+impl Display for Dither {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Dither::None => f.write_str("none"),
+            Dither::FloydSteinberg => f.write_str("floyd-steinberg"),
+            Dither::Ordered => f.write_str("ordered"),
+        }
+    }
+}
+
+/// Lossy quantization of the written colors to a palette (`Config.quantize`).
+///
+/// Pixels store palette indices, the palette and the error are recorded in the file
+/// (see [`crate::read_palette`]). [`Quantize::with_quality`] is the way to quantize by a
+/// quality setting: it only picks `colors`, the quality itself is not stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Encode, Decode)]
+pub struct Quantize {
+    /// Largest palette size, 2-256
+    pub colors: u16,
+    pub method: QuantizeMethod,
+    pub dither: Dither,
+}
+
+impl Default for Quantize {
+    fn default() -> Self {
+        Quantize {
+            colors: 256,
+            method: QuantizeMethod::MedianCut,
+            dither: Dither::FloydSteinberg,
+        }
+    }
+}
+
+impl Display for Quantize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}/{}", self.method, self.colors, self.dither)
+    }
+}
+
+/// Palette of a quantized image
+#[derive(Debug, Clone, PartialEq, Encode, Decode)]
+pub struct Palette {
+    /// `0xRRGGBBAA` colors, referenced by index
+    pub colors: Vec<u32>,
+    /// Mean squared error per channel (RGBA) of the quantized colors
+    pub mse: f64,
+}
+
+impl Palette {
+    /// Peak signal-to-noise ratio in dB (infinite if lossless)
+    pub fn psnr(&self) -> f64 {
+        if self.mse == 0.0 {
+            f64::INFINITY
+        } else {
+            10.0 * (255.0 * 255.0 / self.mse).log10()
+        }
+    }
+}
+
+/// 4x4 Bayer matrix
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+type Rgba = [f64; 4];
+
+fn channels(color: u32) -> Rgba {
+    color.to_be_bytes().map(|c| c as f64)
+}
+
+fn to_color(c: Rgba) -> u32 {
+    u32::from_be_bytes(c.map(|v| v.round().clamp(0.0, 255.0) as u8))
+}
+
+fn distance(a: Rgba, b: Rgba) -> f64 {
+    a.iter().zip(b).map(|(a, b)| (a - b) * (a - b)).sum()
+}
+
+/// Weighted mean of colors
+fn mean(colors: &[(u32, u32)]) -> u32 {
+    let mut sum = [0.0; 4];
+    let mut n = 0.0;
+    for &(color, count) in colors {
+        for (s, c) in sum.iter_mut().zip(channels(color)) {
+            *s += c * count as f64;
+        }
+        n += count as f64;
+    }
+    to_color(sum.map(|s| s / n.max(1.0)))
+}
+
+impl Quantize {
+    pub fn new(colors: u16, method: QuantizeMethod, dither: Dither) -> Self {
+        Quantize {
+            colors,
+            method,
+            dither,
+        }
+    }
+
+    /// Palette size for a quality of 0-100 (2 to 256 colors), median-cut with
+    /// Floyd-Steinberg dithering. The other fields can be changed afterwards:
+    /// `Quantize { dither: Dither::None, ..Quantize::with_quality(80) }`
+    pub fn with_quality(quality: u8) -> Self {
+        let q = quality.min(100) as u32;
+        Quantize {
+            colors: (2 + 254 * q * q / 10_000) as u16,
+            ..Default::default()
+        }
+    }
+
+    /// Quantize the colors (`0xRRGGBBAA`) of `pixels`.
+    ///
+    /// # Returns
+    /// The palette index of every pixel and the palette
+    pub(crate) fn apply(self, pixels: &[WidePixel]) -> Result<(Vec<u8>, Palette), NPNGError> {
+        if !(2..=256).contains(&self.colors) {
+            return Err(NPNGError::Error("Palette size must be 2-256".to_string()));
+        }
+        let mut histogram: HashMap<u32, u32> = HashMap::new();
+        for p in pixels {
+            *histogram.entry(p.color).or_default() += 1;
+        }
+        let mut histogram: Vec<(u32, u32)> = histogram.into_iter().collect();
+        histogram.sort_unstable();
+
+        let colors = if histogram.len() <= self.colors as usize {
+            histogram.iter().map(|&(c, _)| c).collect()
+        } else {
+            match self.method {
+                QuantizeMethod::MedianCut => median_cut(&histogram, self.colors as usize),
+                QuantizeMethod::Octree => octree(&histogram, self.colors as usize),
+                QuantizeMethod::KMeans => {
+                    k_means(&histogram, median_cut(&histogram, self.colors as usize))
+                }
+            }
+        };
+        let palette: Vec<Rgba> = colors.iter().map(|&c| channels(c)).collect();
+
+        let indices = match self.dither {
+            Dither::None => {
+                let mut cache: HashMap<u32, u8> = HashMap::new();
+                pixels
+                    .iter()
+                    .map(|p| {
+                        *cache
+                            .entry(p.color)
+                            .or_insert_with(|| nearest(&palette, channels(p.color)))
+                    })
+                    .collect()
+            }
+            Dither::Ordered => {
+                let spread = 255.0 / (palette.len() as f64).cbrt();
+                pixels
+                    .iter()
+                    .map(|p| {
+                        let offset =
+                            (BAYER[p.y as usize % 4][p.x as usize % 4] as f64 + 0.5) / 16.0 - 0.5;
+                        let mut c = channels(p.color);
+                        for v in &mut c[..3] {
+                            *v = (*v + offset * spread).clamp(0.0, 255.0);
+                        }
+                        nearest(&palette, c)
+                    })
+                    .collect()
+            }
+            Dither::FloydSteinberg => floyd_steinberg(pixels, &palette),
+        };
+
+        let error: f64 = pixels
+            .iter()
+            .zip(&indices)
+            .map(|(p, &i)| distance(channels(p.color), palette[i as usize]))
+            .sum();
+        let mse = error / (pixels.len().max(1) * 4) as f64;
+        Ok((indices, Palette { colors, mse }))
+    }
+}
+
+/// Index of the closest palette color
+fn nearest(palette: &[Rgba], color: Rgba) -> u8 {
+    palette
+        .iter()
+        .enumerate()
+        .map(|(i, &p)| (i, distance(p, color)))
+        .fold(
+            (0, f64::INFINITY),
+            |best, d| if d.1 < best.1 { d } else { best },
+        )
+        .0 as u8
+}
+
+fn median_cut(histogram: &[(u32, u32)], colors: usize) -> Vec<u32> {
+    let range = |b: &[(u32, u32)], channel: usize| {
+        let values = b.iter().map(|&(c, _)| c.to_be_bytes()[channel]);
+        values.clone().max().unwrap_or(0) - values.min().unwrap_or(0)
+    };
+    let mut boxes = vec![histogram.to_vec()];
+    while boxes.len() < colors {
+        // box and channel with the widest range
+        let Some((i, channel, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.len() > 1)
+            .flat_map(|(i, b)| (0..4).map(move |c| (i, c, range(b, c))))
+            .max_by_key(|&(i, c, r)| (r, std::cmp::Reverse((i, c))))
+        else {
+            break;
+        };
+        let mut b = boxes.swap_remove(i);
+        b.sort_unstable_by_key(|&(c, _)| (c.to_be_bytes()[channel], c));
+        let total: u64 = b.iter().map(|&(_, n)| n as u64).sum();
+        let mut acc = 0;
+        let split = b
+            .iter()
+            .position(|&(_, n)| {
+                acc += n as u64;
+                acc * 2 >= total
+            })
+            .unwrap_or(0)
+            .clamp(0, b.len() - 2)
+            + 1;
+        let upper = b.split_off(split);
+        boxes.push(b);
+        boxes.push(upper);
+    }
+    boxes.iter().map(|b| mean(b)).collect()
+}
+
+/// Mask of the top `level` bits of every channel
+fn level_mask(level: u32) -> u32 {
+    let channel = (0xFF00u32 >> level) & 0xFF;
+    u32::from_be_bytes([channel as u8; 4])
+}
+
+fn octree(histogram: &[(u32, u32)], colors: usize) -> Vec<u32> {
+    // node key -> (RGBA sums, count)
+    let mut leaves: BTreeMap<u32, ([f64; 4], u64)> = histogram
+        .iter()
+        .map(|&(c, n)| (c, (channels(c).map(|v| v * n as f64), n as u64)))
+        .collect();
+    let mut level = 8;
+    while leaves.len() > colors && level > 0 {
+        let mask = level_mask(level - 1);
+        let mut parents: BTreeMap<u32, (u64, Vec<u32>)> = BTreeMap::new();
+        for (&key, &(_, n)) in &leaves {
+            let parent = parents.entry(key & mask).or_default();
+            parent.0 += n;
+            parent.1.push(key);
+        }
+        // least used nodes are merged first
+        let mut parents: Vec<(u32, (u64, Vec<u32>))> = parents.into_iter().collect();
+        parents.sort_by_key(|(key, (n, _))| (*n, *key));
+        for (key, (_, children)) in parents {
+            if leaves.len() <= colors {
+                break;
+            }
+            let mut merged = ([0.0; 4], 0);
+            for child in children {
+                let (sum, n) = leaves.remove(&child).unwrap();
+                for (m, s) in merged.0.iter_mut().zip(sum) {
+                    *m += s;
+                }
+                merged.1 += n;
+            }
+            leaves.insert(key, merged);
+        }
+        level -= 1;
+    }
+    leaves
+        .values()
+        .map(|(sum, n)| to_color(sum.map(|s| s / (*n).max(1) as f64)))
+        .collect()
+}
+
+fn k_means(histogram: &[(u32, u32)], initial: Vec<u32>) -> Vec<u32> {
+    let mut centroids: Vec<Rgba> = initial.iter().map(|&c| channels(c)).collect();
+    for _ in 0..16 {
+        let mut sums = vec![([0.0; 4], 0.0); centroids.len()];
+        for &(color, count) in histogram {
+            let c = channels(color);
+            let (sum, n) = &mut sums[nearest(&centroids, c) as usize];
+            for (s, v) in sum.iter_mut().zip(c) {
+                *s += v * count as f64;
+            }
+            *n += count as f64;
+        }
+        let next: Vec<Rgba> = sums
+            .iter()
+            .zip(&centroids)
+            .map(|((sum, n), &old)| if *n > 0.0 { sum.map(|s| s / n) } else { old })
+            .collect();
+        let moved = next
+            .iter()
+            .zip(&centroids)
+            .any(|(a, b)| distance(*a, *b) > 0.25);
+        centroids = next;
+        if !moved {
+            break;
+        }
+    }
+    centroids.into_iter().map(to_color).collect()
+}
+
+/// Error diffusion in row-major order (neighbors that are not written are skipped)
+fn floyd_steinberg(pixels: &[WidePixel], palette: &[Rgba]) -> Vec<u8> {
+    let mut order: Vec<usize> = (0..pixels.len()).collect();
+    order.sort_unstable_by_key(|&i| (pixels[i].y, pixels[i].x));
+    let position: HashMap<(u32, u32), usize> = pixels
+        .iter()
+        .enumerate()
+        .map(|(i, p)| ((p.x, p.y), i))
+        .collect();
+
+    let mut errors = vec![[0.0f64; 4]; pixels.len()];
+    let mut indices = vec![0u8; pixels.len()];
+    for i in order {
+        let p = &pixels[i];
+        let mut target = channels(p.color);
+        for (t, e) in target.iter_mut().zip(errors[i]) {
+            *t = (*t + e).clamp(0.0, 255.0);
+        }
+        let index = nearest(palette, target);
+        indices[i] = index;
+
+        let error: Rgba = std::array::from_fn(|c| target[c] - palette[index as usize][c]);
+        let neighbors = [
+            (p.x.checked_add(1), Some(p.y), 7.0),
+            (p.x.checked_sub(1), p.y.checked_add(1), 3.0),
+            (Some(p.x), p.y.checked_add(1), 5.0),
+            (p.x.checked_add(1), p.y.checked_add(1), 1.0),
+        ];
+        for (x, y, weight) in neighbors {
+            if let (Some(x), Some(y)) = (x, y)
+                && let Some(&j) = position.get(&(x, y))
+            {
+                for (e, d) in errors[j].iter_mut().zip(error) {
+                    *e += d * weight / 16.0;
+                }
+            }
+        }
+    }
+    indices
+}
@@ -0,0 +1,77 @@
+use crate::error::NPNGError;
+use crate::rans::{rans_compress, rans_decompress};
+use crate::types::color::AlphaMode;
+use crate::types::mipmap::{MipFilter, premultiplied, resize, to_rgba};
+use crate::types::pixel::WidePixel;
+use bincode::{Decode, Encode};
+use image::RgbaImage;
+
+/// Largest side of a thumbnail (`Config.thumbnail`)
+pub const MAX_THUMBNAIL_SIZE: u16 = 256;
+
+/// Stored thumbnail: size and rANS-coded RGBA rows (straight alpha)
+#[derive(Debug, Clone, Encode, Decode)]
+pub(crate) struct ThumbnailRecord {
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Thumbnail of a `width` x `height` canvas that fits into `size` x `size`
+/// (area average, the aspect ratio is kept and the image is never enlarged).
+/// `pixels` have their decoded colors (see `RecordFormat::decoded_color`)
+pub(crate) fn encode_thumbnail(
+    pixels: &[WidePixel],
+    (width, height): (u32, u32),
+    alpha_mode: AlphaMode,
+    size: u16,
+) -> Result<ThumbnailRecord, NPNGError> {
+    if size == 0 || size > MAX_THUMBNAIL_SIZE {
+        return Err(NPNGError::Error(format!(
+            "Thumbnail size must be 1-{}",
+            MAX_THUMBNAIL_SIZE
+        )));
+    }
+    if width == 0 || height == 0 {
+        return Ok(ThumbnailRecord {
+            width: 0,
+            height: 0,
+            data: rans_compress(&[]),
+        });
+    }
+    let scale = (size as f64 / width.max(height) as f64).min(1.0);
+    let to = (
+        ((width as f64 * scale).round() as u32).max(1),
+        ((height as f64 * scale).round() as u32).max(1),
+    );
+    let mut rgba = vec![0; to.0 as usize * to.1 as usize * 4];
+    for (x, y, c) in resize(
+        &premultiplied(pixels, alpha_mode),
+        (width, height),
+        to,
+        MipFilter::Box,
+    ) {
+        let i = (y as usize * to.0 as usize + x as usize) * 4;
+        rgba[i..i + 4].copy_from_slice(&to_rgba(c, AlphaMode::Straight));
+    }
+
+    Ok(ThumbnailRecord {
+        width: to.0,
+        height: to.1,
+        data: rans_compress(&rgba),
+    })
+}
+
+/// Image buffer of a stored thumbnail
+pub(crate) fn decode_thumbnail(record: ThumbnailRecord) -> Result<RgbaImage, NPNGError> {
+    if record.width > MAX_THUMBNAIL_SIZE as u32 || record.height > MAX_THUMBNAIL_SIZE as u32 {
+        return Err(NPNGError::Error("Thumbnail is too large".to_string()));
+    }
+    let len = record.width as usize * record.height as usize * 4;
+    let rgba = rans_decompress(&record.data, len)?;
+    if rgba.len() != len {
+        return Err(NPNGError::Error("Thumbnail size mismatch".to_string()));
+    }
+    RgbaImage::from_raw(record.width, record.height, rgba)
+        .ok_or_else(|| NPNGError::Error("Thumbnail size mismatch".to_string()))
+}
@@ -0,0 +1,284 @@
+use bincode::{
+    Decode, Encode,
+    de::Decoder,
+    error::{AllowedEnumVariants, DecodeError},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Deepest nesting of `List`/`Map` values accepted when decoding
+pub const MAX_VALUE_DEPTH: usize = 64;
+
+/// Typed metadata value
+#[derive(Debug, Clone, PartialEq, Encode)]
+pub enum MetadataValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    Bytes(Vec<u8>),
+    String(String),
+    List(Vec<MetadataValue>),
+    Map(BTreeMap<String, MetadataValue>),
+    /// Milliseconds since the Unix epoch
+    Timestamp(i64),
+}
+
+impl<Context> Decode<Context> for MetadataValue {
+    fn decode<D: Decoder<Context = Context>>(decoder: &mut D) -> Result<Self, DecodeError> {
+        decode_value(decoder, 0)
+    }
+}
+
+bincode::impl_borrow_decode!(MetadataValue);
+
+/// Same layout as a derived `Decode`, with the nesting of lists and maps limited
+/// to [`MAX_VALUE_DEPTH`]
+fn decode_value<D: Decoder>(decoder: &mut D, depth: usize) -> Result<MetadataValue, DecodeError> {
+    let variant = u32::decode(decoder)?;
+    Ok(match variant {
+        0 => MetadataValue::Int(Decode::decode(decoder)?),
+        1 => MetadataValue::Float(Decode::decode(decoder)?),
+        2 => MetadataValue::Bool(Decode::decode(decoder)?),
+        3 => MetadataValue::Bytes(Decode::decode(decoder)?),
+        4 => MetadataValue::String(Decode::decode(decoder)?),
+        5 | 6 => {
+            if depth >= MAX_VALUE_DEPTH {
+                return Err(DecodeError::Other("Metadata value is nested too deeply"));
+            }
+            let len = u64::decode(decoder)?;
+            let len = usize::try_from(len).map_err(|_| DecodeError::OutsideUsizeRange(len))?;
+            if variant == 5 {
+                decoder.claim_container_read::<MetadataValue>(len)?;
+                let mut list = Vec::with_capacity(len);
+                for _ in 0..len {
+                    decoder.unclaim_bytes_read(std::mem::size_of::<MetadataValue>());
+                    list.push(decode_value(decoder, depth + 1)?);
+                }
+                MetadataValue::List(list)
+            } else {
+                decoder.claim_container_read::<(String, MetadataValue)>(len)?;
+                let mut map = BTreeMap::new();
+                for _ in 0..len {
+                    decoder.unclaim_bytes_read(std::mem::size_of::<(String, MetadataValue)>());
+                    let key = String::decode(decoder)?;
+                    map.insert(key, decode_value(decoder, depth + 1)?);
+                }
+                MetadataValue::Map(map)
+            }
+        }
+        7 => MetadataValue::Timestamp(Decode::decode(decoder)?),
+        found => {
+            return Err(DecodeError::UnexpectedVariant {
+                type_name: "MetadataValue",
+                allowed: &AllowedEnumVariants::Range { min: 0, max: 7 },
+                found,
+            });
+        }
+    })
+}
+
+impl MetadataValue {
+    /// Map a string value (`Metadata.extra`) to a typed value.
+    ///
+    /// Integers, floats and booleans (`true`/`false`) are recognized,
+    /// anything else stays a `String`.
+    pub fn parse(s: &str) -> Self {
+        let t = s.trim();
+        if let Ok(i) = t.parse::<i64>() {
+            return MetadataValue::Int(i);
+        }
+        if let Ok(f) = t.parse::<f64>()
+            && f.is_finite()
+        {
+            return MetadataValue::Float(f);
+        }
+        match t {
+            "true" => MetadataValue::Bool(true),
+            "false" => MetadataValue::Bool(false),
+            _ => MetadataValue::String(s.to_string()),
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            MetadataValue::Int(i) => Some(*i),
+            MetadataValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            MetadataValue::Float(f) => Some(*f),
+            MetadataValue::Int(i) => Some(*i as f64),
+            MetadataValue::String(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            MetadataValue::Bool(b) => Some(*b),
+            MetadataValue::String(s) => match s.trim() {
+                "true" | "1" => Some(true),
+                "false" | "0" => Some(false),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            MetadataValue::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            MetadataValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&[MetadataValue]> {
+        match self {
+            MetadataValue::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&BTreeMap<String, MetadataValue>> {
+        match self {
+            MetadataValue::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    /// Timestamp (milliseconds since the Unix epoch) as `SystemTime`.
+    /// Integer strings are accepted for string-only metadata.
+    pub fn as_timestamp(&self) -> Option<SystemTime> {
+        let ms = match self {
+            MetadataValue::Timestamp(ms) => *ms,
+            MetadataValue::String(s) => s.trim().parse().ok()?,
+            _ => return None,
+        };
+        if ms >= 0 {
+            UNIX_EPOCH.checked_add(Duration::from_millis(ms as u64))
+        } else {
+            UNIX_EPOCH.checked_sub(Duration::from_millis(ms.unsigned_abs()))
+        }
+    }
+}
+
+impl Display for MetadataValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MetadataValue::Int(i) => write!(f, "{}", i),
+            MetadataValue::Float(v) => write!(f, "{}", v),
+            MetadataValue::Bool(b) => write!(f, "{}", b),
+            MetadataValue::Bytes(b) => {
+                for byte in b {
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+            MetadataValue::String(s) => f.write_str(s),
+            MetadataValue::List(l) => {
+                f.write_str("[")?;
+                for (i, v) in l.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", v)?;
+                }
+                f.write_str("]")
+            }
+            MetadataValue::Map(m) => {
+                f.write_str("{")?;
+                for (i, (k, v)) in m.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}: {}", k, v)?;
+                }
+                f.write_str("}")
+            }
+            MetadataValue::Timestamp(ms) => write!(f, "{}", ms),
+        }
+    }
+}
+
+impl From<i64> for MetadataValue {
+    fn from(v: i64) -> Self {
+        MetadataValue::Int(v)
+    }
+}
+
+impl From<i32> for MetadataValue {
+    fn from(v: i32) -> Self {
+        MetadataValue::Int(v as i64)
+    }
+}
+
+impl From<u32> for MetadataValue {
+    fn from(v: u32) -> Self {
+        MetadataValue::Int(v as i64)
+    }
+}
+
+impl From<f64> for MetadataValue {
+    fn from(v: f64) -> Self {
+        MetadataValue::Float(v)
+    }
+}
+
+impl From<bool> for MetadataValue {
+    fn from(v: bool) -> Self {
+        MetadataValue::Bool(v)
+    }
+}
+
+impl From<Vec<u8>> for MetadataValue {
+    fn from(v: Vec<u8>) -> Self {
+        MetadataValue::Bytes(v)
+    }
+}
+
+impl From<String> for MetadataValue {
+    fn from(v: String) -> Self {
+        MetadataValue::String(v)
+    }
+}
+
+impl From<&str> for MetadataValue {
+    fn from(v: &str) -> Self {
+        MetadataValue::String(v.to_string())
+    }
+}
+
+impl From<Vec<MetadataValue>> for MetadataValue {
+    fn from(v: Vec<MetadataValue>) -> Self {
+        MetadataValue::List(v)
+    }
+}
+
+impl From<BTreeMap<String, MetadataValue>> for MetadataValue {
+    fn from(v: BTreeMap<String, MetadataValue>) -> Self {
+        MetadataValue::Map(v)
+    }
+}
+
+impl From<SystemTime> for MetadataValue {
+    fn from(v: SystemTime) -> Self {
+        let ms = match v.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_millis() as i64,
+            Err(e) => -(e.duration().as_millis() as i64),
+        };
+        MetadataValue::Timestamp(ms)
+    }
+}
@@ -1,10 +1,13 @@
+use crate::coding::RecordFormat;
+use crate::error::NPNGError;
+use crate::types::MAX_PIXELS;
+use crate::types::pixel::{CoordWidth, RGBPixel, RGBWidePixel, WidePixel};
+use crate::{Pixel, PixelFormat};
 use bincode::{
     Decode, Encode,
     config::{legacy, standard as std_config},
 };
-use crate::Pixel;
-use crate::error::NPNGError;
-use crate::types::pixel::RGBPixel;
+use rayon::slice::ParallelSliceMut;
 
 /// Serialize a value into a byte vector. (bincode wrapper)
 ///
@@ -40,16 +43,75 @@ pub(crate) fn deserialize<O: Decode<()>>(data: Vec<u8>, standard: bool) -> Resul
     Ok(bincode::decode_from_slice(data.as_slice(), legacy())?.0)
 }
 
+/// Morton (Z-order) code of (x, y): bits of `x` at even positions, bits of `y` at odd positions
+pub(crate) fn morton(x: u32, y: u32) -> u64 {
+    fn spread(v: u32) -> u64 {
+        let mut v = v as u64;
+        v = (v | (v << 16)) & 0x0000_FFFF_0000_FFFF;
+        v = (v | (v << 8)) & 0x00FF_00FF_00FF_00FF;
+        v = (v | (v << 4)) & 0x0F0F_0F0F_0F0F_0F0F;
+        v = (v | (v << 2)) & 0x3333_3333_3333_3333;
+        v = (v | (v << 1)) & 0x5555_5555_5555_5555;
+        v
+    }
+    spread(x) | (spread(y) << 1)
+}
+
+/// Distance of (x, y) along the Hilbert curve covering the 2^32 x 2^32 coordinate space
+pub(crate) fn hilbert(x: u32, y: u32) -> u64 {
+    let (mut x, mut y) = (x as u64, y as u64);
+    let mut d = 0u64;
+    let mut s = 1u64 << 31;
+    while s > 0 {
+        let rx = (x & s != 0) as u64;
+        let ry = (y & s != 0) as u64;
+        d += s * s * ((3 * rx) ^ ry);
+        // rotate the quadrant
+        if ry == 0 {
+            if rx == 1 {
+                x = s * 2 - 1 - x;
+                y = s * 2 - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        x &= s - 1;
+        y &= s - 1;
+        s >>= 1;
+    }
+    d
+}
+
+/// Deserialize a value from the start of a slice.
+///
+/// # Returns
+/// The value and the number of bytes read
+pub(crate) fn deserialize_prefix<O: Decode<()>>(
+    data: &[u8],
+    standard: bool,
+) -> Result<(O, usize), NPNGError> {
+    if standard {
+        return Ok(bincode::decode_from_slice(data, std_config())?);
+    }
+    Ok(bincode::decode_from_slice(data, legacy())?)
+}
+
 /// Encodes a Pixel into a byte vector.
 ///
 /// This function can encode either a full `Pixel` with alpha channel
 /// or an `RGBPixel` without alpha, depending on the `save_alpha` flag.
-/// Fully transparent pixels (alpha = 0x00) are skipped and return an empty vector.
+/// Fully transparent pixels (alpha = 0x00) are handled by `alpha_policy`:
+/// skipped (an empty vector), stored with a zero color, or stored as is.
 ///
 /// # Parameters
-/// - `d`: The `Pixel` to encode.
-/// - `save_alpha`: If `true`, encode the full `Pixel` including alpha.
-///                 If `false`, encode only the RGB channels.
+/// - `d`: The `WidePixel` to encode.
+/// - `format`: Record format:
+///     - `save_alpha`: If `true`, encode the full `Pixel` including alpha.
+///       If `false`, encode only the RGB channels.
+///     - `coords`: Coordinate width of the record. With [`CoordWidth::U16`] the
+///       coordinates must fit into `u16` (checked by the caller).
+///     - `alpha_policy`: What to do with fully transparent pixels.
+///     - `pixel_format`: [`PixelFormat::BinaryAlpha`] writes RGB records and
+///       [`PixelFormat::Shape`] only the coordinates, for pixels with alpha >= 0x80.
 ///
 /// # Returns
 /// - `Ok(Vec<u8>)`: The serialized pixel data.
@@ -57,61 +119,153 @@ pub(crate) fn deserialize<O: Decode<()>>(data: Vec<u8>, standard: bool) -> Resul
 ///
 /// # Example
 /// ```rust
-/// let encoded = encode_pixel(pixel, true)?;
+/// let encoded = encode_pixel(pixel, format)?;
 /// ```
-pub(crate) fn encode_pixel(
-    mut d: Pixel,
-    save_alpha: bool,
-    varint: bool,
-) -> Result<Vec<u8>, NPNGError> {
-    let color = d.color;
-
-    // Fully transparent pixel - nothing to save
-    if (color & 0xFF) == 0x00 {
+pub(crate) fn encode_pixel(d: WidePixel, format: RecordFormat) -> Result<Vec<u8>, NPNGError> {
+    let RecordFormat {
+        save_alpha,
+        varint,
+        coords,
+        pixel_format,
+        ..
+    } = format;
+    let Some(color) = format.stored_color(d.color) else {
+        // Dropped by the alpha policy or below the 1-bit alpha threshold
         return Ok(Vec::new());
+    };
+
+    if format.indexed {
+        // palette index in the red channel
+        let index = (color >> 24) as u8;
+        let s = match coords {
+            CoordWidth::U8 | CoordWidth::U16 => serialize((d.x as u16, d.y as u16, index), varint)?,
+            CoordWidth::U32 => serialize((d.x, d.y, index), varint)?,
+        };
+        return Ok(s);
     }
 
-    if !save_alpha {
-        // Encode as RGBPixel (without alpha)
-        let rgb_pixel = RGBPixel {
-            x: d.x,
-            y: d.y,
-            color: [
-                ((color >> 24) & 0xFF) as u8, // Red
-                ((color >> 16) & 0xFF) as u8, // Green
-                ((color >> 8) & 0xFF) as u8,  // Blue
-            ],
+    if pixel_format == PixelFormat::Shape {
+        let s = match coords {
+            CoordWidth::U8 | CoordWidth::U16 => serialize((d.x as u16, d.y as u16), varint)?,
+            CoordWidth::U32 => serialize((d.x, d.y), varint)?,
         };
+        return Ok(s);
+    }
+
+    if !save_alpha || pixel_format == PixelFormat::BinaryAlpha {
+        // Encode as RGBPixel (without alpha)
+        let rgb = [
+            ((color >> 24) & 0xFF) as u8, // Red
+            ((color >> 16) & 0xFF) as u8, // Green
+            ((color >> 8) & 0xFF) as u8,  // Blue
+        ];
 
         // Serialize RGBPixel and return
-        let s = serialize(rgb_pixel, varint)?;
+        let s = match coords {
+            CoordWidth::U8 | CoordWidth::U16 => serialize(
+                RGBPixel {
+                    x: d.x as u16,
+                    y: d.y as u16,
+                    color: rgb,
+                },
+                varint,
+            )?,
+            CoordWidth::U32 => serialize(
+                RGBWidePixel {
+                    x: d.x,
+                    y: d.y,
+                    color: rgb,
+                },
+                varint,
+            )?,
+        };
         return Ok(s);
     }
 
     // encode full Pixel with alpha
-    d.color = color;
-    let s = serialize(d, varint)?;
+    let s = match coords {
+        CoordWidth::U8 | CoordWidth::U16 => {
+            serialize(Pixel::new(d.x as u16, d.y as u16, color), varint)?
+        }
+        CoordWidth::U32 => serialize(WidePixel::new(d.x, d.y, color), varint)?,
+    };
     Ok(s)
 }
 
 /// Get npng image size
 /// # Returns
 /// tuple with `(width, height)`
-pub(crate) fn check_image_size_f(pixels: Vec<Pixel>) -> (u16, u16) {
-    let width = pixels.iter().map(|p| p.x).max().unwrap_or(0) + 1;
-    let height = pixels.iter().map(|p| p.y).max().unwrap_or(0) + 1;
-    (width, height)
+pub(crate) fn check_image_size_f(pixels: &[WidePixel]) -> Result<(u32, u32), NPNGError> {
+    let max_x = pixels.iter().map(|p| p.x).max().unwrap_or(0);
+    let max_y = pixels.iter().map(|p| p.y).max().unwrap_or(0);
+    match (max_x.checked_add(1), max_y.checked_add(1)) {
+        (Some(width), Some(height)) => Ok((width, height)),
+        _ => Err(NPNGError::Error(
+            "Canvas size does not fit into 32 bits".to_string(),
+        )),
+    }
 }
 
+/// Check that every pixel lies inside a `width` x `height` canvas
+pub(crate) fn check_canvas_bounds(
+    pixels: &[WidePixel],
+    width: u32,
+    height: u32,
+) -> Result<(), NPNGError> {
+    match pixels.iter().find(|p| p.x >= width || p.y >= height) {
+        Some(p) => Err(NPNGError::PixelOutOfBounds(p.x, p.y, width, height)),
+        None => Ok(()),
+    }
+}
+
+/// Check that no two pixels share coordinates.
+///
+/// Small (or densely filled) canvases are checked with a bitmap, large sparse ones
+/// by sorting the coordinates, so memory use follows the image instead of the
+/// coordinate range.
+pub(crate) fn check_duplicates(
+    pixels: &[WidePixel],
+    width: u32,
+    height: u32,
+) -> Result<(), NPNGError> {
+    let area = width as u64 * height as u64;
+    let bitmap_limit = (1u64 << 27).max(pixels.len() as u64 * 64); // 16 MB or 8 bytes per pixel
+
+    if area <= bitmap_limit && area <= MAX_PIXELS as u64 {
+        let mut bitmap = vec![0u8; area.div_ceil(8) as usize];
+
+        for p in pixels {
+            let idx = (p.y as usize) * (width as usize) + (p.x as usize);
+            let byte = idx / 8;
+            let bit = idx % 8;
+            let mask = 1 << bit;
+            if bitmap[byte] & mask != 0 {
+                return Err(NPNGError::DuplicatePixel(p.x, p.y));
+            }
+            bitmap[byte] |= mask;
+        }
+        return Ok(());
+    }
+
+    let mut keys: Vec<u64> = pixels
+        .iter()
+        .map(|p| ((p.y as u64) << 32) | p.x as u64)
+        .collect();
+    keys.par_sort_unstable();
+    match keys.windows(2).find(|w| w[0] == w[1]) {
+        Some(w) => Err(NPNGError::DuplicatePixel(w[0] as u32, (w[0] >> 32) as u32)),
+        None => Ok(()),
+    }
+}
 
 pub(crate) fn set_byte<T>(mut a: T, n: u8, value: u8) -> T
 where
     T: Copy
-    + std::ops::BitOr<Output = T>
-    + std::ops::BitAnd<Output = T>
-    + std::ops::Not<Output = T>
-    + std::ops::Shl<u8, Output = T>
-    + From<u8>,
+        + std::ops::BitOr<Output = T>
+        + std::ops::BitAnd<Output = T>
+        + std::ops::Not<Output = T>
+        + std::ops::Shl<u8, Output = T>
+        + From<u8>,
 {
     a = a & !(T::from(0xFF) << (n * 8));
     a = a | (T::from(value) << (n * 8));
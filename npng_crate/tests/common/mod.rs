@@ -0,0 +1,29 @@
+use std::{fs, path::Path, sync::Once};
+
+use image::{Rgba, RgbaImage};
+
+static FIXTURE: Once = Once::new();
+
+/// Write the test image `in.png`: a gradient disc (left part opaque, right part
+/// half transparent) on a fully transparent 96x64 canvas
+pub fn require_in_png() {
+    FIXTURE.call_once(|| {
+        let img = RgbaImage::from_fn(96, 64, |x, y| {
+            if (x as i32 - 48).pow(2) + (y as i32 - 32).pow(2) > 30 * 30 {
+                Rgba([0, 0, 0, 0])
+            } else {
+                let alpha = if x < 60 { 255 } else { 128 };
+                Rgba([
+                    (x * 5 % 256) as u8,
+                    (y * 4 % 256) as u8,
+                    (x * y % 256) as u8,
+                    alpha,
+                ])
+            }
+        });
+        // written next to the final name first, tests may read it concurrently
+        img.save("in.tmp.png").expect("cannot write the test image");
+        fs::rename("in.tmp.png", "in.png").expect("cannot write the test image");
+    });
+    assert!(Path::new("in.png").exists(), "in.png not found");
+}
@@ -4,32 +4,37 @@ use image::ImageReader;
 
 extern crate npng_crate;
 
-use npng_crate::{compression::CompressMap, types::{metadata::Metadata}, *};
 use npng_crate::error::NPNGError;
+use npng_crate::{
+    compression::CompressMap,
+    types::metadata::{Metadata, TextEntry},
+    *,
+};
 
-fn require_in_png() {
-    let p = Path::new("in.png");
-    println!("in.png!");
-    assert!(p.exists(), "in.png not found");
-}
+mod common;
+use common::require_in_png;
 
 fn get_test_configs() -> Vec<Config> {
     vec![
         Config {
             save_alpha: true,
             varint: true,
+            ..Config::default()
         },
         Config {
             save_alpha: true,
             varint: false,
+            ..Config::default()
         },
         Config {
             save_alpha: false,
             varint: true,
+            ..Config::default()
         },
         Config {
             save_alpha: false,
             varint: false,
+            ..Config::default()
         },
     ]
 }
@@ -40,7 +45,7 @@ fn test_encode_image_to_npng_image_with_configs() {
     let metadata = Metadata::new("TEST", HashMap::<String, String>::new());
     let out_path = "out.npng";
 
-    let compress_maps = vec![
+    let compress_maps = [
         CompressMap::plain(),
         CompressMap::zlib(6),
         CompressMap::zstd(6),
@@ -90,7 +95,7 @@ fn test_encode_bytes_and_decode_bytes_roundtrip_with_configs() {
 
     let metadata = Metadata::new("TEST", HashMap::<String, String>::new());
 
-    let compress_maps = vec![
+    let compress_maps = [
         CompressMap::plain(),
         CompressMap::zlib(3),
         CompressMap::zstd(1),
@@ -123,7 +128,7 @@ fn test_encode_bytes_and_decode_bytes_roundtrip_with_configs() {
             }
 
             println!("    -> Decoding bytes to image...");
-            let (version, decoded_meta) =
+            let (_version, _decoded_meta) =
                 decode_bytes_to_image(&bytes, out_decoded, false, cmap.clone())
                     .expect("decode_bytes_to_image failed");
 
@@ -152,3 +157,1778 @@ fn test_coordinates_duplicates() {
         r.err().unwrap().to_string()
     );
 }
+
+#[test]
+fn test_color_space_roundtrip_and_conversion() {
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.color_space = ColorSpace::LinearSrgb;
+    let pixels = vec![Pixel::new(0, 0, 0x373737FF), Pixel::new(1, 0, 0xFF000080)];
+
+    let bytes =
+        encode_pixel_vec_with_metadata(pixels, metadata, Config::default(), Encoding::Plain)
+            .expect("encode failed");
+
+    let img =
+        decode_bytes_to_pixel_vec(&bytes, true, false, Encoding::Plain).expect("decode failed");
+    assert_eq!(img.metadata.color_space, ColorSpace::LinearSrgb);
+    assert_eq!(img.pixels[0].color, 0x373737FF);
+
+    let img = decode_bytes_to_pixel_vec_with_config(
+        &bytes,
+        true,
+        false,
+        Encoding::Plain,
+        DecodeConfig::new(Some(ColorSpace::Srgb)),
+    )
+    .expect("decode failed");
+    assert_eq!(img.metadata.color_space, ColorSpace::Srgb);
+    let gray = (img.pixels[0].color >> 24) as i32;
+    assert!(
+        (gray - 128).abs() <= 2,
+        "linear 0x37 should be ~sRGB 0x80, got {gray:#x}"
+    );
+    assert_eq!(img.pixels[1].color & 0xFF, 0x80, "alpha must be kept");
+}
+
+#[test]
+fn test_icc_profile_is_carried_through_png() {
+    let icc = ColorSpace::DisplayP3.icc_profile().unwrap().unwrap();
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.color_space = ColorSpace::DisplayP3;
+    let pixels = vec![Pixel::new(0, 0, 0xFF0000FF), Pixel::new(1, 1, 0x00FF00FF)];
+    let bytes =
+        encode_pixel_vec_with_metadata(pixels, metadata, Config::default(), Encoding::Plain)
+            .expect("encode failed");
+
+    let out = "icc_p3.png";
+    decode_bytes_to_image(&bytes, out, false, Encoding::Plain).expect("decode failed");
+    let reencoded = encode_image_to_npng_bytes(
+        out,
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+        Config::default(),
+        Encoding::Plain,
+    );
+    let _ = fs::remove_file(out);
+
+    let img = decode_bytes_to_pixel_vec(&reencoded.unwrap(), true, false, Encoding::Plain).unwrap();
+    assert_eq!(img.metadata.color_space, ColorSpace::Icc(icc));
+}
+
+#[test]
+fn test_exif_xmp_text_are_preserved_across_conversions() {
+    // minimal little-endian TIFF header with an empty IFD
+    let exif = vec![
+        0x49, 0x49, 0x2A, 0x00, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    let xmp = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"></x:xmpmeta>".to_vec();
+
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.exif = Some(exif.clone());
+    metadata.xmp = Some(xmp.clone());
+    metadata.text = vec![
+        TextEntry::new("Copyright", "(c) photographer"),
+        TextEntry::new("Description", "Снежинка"),
+    ];
+    let pixels = vec![Pixel::new(0, 0, 0xFF0000FF), Pixel::new(3, 2, 0x00FF00FF)];
+    let bytes = encode_pixel_vec_with_metadata(
+        pixels,
+        metadata.clone(),
+        Config::default(),
+        Encoding::Plain,
+    )
+    .expect("encode failed");
+
+    let img = decode_bytes_to_pixel_vec(&bytes, true, false, Encoding::Plain).unwrap();
+    assert_eq!(img.metadata.exif, metadata.exif);
+    assert_eq!(img.metadata.xmp, metadata.xmp);
+    assert_eq!(img.metadata.text, metadata.text);
+
+    for (out, with_text) in [("meta_roundtrip.png", true), ("meta_roundtrip.jpg", false)] {
+        decode_bytes_to_image(&bytes, out, false, Encoding::Plain).expect("export failed");
+        let reencoded = encode_image_to_npng_bytes(
+            out,
+            Metadata::new("TEST", HashMap::<String, String>::new()),
+            Config::default(),
+            Encoding::Plain,
+        );
+        let _ = fs::remove_file(out);
+
+        let img =
+            decode_bytes_to_pixel_vec(&reencoded.unwrap(), true, false, Encoding::Plain).unwrap();
+        assert_eq!(img.metadata.exif.as_ref(), Some(&exif), "{out}: EXIF lost");
+        assert_eq!(img.metadata.xmp.as_ref(), Some(&xmp), "{out}: XMP lost");
+        if with_text {
+            assert_eq!(img.metadata.text, metadata.text, "{out}: text lost");
+        }
+    }
+}
+
+#[test]
+fn test_typed_metadata_values() {
+    let mut metadata = Metadata::new(
+        "TEST",
+        HashMap::from([("legacy_count", "42"), ("legacy_flag", "true")]),
+    );
+    let when = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1_700_000_000_123);
+    metadata.set_value("exposure", 0.125);
+    metadata.set_value("iso", 400);
+    metadata.set_value("hdr", false);
+    metadata.set_value("thumb", vec![1u8, 2, 3]);
+    metadata.set_value("taken", when);
+    metadata.set_value(
+        "lens",
+        std::collections::BTreeMap::from([
+            ("model".to_string(), MetadataValue::from("50mm")),
+            (
+                "tags".to_string(),
+                MetadataValue::from(vec![MetadataValue::Int(1), MetadataValue::Bool(true)]),
+            ),
+        ]),
+    );
+
+    let bytes = encode_pixel_vec_with_metadata(
+        vec![Pixel::new(0, 0, 0xFFFFFFFF)],
+        metadata,
+        Config::default(),
+        Encoding::Plain,
+    )
+    .expect("encode failed");
+    let decoded = decode_bytes_to_pixel_vec(&bytes, true, false, Encoding::Plain)
+        .unwrap()
+        .metadata;
+
+    assert_eq!(decoded.get_float("exposure"), Some(0.125));
+    assert_eq!(decoded.get_int("iso"), Some(400));
+    assert_eq!(decoded.get_bool("hdr"), Some(false));
+    assert_eq!(decoded.get_bytes("thumb"), Some(&[1u8, 2, 3][..]));
+    assert_eq!(decoded.get_timestamp("taken"), Some(when));
+    assert_eq!(
+        decoded.get_map("lens").unwrap()["model"],
+        MetadataValue::from("50mm")
+    );
+
+    // string-only values are mapped
+    assert_eq!(decoded.get_int("legacy_count"), Some(42));
+    assert_eq!(decoded.get_bool("legacy_flag"), Some(true));
+    assert_eq!(decoded.get_string("legacy_count"), Some("42".to_string()));
+    assert_eq!(
+        decoded.typed_values()["legacy_count"],
+        MetadataValue::Int(42)
+    );
+
+    // nesting past MAX_VALUE_DEPTH is rejected instead of overflowing the stack
+    let nested =
+        |depth: usize| (0..depth).fold(MetadataValue::Int(0), |v, _| MetadataValue::List(vec![v]));
+    for (depth, ok) in [(MAX_VALUE_DEPTH, true), (MAX_VALUE_DEPTH + 1, false)] {
+        let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+        metadata.set_value("nested", nested(depth));
+        let bytes = encode_pixel_vec_with_metadata(
+            vec![Pixel::new(0, 0, 0xFFFFFFFF)],
+            metadata,
+            Config::default(),
+            Encoding::Plain,
+        )
+        .expect("encode failed");
+        let decoded = decode_bytes_to_pixel_vec(&bytes, true, false, Encoding::Plain);
+        assert_eq!(decoded.is_ok(), ok, "depth {depth}");
+    }
+}
+
+#[test]
+fn test_deterministic_encoding() {
+    let entries: Vec<(String, String)> = (0..600)
+        .map(|i| (format!("key{i:03}"), format!("v{i}")))
+        .collect();
+    let forward: HashMap<String, String> = entries.iter().cloned().collect();
+    let backward: HashMap<String, String> = entries.iter().rev().cloned().collect();
+
+    let pixels: Vec<Pixel> = (0..64u16)
+        .map(|i| Pixel::new(i % 8, i / 8, 0x102030FF + i as u32))
+        .collect();
+    let mut shuffled = pixels.clone();
+    shuffled.reverse();
+    shuffled.swap(3, 40);
+
+    let config = Config {
+        pixel_order: PixelOrder::RowMajor,
+        ..Config::default()
+    };
+    let a = encode_pixel_vec_with_metadata(
+        pixels,
+        Metadata::new("TEST", forward.clone()),
+        config.clone(),
+        CompressMap::zstd(3),
+    )
+    .expect("encode failed");
+    let b = encode_pixel_vec_with_metadata(
+        shuffled,
+        Metadata::new("TEST", backward),
+        config,
+        CompressMap::zstd(3),
+    )
+    .expect("encode failed");
+    assert_eq!(a, b, "equal inputs must produce equal files");
+
+    // truncation keeps the first 512 keys in order
+    let img = decode_bytes_to_pixel_vec(&a, true, false, CompressMap::zstd(3)).unwrap();
+    assert_eq!(img.metadata.extra.len(), 512);
+    assert!(img.metadata.extra.contains_key("key511"));
+    assert!(!img.metadata.extra.contains_key("key512"));
+
+    let reject = Config {
+        metadata_overflow: OverflowPolicy::Reject,
+        ..Config::default()
+    };
+    let r = encode_pixel_vec_with_metadata(
+        vec![Pixel::new(0, 0, 0xFF)],
+        Metadata::new("TEST", forward),
+        reject,
+        Encoding::Plain,
+    );
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_declared_canvas_size() {
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.width = 512;
+    metadata.height = 512;
+    let pixels = vec![
+        Pixel::new(10, 10, 0xFF0000FF),
+        Pixel::new(499, 479, 0x00FF00FF),
+    ];
+
+    let bytes = encode_pixel_vec_with_metadata(
+        pixels.clone(),
+        metadata.clone(),
+        Config::default(),
+        Encoding::Plain,
+    )
+    .expect("encode failed");
+    let (buffer, meta) = decode_npng_bytes_to_image_buffer(&bytes, false, Encoding::Plain).unwrap();
+    assert_eq!((buffer.width(), buffer.height()), (512, 512));
+    assert_eq!((meta.width, meta.height), (512, 512));
+
+    // undeclared canvas is still derived from the pixels
+    let bytes = encode_pixel_vec_with_metadata(
+        pixels.clone(),
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+        Config::default(),
+        Encoding::Plain,
+    )
+    .unwrap();
+    let (buffer, _) = decode_npng_bytes_to_image_buffer(&bytes, false, Encoding::Plain).unwrap();
+    assert_eq!((buffer.width(), buffer.height()), (500, 480));
+
+    metadata.width = 400;
+    let r = encode_pixel_vec_with_metadata(pixels, metadata, Config::default(), Encoding::Plain);
+    assert!(matches!(
+        r,
+        Err(NPNGError::PixelOutOfBounds(499, 479, 400, 512))
+    ));
+}
+
+#[test]
+fn test_canvas_origin_and_tiles() {
+    let world: Vec<GlobalPixel> = (0..40i64)
+        .map(|i| GlobalPixel {
+            x: 100_000 + i * 3,
+            y: -5_000 + (i % 7),
+            color: 0x11223300 | (i as u32 + 1),
+        })
+        .collect();
+
+    // two tiles, stored and decoded separately
+    let mut restored = Vec::new();
+    for tile in world.chunks(20) {
+        let img = Img::from_global_pixels(
+            tile.to_vec(),
+            Metadata::new("TEST", HashMap::<String, String>::new()),
+        )
+        .expect("tile does not fit");
+        assert_eq!(img.metadata.origin, (tile[0].x as i32, -5_000));
+        let bytes = encode_img_to_npng_bytes(img, Config::default(), Encoding::Plain).unwrap();
+        let decoded = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+        restored.extend(decoded.global_pixels());
+    }
+    assert_eq!(restored, world);
+
+    let mut img = Img::from_global_pixels(
+        world.clone(),
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+    img.translate(-100_000, 5_000).unwrap();
+    assert_eq!(img.metadata.origin, (0, 0));
+    assert_eq!(img.global_pixels()[1].x, 3);
+
+    // the widest canvas with 16-bit coordinates, extreme positions don't overflow
+    let pixel = |x, y| GlobalPixel { x, y, color: 0xFF };
+    let metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    let img =
+        Img::from_global_pixels(vec![pixel(-10, 0), pixel(65_525, 1)], metadata.clone()).unwrap();
+    assert_eq!((img.metadata.width, img.metadata.height), (65_536, 2));
+    assert_eq!(img.pixels[1].x, u16::MAX);
+    assert!(
+        Img::from_global_pixels(vec![pixel(-10, 0), pixel(65_526, 0)], metadata.clone()).is_err()
+    );
+    assert!(
+        Img::from_global_pixels(vec![pixel(i64::MIN, 0), pixel(i64::MAX, 0)], metadata).is_err()
+    );
+
+    // normalize keeps global positions
+    let mut img = Img {
+        pixels: vec![Pixel::new(10, 20, 0xFF), Pixel::new(12, 25, 0xFF)],
+        encoder_version: version(),
+        metadata: Metadata::new("TEST", HashMap::<String, String>::new()),
+    };
+    img.metadata.width = 30;
+    img.metadata.height = 30;
+    let before = img.global_pixels();
+    img.normalize().unwrap();
+    assert_eq!(img.global_pixels(), before);
+    assert_eq!((img.pixels[0].x, img.pixels[0].y), (0, 0));
+    assert_eq!(img.metadata.origin, (10, 20));
+    assert_eq!((img.metadata.width, img.metadata.height), (20, 10));
+}
+
+#[test]
+fn test_wide_canvas() {
+    let pixels = vec![
+        WidePixel::new(0, 0, 0x112233FF),
+        WidePixel::new(70_000, 3, 0x445566FF),
+        WidePixel::new(5, 100_000, 0x778899FF),
+    ];
+    for config in get_test_configs() {
+        let bytes = encode_wide_pixel_vec_with_metadata(
+            pixels.clone(),
+            Metadata::new("TEST", HashMap::<String, String>::new()),
+            config.clone(),
+            Encoding::Zstd(3),
+        )
+        .unwrap();
+        let img = decode_bytes_to_wide_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!((img.metadata.width, img.metadata.height), (70_001, 100_001));
+        let mut decoded: Vec<_> = img.pixels.iter().map(|p| (p.x, p.y)).collect();
+        decoded.sort();
+        assert_eq!(decoded, vec![(0, 0), (5, 100_000), (70_000, 3)]);
+
+        // does not fit into `Pixel`
+        assert!(decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).is_err());
+    }
+
+    // compared without a buffer of the bounding box
+    let original = WideImg {
+        pixels: pixels.clone(),
+        encoder_version: version(),
+        metadata: Metadata::new("TEST", HashMap::<String, String>::new()),
+    };
+    let mut changed = original.clone();
+    changed.pixels[1].color = 0x405060FF;
+    assert_eq!(
+        compare_wide_images(&original, &original).unwrap().max_error,
+        0
+    );
+    let report = compare_wide_images(&original, &changed).unwrap();
+    assert_eq!(report.max_error, 6);
+    assert!(report.ssim < 1.0 && report.ssim > 0.99);
+
+    // small canvases keep 16-bit records
+    let small = vec![Pixel::new(1, 2, 0xFFFFFFFF), Pixel::new(3, 4, 0x000000FF)];
+    let meta = Metadata::new("TEST", HashMap::<String, String>::new());
+    let a = encode_pixel_vec_with_metadata(
+        small.clone(),
+        meta.clone(),
+        Config::default(),
+        Encoding::Plain,
+    )
+    .unwrap();
+    let b = encode_wide_pixel_vec_with_metadata(
+        small.into_iter().map(WidePixel::from).collect(),
+        meta,
+        Config::default(),
+        Encoding::Plain,
+    )
+    .unwrap();
+    assert_eq!(a, b);
+
+    let r = encode_wide_pixel_vec_with_metadata(
+        vec![
+            WidePixel::new(80_000, 1, 0xFF),
+            WidePixel::new(80_000, 1, 0xFF),
+        ],
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+        Config::default(),
+        Encoding::Plain,
+    );
+    assert!(matches!(r, Err(NPNGError::DuplicatePixel(80_000, 1))));
+}
+
+#[test]
+fn test_layered_document() {
+    let mut doc = NpngDocument::new(Metadata::new("TEST", HashMap::<String, String>::new()));
+    doc.metadata.width = 8;
+    doc.metadata.height = 8;
+    doc.add_layer(Layer::new(
+        "bg",
+        (0..8)
+            .flat_map(|y| (0..8).map(move |x| Pixel::new(x, y, 0x808080FF)))
+            .collect(),
+    ));
+
+    let mut multiply = Layer::new("multiply", vec![Pixel::new(0, 0, 0x800000FF)]);
+    multiply.blend_mode = BlendMode::Multiply;
+    doc.add_layer(multiply);
+
+    let mut screen = Layer::new("screen", vec![Pixel::new(0, 0, 0x808080FF)]);
+    screen.blend_mode = BlendMode::Screen;
+    screen.offset = (1, 0);
+    doc.add_layer(screen);
+
+    let mut half = Layer::new(
+        "half",
+        vec![Pixel::new(0, 0, 0xFFFFFFFF), Pixel::new(20, 20, 0xFFFFFFFF)],
+    );
+    half.opacity = 0.5;
+    half.offset = (2, 0); // second pixel is clipped
+    doc.add_layer(half);
+
+    let mut hidden = Layer::new("hidden", vec![Pixel::new(3, 0, 0xFF0000FF)]);
+    hidden.visible = false;
+    doc.add_layer(hidden);
+
+    let buffer = doc.flatten_to_buffer();
+    assert_eq!(buffer.dimensions(), (8, 8));
+    assert_eq!(buffer.get_pixel(0, 0).0, [0x40, 0x00, 0x00, 0xFF]);
+    assert_eq!(buffer.get_pixel(1, 0).0, [0xC0, 0xC0, 0xC0, 0xFF]);
+    assert_eq!(buffer.get_pixel(2, 0).0, [0xC0, 0xC0, 0xC0, 0xFF]);
+    assert_eq!(buffer.get_pixel(3, 0).0, [0x80, 0x80, 0x80, 0xFF]);
+
+    for config in get_test_configs() {
+        let bytes = encode_document_to_npng_bytes(doc.clone(), config, Encoding::Zstd(3)).unwrap();
+
+        // plain decoders get the flattened image
+        let (plain, _) =
+            decode_npng_bytes_to_image_buffer(&bytes, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(plain, buffer);
+
+        let decoded = decode_bytes_to_document(&bytes, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(decoded.layers.len(), 5);
+        let half = decoded.layer("half").unwrap();
+        assert_eq!(
+            (half.offset, half.opacity, half.blend_mode),
+            ((2, 0), 0.5, BlendMode::Normal)
+        );
+        assert!(!decoded.layer("hidden").unwrap().visible);
+        assert_eq!(decoded.flatten_to_buffer(), buffer);
+    }
+
+    // layers only: the body is empty
+    let with_composite =
+        encode_document_to_npng_bytes(doc.clone(), Config::default(), Encoding::Zstd(3)).unwrap();
+    doc.composite = false;
+    let bytes = encode_document_to_npng_bytes(doc, Config::default(), Encoding::Zstd(3)).unwrap();
+    assert!(bytes.len() < with_composite.len());
+    let decoded = decode_bytes_to_document(&bytes, false, Encoding::Zstd(3)).unwrap();
+    assert!(!decoded.composite);
+    assert_eq!(decoded.flatten_to_buffer(), buffer);
+
+    // plain files decode as a single layer
+    let bytes = encode_pixel_vec_with_metadata(
+        vec![Pixel::new(1, 1, 0xFFFFFFFF)],
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+        Config::default(),
+        Encoding::Plain,
+    )
+    .unwrap();
+    let decoded = decode_bytes_to_document(&bytes, false, Encoding::Plain).unwrap();
+    assert_eq!(decoded.layers.len(), 1);
+    assert_eq!(decoded.layers[0].pixels, vec![Pixel::new(1, 1, 0xFFFFFFFF)]);
+}
+
+#[test]
+fn test_aux_channels() {
+    // reverse order, so sorting moves pixels around
+    let pixels: Vec<Pixel> = (0..6u16)
+        .rev()
+        .map(|i| Pixel::new(i, i % 2, if i == 2 { 0 } else { 0x102030FF }))
+        .collect();
+    let img = Img {
+        pixels,
+        encoder_version: version(),
+        metadata: Metadata::new("TEST", HashMap::<String, String>::new()),
+    };
+    let mut img = ChannelImg::new(img);
+    img.add_channel("depth", ChannelType::F32, 1).unwrap();
+    img.add_channel("id", ChannelType::U32, 1).unwrap();
+    img.add_channel("normal", ChannelType::F32, 3).unwrap();
+    assert!(img.add_channel("id", ChannelType::U8, 1).is_err());
+    assert!(img.add_channel("bad", ChannelType::U8, 5).is_err());
+    for i in 0..6u16 {
+        img.set_value_at("depth", i, i % 2, &[i as f32 * 0.5])
+            .unwrap();
+        img.set_value_at("id", i, i % 2, &[1000 + i as u32])
+            .unwrap();
+        img.set_value_at("normal", i, i % 2, &[0.0, i as f32, 1.0])
+            .unwrap();
+    }
+    assert!(img.set_value_at("id", 0, 0, &[1u8]).is_err()); // wrong type
+    assert!(img.set_value_at("normal", 0, 0, &[1.0f32]).is_err()); // wrong component count
+
+    // index-based access, the position index follows changes of the pixels
+    let mut moved = img.clone();
+    let i = moved.pixel_index(3, 1).unwrap();
+    assert_eq!(moved.value::<u32>("id", i), Some(&[1003][..]));
+    moved.img_mut().pixels[i].x = 10;
+    assert_eq!(moved.value_at::<u32>("id", 3, 1), None);
+    assert_eq!(moved.value_at::<u32>("id", 10, 1), Some(&[1003][..]));
+    moved.set_value("id", i, &[7u32]).unwrap();
+    assert_eq!(moved.value_at::<u32>("id", 10, 1), Some(&[7][..]));
+    assert!(moved.set_value("id", 6, &[7u32]).is_err());
+
+    let mut configs = get_test_configs();
+    configs.push(Config {
+        pixel_order: PixelOrder::RowMajor,
+        ..Config::default()
+    });
+    configs.push(Config {
+        layout: Layout::Quadtree,
+        ..Config::default()
+    });
+    for config in configs {
+        let bytes =
+            encode_channel_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap();
+        let decoded = decode_bytes_to_channel_img(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(decoded.img().pixels.len(), 5); // transparent pixel dropped
+        assert_eq!(decoded.channels.len(), 3);
+        assert_eq!(decoded.channel("normal").unwrap().kind(), ChannelType::F32);
+        for i in [0u16, 1, 3, 4, 5] {
+            assert_eq!(
+                decoded.value_at::<f32>("depth", i, i % 2),
+                Some(&[i as f32 * 0.5][..])
+            );
+            assert_eq!(
+                decoded.value_at::<u32>("id", i, i % 2),
+                Some(&[1000 + i as u32][..])
+            );
+            assert_eq!(
+                decoded.value_at::<f32>("normal", i, i % 2),
+                Some(&[0.0, i as f32, 1.0][..])
+            );
+        }
+        assert_eq!(decoded.value_at::<u8>("id", 0, 0), None);
+
+        // RGBA-only functions ignore the channels
+        let plain = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(plain.pixels.len(), 5);
+    }
+
+    // covered pixels keep their values
+    for pixel_order in [
+        PixelOrder::Preserve,
+        PixelOrder::RowMajor,
+        PixelOrder::Hilbert,
+    ] {
+        let config = Config {
+            alpha_policy: AlphaPolicy::KeepCoverage,
+            pixel_order,
+            ..Config::default()
+        };
+        let bytes =
+            encode_channel_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap();
+        let decoded = decode_bytes_to_channel_img(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(decoded.img().pixels.len(), 6);
+        for i in 0..6u16 {
+            assert_eq!(
+                decoded.value_at::<u32>("id", i, i % 2),
+                Some(&[1000 + i as u32][..]),
+                "{pixel_order}"
+            );
+        }
+    }
+
+    let mut short = img.clone();
+    short.channels[0] = AuxChannel::new("depth", ChannelType::F32, 1, 2).unwrap();
+    assert!(encode_channel_img_to_npng_bytes(short, Config::default(), Encoding::Plain).is_err());
+}
+
+#[test]
+fn test_alpha_policy() {
+    let pixels = vec![
+        Pixel::new(0, 0, 0x112233FF),
+        Pixel::new(1, 0, 0x44556600),
+        Pixel::new(2, 0, 0x00000000),
+    ];
+    let meta = Metadata::new("TEST", HashMap::<String, String>::new());
+
+    for (policy, expected) in [
+        (AlphaPolicy::Drop, vec![0x112233FF]),
+        (AlphaPolicy::KeepCoverage, vec![0x112233FF, 0, 0]),
+        (AlphaPolicy::KeepExact, vec![0x112233FF, 0x44556600, 0]),
+    ] {
+        for varint in [false, true] {
+            let config = Config {
+                varint,
+                alpha_policy: policy,
+                ..Config::default()
+            };
+            let bytes = encode_pixel_vec_with_metadata(
+                pixels.clone(),
+                meta.clone(),
+                config,
+                Encoding::Zstd(3),
+            )
+            .unwrap();
+            let img = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+            assert_eq!(
+                img.pixels.iter().map(|p| p.color).collect::<Vec<_>>(),
+                expected
+            );
+            assert_eq!(img.metadata.width, 3);
+
+            let read = read_encoding_config(&bytes).unwrap();
+            assert_eq!(
+                (read.alpha_policy, read.varint, read.save_alpha),
+                (policy, varint, true)
+            );
+        }
+    }
+
+    // covered pixels are stored without colors, in every layout
+    let pixels: Vec<Pixel> = (0..4096u32)
+        .map(|i| {
+            Pixel::new(
+                (i % 64) as u16,
+                (i / 64) as u16,
+                if i % 5 == 0 { 0x112233FF } else { i << 8 },
+            )
+        })
+        .collect();
+    let exact_config = Config {
+        alpha_policy: AlphaPolicy::KeepExact,
+        pixel_order: PixelOrder::RowMajor,
+        ..Config::default()
+    };
+    let exact = encode_pixel_vec_with_metadata(
+        pixels.clone(),
+        meta.clone(),
+        exact_config.clone(),
+        Encoding::Plain,
+    )
+    .unwrap();
+    for layout in [
+        Layout::Pixels,
+        Layout::Spans,
+        Layout::Quadtree,
+        Layout::Delta,
+        Layout::Planar,
+        Layout::Qoi,
+        Layout::Progressive,
+    ] {
+        let config = Config {
+            alpha_policy: AlphaPolicy::KeepCoverage,
+            layout,
+            ..exact_config.clone()
+        };
+        let bytes =
+            encode_pixel_vec_with_metadata(pixels.clone(), meta.clone(), config, Encoding::Plain)
+                .unwrap();
+        if layout == Layout::Pixels {
+            assert!(bytes.len() < exact.len() * 3 / 4, "{layout}");
+        }
+        let img = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+        let expected: Vec<u32> = pixels
+            .iter()
+            .map(|p| if p.color & 0xFF == 0 { 0 } else { p.color })
+            .collect();
+        assert_eq!(
+            img.pixels.iter().map(|p| p.color).collect::<Vec<_>>(),
+            expected,
+            "{layout}"
+        );
+    }
+
+    // keeping transparent pixels needs the alpha channel
+    let config = Config {
+        save_alpha: false,
+        alpha_policy: AlphaPolicy::KeepExact,
+        ..Config::default()
+    };
+    assert!(encode_pixel_vec_with_metadata(pixels, meta, config, Encoding::Plain).is_err());
+}
+
+#[test]
+fn test_premultiplied_alpha() {
+    let straight = vec![
+        Pixel::new(0, 0, 0xFF804080),
+        Pixel::new(1, 0, 0x204060FF),
+        Pixel::new(2, 0, 0xFFFFFF00),
+    ];
+    let mut img = Img {
+        pixels: straight.clone(),
+        encoder_version: version(),
+        metadata: Metadata::new("TEST", HashMap::<String, String>::new()),
+    };
+    img.premultiply();
+    assert_eq!(img.metadata.alpha_mode, AlphaMode::Premultiplied);
+    assert_eq!(img.pixels[0].color, 0x80402080);
+    assert_eq!(img.pixels[1].color, 0x204060FF);
+    img.premultiply(); // no-op
+    assert_eq!(img.pixels[0].color, 0x80402080);
+
+    // the mode is stored in the file
+    let bytes = encode_img_to_npng_bytes(img.clone(), Config::default(), Encoding::Plain).unwrap();
+    let decoded = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+    assert_eq!(decoded.metadata.alpha_mode, AlphaMode::Premultiplied);
+    assert_eq!(decoded.pixels[0].color, 0x80402080);
+
+    // decode into the requested mode
+    let config = DecodeConfig {
+        alpha_mode: Some(AlphaMode::Straight),
+        ..DecodeConfig::default()
+    };
+    let decoded =
+        decode_bytes_to_pixel_vec_with_config(&bytes, false, false, Encoding::Plain, config)
+            .unwrap();
+    assert_eq!(decoded.metadata.alpha_mode, AlphaMode::Straight);
+    assert_eq!(decoded.pixels[0].color, 0xFF804080);
+    assert_eq!(decoded.pixels[1].color, 0x204060FF);
+
+    // image buffers are straight by default
+    let (buffer, meta) = decode_npng_bytes_to_image_buffer(&bytes, false, Encoding::Plain).unwrap();
+    assert_eq!(meta.alpha_mode, AlphaMode::Straight);
+    assert_eq!(buffer.get_pixel(0, 0).0, [0xFF, 0x80, 0x40, 0x80]);
+    let config = DecodeConfig {
+        alpha_mode: Some(AlphaMode::Premultiplied),
+        ..DecodeConfig::default()
+    };
+    let (buffer, _) =
+        decode_npng_bytes_to_image_buffer_with_config(&bytes, false, Encoding::Plain, config)
+            .unwrap();
+    assert_eq!(buffer.get_pixel(0, 0).0, [0x80, 0x40, 0x20, 0x80]);
+
+    img.unpremultiply();
+    assert_eq!(img.pixels[..2], straight[..2]);
+}
+
+#[test]
+fn test_pixel_formats() {
+    let pixels = vec![
+        Pixel::new(0, 0, 0x112233FF),
+        Pixel::new(1, 0, 0x44556680),
+        Pixel::new(2, 1, 0x7788997F),
+    ];
+    let meta = Metadata::new("TEST", HashMap::<String, String>::new());
+    let encode = |config: Config| {
+        encode_pixel_vec_with_metadata(pixels.clone(), meta.clone(), config, Encoding::Plain)
+    };
+    let rgba = encode(Config::default()).unwrap();
+
+    for (format, expected) in [
+        (PixelFormat::BinaryAlpha, vec![0x112233FF, 0x445566FF]),
+        (PixelFormat::Shape, vec![0xFFFFFFFF, 0xFFFFFFFF]),
+    ] {
+        for varint in [false, true] {
+            let bytes = encode(Config {
+                varint,
+                pixel_format: format,
+                ..Config::default()
+            })
+            .unwrap();
+            assert!(bytes.len() < rgba.len());
+            let img = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+            assert_eq!(
+                img.pixels.iter().map(|p| p.color).collect::<Vec<_>>(),
+                expected
+            );
+            assert_eq!((img.pixels[1].x, img.pixels[1].y), (1, 0));
+            assert_eq!((img.metadata.width, img.metadata.height), (3, 2));
+
+            let read = read_encoding_config(&bytes).unwrap();
+            assert_eq!((read.pixel_format, read.varint), (format, varint));
+        }
+    }
+
+    // transparent pixels can't be kept without an alpha channel
+    let config = Config {
+        pixel_format: PixelFormat::Shape,
+        alpha_policy: AlphaPolicy::KeepCoverage,
+        ..Config::default()
+    };
+    assert!(encode(config).is_err());
+}
+
+#[test]
+fn test_span_layout() {
+    // flat-shaded rows with a few noisy pixels and a gap
+    let mut pixels = Vec::new();
+    for y in 0..64u16 {
+        for x in 0..200u16 {
+            let color = match x {
+                _ if x % 50 == 7 => 0x10203000 | (x as u32 & 0xFF) << 8 | 0xFF,
+                90..=99 => continue,
+                _ if x < 120 => 0xFF0000FF,
+                _ => 0x00FF0080,
+            };
+            pixels.push(Pixel::new(x, y, color));
+        }
+    }
+    let meta = Metadata::new("TEST", HashMap::<String, String>::new());
+
+    for config in get_test_configs() {
+        let encode = |layout| {
+            let config = Config {
+                layout,
+                ..config.clone()
+            };
+            encode_pixel_vec_with_metadata(pixels.clone(), meta.clone(), config, Encoding::Plain)
+                .unwrap()
+        };
+        let records = encode(Layout::Pixels);
+        let spans = encode(Layout::Spans);
+        assert!(spans.len() * 5 < records.len());
+        assert_eq!(encode(Layout::Auto), spans);
+        assert_eq!(read_encoding_config(&spans).unwrap().layout, Layout::Spans);
+
+        let a = decode_bytes_to_pixel_vec(&records, false, false, Encoding::Plain).unwrap();
+        let b = decode_bytes_to_pixel_vec(&spans, false, false, Encoding::Plain).unwrap();
+        assert_eq!(a.pixels, b.pixels);
+    }
+
+    // shapes are single runs per row
+    let config = Config {
+        layout: Layout::Spans,
+        pixel_format: PixelFormat::Shape,
+        ..Config::default()
+    };
+    let bytes =
+        encode_pixel_vec_with_metadata(pixels.clone(), meta, config, Encoding::Plain).unwrap();
+    let img = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+    assert_eq!(img.pixels.len(), pixels.len());
+    assert!(
+        img.pixels
+            .iter()
+            .zip(&pixels)
+            .all(|(a, b)| (a.x, a.y) == (b.x, b.y))
+    );
+
+    // a span longer than the canvas is rejected before its pixels are decoded
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.width = 70_000;
+    metadata.height = 1;
+    let config = Config {
+        layout: Layout::Spans,
+        varint: false,
+        ..Config::default()
+    };
+    let mut bytes = encode_wide_pixel_vec_with_metadata(
+        vec![WidePixel::new(0, 0, 0x112233FF)],
+        metadata,
+        config,
+        Encoding::Plain,
+    )
+    .unwrap();
+    // single record (kind, y, x, len, RGBA) before the 20-byte checksum
+    let len_at = bytes.len() - 20 - 17 + 9;
+    assert_eq!(bytes[len_at..len_at + 4], 1u32.to_le_bytes());
+    bytes[len_at..len_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+    assert!(decode_bytes_to_wide_pixel_vec(&bytes, false, true, Encoding::Plain).is_err());
+}
+
+#[test]
+fn test_quadtree_layout() {
+    // clustered blobs on a canvas that is not a power of two
+    let mut pixels = Vec::new();
+    for (cx, cy, r) in [(40i32, 30i32, 12i32), (150, 90, 20), (299, 199, 9)] {
+        for y in (cy - r).max(0)..(cy + r).min(200) {
+            for x in (cx - r).max(0)..(cx + r).min(300) {
+                if (x - cx).pow(2) + (y - cy).pow(2) <= r * r {
+                    pixels.push(Pixel::new(
+                        x as u16,
+                        y as u16,
+                        0x3060C0FF ^ ((x * 7 + y) as u32 & 0x0F) << 8,
+                    ));
+                }
+            }
+        }
+    }
+    pixels.reverse();
+    let mut meta = Metadata::new("TEST", HashMap::<String, String>::new());
+    (meta.width, meta.height) = (300, 200);
+
+    let mut sorted = pixels.clone();
+    sorted.sort_by_key(|p| (p.y, p.x));
+    for config in get_test_configs() {
+        let encode = |layout, pixel_order| {
+            let config = Config {
+                layout,
+                pixel_order,
+                ..config.clone()
+            };
+            encode_pixel_vec_with_metadata(pixels.clone(), meta.clone(), config, Encoding::Plain)
+                .unwrap()
+        };
+        let records = encode(Layout::Pixels, PixelOrder::RowMajor);
+        let quadtree = encode(Layout::Quadtree, PixelOrder::Preserve);
+        assert!(quadtree.len() < records.len());
+        assert_eq!(
+            read_encoding_config(&quadtree).unwrap().layout,
+            Layout::Quadtree
+        );
+
+        // decoded in row-major order
+        let img = decode_bytes_to_pixel_vec(&quadtree, false, false, Encoding::Plain).unwrap();
+        let expected: Vec<u32> = sorted
+            .iter()
+            .map(|p| {
+                if config.save_alpha {
+                    p.color
+                } else {
+                    p.color | 0xFF
+                }
+            })
+            .collect();
+        assert_eq!(
+            img.pixels.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>(),
+            sorted.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            img.pixels.iter().map(|p| p.color).collect::<Vec<_>>(),
+            expected
+        );
+        assert_eq!((img.metadata.width, img.metadata.height), (300, 200));
+
+        // only tried automatically when the order doesn't have to be preserved
+        // (QOI is smaller on this shape without a compressor)
+        let auto = encode(Layout::Auto, PixelOrder::RowMajor);
+        assert!(auto.len() <= quadtree.len());
+        let auto = encode(Layout::Auto, PixelOrder::Preserve);
+        assert_ne!(
+            read_encoding_config(&auto).unwrap().layout,
+            Layout::Quadtree
+        );
+    }
+
+    // coverage alone is a fraction of the coordinate list
+    let encode = |layout| {
+        let config = Config {
+            layout,
+            pixel_format: PixelFormat::Shape,
+            ..Config::default()
+        };
+        encode_pixel_vec_with_metadata(pixels.clone(), meta.clone(), config, Encoding::Plain)
+            .unwrap()
+    };
+    let (records, quadtree) = (encode(Layout::Pixels), encode(Layout::Quadtree));
+    assert!(quadtree.len() * 10 < records.len());
+    let img = decode_bytes_to_pixel_vec(&quadtree, false, false, Encoding::Plain).unwrap();
+    assert_eq!(img.pixels.len(), pixels.len());
+
+    // a full node is bounded by the stored colors before its pixels are decoded
+    (meta.width, meta.height) = (65536, 65536);
+    let config = Config {
+        layout: Layout::Quadtree,
+        varint: false,
+        ..Config::default()
+    };
+    let mut bytes = encode_pixel_vec_with_metadata(
+        vec![Pixel::new(0, 0, 0x112233FF)],
+        meta,
+        config,
+        Encoding::Plain,
+    )
+    .unwrap();
+    // body: node count (u64), node bits, one RGBA color, then the 20-byte checksum
+    let end = bytes.len() - 20 - 4;
+    let nodes = (1..end - 8)
+        .find(|&n| bytes[end - n - 8..end - n] == (n as u64).to_le_bytes())
+        .unwrap();
+    let root = end - nodes;
+    assert_eq!(bytes[root] >> 6, 0b10); // mixed
+    bytes[root] = (bytes[root] & 0x3F) | 0b01 << 6; // full
+    assert!(decode_bytes_to_pixel_vec(&bytes, false, true, Encoding::Plain).is_err());
+}
+
+#[test]
+fn test_pixel_order() {
+    // 16x16 square in a scrambled order
+    let mut pixels: Vec<Pixel> = (0..256u32)
+        .map(|i| {
+            let j = (i * 97 + 31) % 256;
+            Pixel::new((j % 16) as u16, (j / 16) as u16, 0x204080FF | (j << 8))
+        })
+        .collect();
+    let meta = Metadata::new("TEST", HashMap::<String, String>::new());
+
+    for order in [
+        PixelOrder::RowMajor,
+        PixelOrder::Morton,
+        PixelOrder::Hilbert,
+    ] {
+        for layout in [Layout::Pixels, Layout::Quadtree] {
+            let config = Config {
+                pixel_order: order,
+                layout,
+                ..Config::default()
+            };
+            let bytes = encode_pixel_vec_with_metadata(
+                pixels.clone(),
+                meta.clone(),
+                config.clone(),
+                Encoding::Plain,
+            )
+            .unwrap();
+            assert_eq!(read_encoding_config(&bytes).unwrap().pixel_order, order);
+
+            // output doesn't depend on the input order
+            pixels.reverse();
+            let again = encode_pixel_vec_with_metadata(
+                pixels.clone(),
+                meta.clone(),
+                config,
+                Encoding::Plain,
+            )
+            .unwrap();
+            assert_eq!(bytes, again);
+
+            let img = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+            assert_eq!(img.pixels.len(), 256);
+            let xy: Vec<(u16, u16)> = img.pixels.iter().map(|p| (p.x, p.y)).collect();
+            match order {
+                PixelOrder::RowMajor => {
+                    assert!(xy.windows(2).all(|w| (w[0].1, w[0].0) < (w[1].1, w[1].0)))
+                }
+                // Z-order: (0,0) (1,0) (0,1) (1,1) (2,0) ...
+                PixelOrder::Morton => assert_eq!(xy[..5], [(0, 0), (1, 0), (0, 1), (1, 1), (2, 0)]),
+                // every step moves to a neighbor
+                _ => assert!(
+                    xy.windows(2)
+                        .all(|w| w[0].0.abs_diff(w[1].0) + w[0].1.abs_diff(w[1].1) == 1)
+                ),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_delta_layout() {
+    // sparse strokes, a few pixels per row
+    let sparse = |w: u32, h: u32| -> Vec<WidePixel> {
+        (0..h)
+            .step_by(3)
+            .flat_map(|y| {
+                (0..4).map(move |i| {
+                    WidePixel::new(y * 7 % (w / 2) + i * 3, y, 0x80402000 | (i * 60) | 0x0F)
+                })
+            })
+            .collect()
+    };
+
+    for (w, h) in [(200u32, 150u32), (3000, 2000), (70000, 90)] {
+        let pixels = sparse(w, h);
+        let mut meta = Metadata::new("TEST", HashMap::<String, String>::new());
+        (meta.width, meta.height) = (w, h);
+        for config in get_test_configs() {
+            for pixel_order in [PixelOrder::Preserve, PixelOrder::RowMajor] {
+                let config = Config {
+                    pixel_order,
+                    ..config.clone()
+                };
+                let encode = |layout| {
+                    let config = Config {
+                        layout,
+                        ..config.clone()
+                    };
+                    encode_wide_pixel_vec_with_metadata(
+                        pixels.clone(),
+                        meta.clone(),
+                        config,
+                        Encoding::Plain,
+                    )
+                    .unwrap()
+                };
+                let records = encode(Layout::Pixels);
+                let delta = encode(Layout::Delta);
+                assert!(delta.len() < records.len());
+
+                let a = decode_bytes_to_wide_pixel_vec(&records, false, false, Encoding::Plain)
+                    .unwrap();
+                let b =
+                    decode_bytes_to_wide_pixel_vec(&delta, false, false, Encoding::Plain).unwrap();
+                assert_eq!(a.pixels, b.pixels);
+                assert_eq!((b.metadata.width, b.metadata.height), (w, h));
+                assert_eq!(read_encoding_config(&delta).unwrap().layout, Layout::Delta);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_color_transforms() {
+    let meta = Metadata::new("TEST", HashMap::<String, String>::new());
+    // soft gradient
+    let gradient: Vec<Pixel> = (0..128u16)
+        .flat_map(|y| {
+            (0..128u16).map(move |x| {
+                let v = (x + y) as u32;
+                Pixel::new(
+                    x,
+                    y,
+                    (v + x as u32 / 16) << 24 | v << 16 | (v + y as u32 / 16) << 8 | 0xFF,
+                )
+            })
+        })
+        .collect();
+    // arbitrary colors, to check that every transform is lossless
+    let mut seed = 12345u32;
+    let noise: Vec<Pixel> = (0..4096u16)
+        .map(|i| {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            Pixel::new(i % 64, i / 64, seed | 1)
+        })
+        .collect();
+
+    for pixels in [&gradient, &noise] {
+        for config in get_test_configs() {
+            for transform in [
+                ColorTransform::None,
+                ColorTransform::SubtractGreen,
+                ColorTransform::YCoCgR,
+                ColorTransform::Auto,
+            ] {
+                let config = Config {
+                    color_transform: transform,
+                    ..config.clone()
+                };
+                let bytes = encode_pixel_vec_with_metadata(
+                    pixels.clone(),
+                    meta.clone(),
+                    config.clone(),
+                    Encoding::Zstd(3),
+                )
+                .unwrap();
+                let img =
+                    decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+                let expected: Vec<u32> = pixels
+                    .iter()
+                    .map(|p| {
+                        if config.save_alpha {
+                            p.color
+                        } else {
+                            p.color | 0xFF
+                        }
+                    })
+                    .collect();
+                assert_eq!(
+                    img.pixels.iter().map(|p| p.color).collect::<Vec<_>>(),
+                    expected
+                );
+
+                let stored = read_encoding_config(&bytes).unwrap().color_transform;
+                if transform != ColorTransform::Auto {
+                    assert_eq!(stored, transform);
+                }
+            }
+        }
+    }
+
+    // gray noise: all channels carry the same information
+    let gray: Vec<Pixel> = noise
+        .iter()
+        .map(|p| {
+            let v = p.color >> 24;
+            Pixel::new(
+                p.x,
+                p.y,
+                (v + 8) << 24 | v << 16 | v.saturating_sub(8) << 8 | 0xFF,
+            )
+        })
+        .collect();
+    let encode = |transform| {
+        let config = Config {
+            color_transform: transform,
+            ..Config::default()
+        };
+        encode_pixel_vec_with_metadata(gray.clone(), meta.clone(), config, Encoding::Zstd(3))
+            .unwrap()
+    };
+    let plain = encode(ColorTransform::None);
+    let auto = encode(ColorTransform::Auto);
+    assert_ne!(
+        read_encoding_config(&auto).unwrap().color_transform,
+        ColorTransform::None
+    );
+    assert!(auto.len() < plain.len());
+}
+
+#[test]
+fn test_planar_layout() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+
+    for config in get_test_configs() {
+        let encode = |layout| {
+            let config = Config {
+                layout,
+                ..config.clone()
+            };
+            encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap()
+        };
+        let records = encode(Layout::Pixels);
+        let planar = encode(Layout::Planar);
+        assert!(planar.len() < records.len());
+        assert_eq!(
+            read_encoding_config(&planar).unwrap().layout,
+            Layout::Planar
+        );
+
+        let a = decode_bytes_to_pixel_vec(&records, false, false, Encoding::Zstd(3)).unwrap();
+        let b = decode_bytes_to_pixel_vec(&planar, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(a.pixels, b.pixels);
+    }
+}
+
+#[test]
+fn test_rans_codec() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+
+    for layout in [Layout::Pixels, Layout::Spans, Layout::Delta, Layout::Planar] {
+        let config = Config {
+            layout,
+            ..Config::default()
+        };
+        let rans = encode_img_to_npng_bytes(img.clone(), config.clone(), Encoding::Rans).unwrap();
+        let zstd = encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(19)).unwrap();
+        // only checked on this image with fixed-size records, not a general claim
+        if layout == Layout::Pixels {
+            assert!(rans.len() < zstd.len());
+        }
+
+        let decoded = decode_bytes_to_pixel_vec(&rans, false, false, Encoding::Rans).unwrap();
+        let expected = decode_bytes_to_pixel_vec(&zstd, false, false, Encoding::Zstd(19)).unwrap();
+        assert_eq!(decoded.pixels, expected.pixels);
+    }
+
+    let cmap = CompressMap::rans();
+    let noise: Vec<u8> = (0..10_000u32)
+        .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+        .collect();
+    for data in [Vec::new(), vec![7], vec![0; 100_000], noise] {
+        let (name, compressed) = cmap.compress(data.clone().into()).unwrap();
+        assert_eq!(name, "rans");
+        assert_eq!(
+            cmap.decompress(compressed.freeze(), &name)
+                .unwrap()
+                .as_ref(),
+            data.as_slice()
+        );
+    }
+    assert!("rans".into_compress_map().is_ok());
+
+    // symbols can't be free, the length is bounded by the stream
+    let stream = |tables: &[u8]| {
+        let mut bytes = vec![0x80, 0x80, 0x80, 0x80, 0x80, 0x20, 0]; // 2^40 bytes (varint), no stride
+        (0..3).for_each(|_| bytes.extend_from_slice(tables));
+        bytes.extend_from_slice(&[0x00, 0x80, 0x00, 0x00]); // state
+        bytes
+    };
+    let full = stream(&[1, 0, 0xFF, 0x1F]); // symbol 0 with every slot
+    let almost_full = stream(&[2, 0, 0xFE, 0x1F, 1, 0]); // and symbol 1 with one slot
+    for bytes in [full, almost_full] {
+        assert!(cmap.decompress(bytes.into(), "rans").is_err());
+    }
+}
+
+#[test]
+fn test_qoi_layout() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+
+    let mut configs = get_test_configs();
+    configs.push(Config {
+        pixel_format: PixelFormat::Shape,
+        ..Config::default()
+    });
+    for config in configs {
+        let encode = |layout| {
+            let config = Config {
+                layout,
+                ..config.clone()
+            };
+            encode_img_to_npng_bytes(img.clone(), config, Encoding::Plain).unwrap()
+        };
+        let records = encode(Layout::Pixels);
+        let qoi = encode(Layout::Qoi);
+        assert!(qoi.len() < records.len());
+        assert_eq!(read_encoding_config(&qoi).unwrap().layout, Layout::Qoi);
+
+        let a = decode_bytes_to_pixel_vec(&records, false, false, Encoding::Plain).unwrap();
+        let b = decode_bytes_to_pixel_vec(&qoi, false, false, Encoding::Plain).unwrap();
+        assert_eq!(a.pixels, b.pixels);
+    }
+}
+
+#[test]
+fn test_quantization() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+    let exact =
+        encode_img_to_npng_bytes(img.clone(), Config::default(), Encoding::Zstd(3)).unwrap();
+    assert!(read_palette(&exact).unwrap().is_none());
+    let reference = decode_bytes_to_pixel_vec(&exact, false, false, Encoding::Zstd(3))
+        .unwrap()
+        .pixels;
+
+    for method in [
+        QuantizeMethod::MedianCut,
+        QuantizeMethod::Octree,
+        QuantizeMethod::KMeans,
+    ] {
+        for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+            let quantize = Quantize::new(16, method, dither);
+            for layout in [Layout::Pixels, Layout::Delta, Layout::Qoi] {
+                let config = Config {
+                    quantize: Some(quantize),
+                    layout,
+                    ..Config::default()
+                };
+                let bytes =
+                    encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap();
+                assert!(bytes.len() < exact.len());
+                assert_eq!(
+                    read_encoding_config(&bytes).unwrap().quantize,
+                    Some(quantize)
+                );
+
+                let palette = read_palette(&bytes).unwrap().unwrap();
+                assert!(palette.colors.len() <= 16);
+                let decoded =
+                    decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+                assert_eq!(decoded.pixels.len(), reference.len());
+                assert!(
+                    decoded
+                        .pixels
+                        .iter()
+                        .all(|p| palette.colors.contains(&p.color))
+                );
+
+                // reported error matches the decoded colors
+                let error: f64 = reference
+                    .iter()
+                    .zip(&decoded.pixels)
+                    .flat_map(|(a, b)| a.color.to_be_bytes().into_iter().zip(b.color.to_be_bytes()))
+                    .map(|(a, b)| (a as f64 - b as f64).powi(2))
+                    .sum();
+                let mse = error / (reference.len() * 4) as f64;
+                assert!(
+                    (mse - palette.mse).abs() < 1e-6,
+                    "{} {} {}",
+                    quantize,
+                    mse,
+                    palette.mse
+                );
+                assert!(palette.psnr() > 15.0);
+            }
+        }
+    }
+
+    // few colors are kept exactly
+    let pixels = vec![
+        Pixel::new(0, 0, 0xFF0000FF),
+        Pixel::new(1, 0, 0x00FF00FF),
+        Pixel::new(0, 1, 0xFF0000FF),
+    ];
+    let config = Config {
+        quantize: Some(Quantize::with_quality(0)),
+        ..Config::default()
+    };
+    let bytes = encode_pixel_vec_with_metadata(
+        pixels.clone(),
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+        config,
+        Encoding::Plain,
+    )
+    .unwrap();
+    assert_eq!(read_palette(&bytes).unwrap().unwrap().psnr(), f64::INFINITY);
+    let decoded = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Plain).unwrap();
+    assert_eq!(decoded.pixels, pixels);
+}
+
+#[test]
+fn test_near_lossless() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+    let lossless =
+        encode_img_to_npng_bytes(img.clone(), Config::default(), Encoding::Zstd(3)).unwrap();
+    let zero = Config {
+        max_error: 0,
+        ..Config::default()
+    };
+    assert_eq!(
+        encode_img_to_npng_bytes(img.clone(), zero, Encoding::Zstd(3)).unwrap(),
+        lossless
+    );
+
+    for max_error in [1, 2, 4, 8] {
+        for save_alpha in [true, false] {
+            for layout in [Layout::Pixels, Layout::Delta, Layout::Auto] {
+                let config = Config {
+                    save_alpha,
+                    layout,
+                    ..Config::default()
+                };
+                let exact =
+                    encode_img_to_npng_bytes(img.clone(), config.clone(), Encoding::Zstd(3))
+                        .unwrap();
+                let config = Config {
+                    max_error,
+                    ..config
+                };
+                let bytes =
+                    encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap();
+                assert!(bytes.len() < exact.len());
+                assert_eq!(read_encoding_config(&bytes).unwrap().max_error, max_error);
+
+                let decoded =
+                    decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+                let mut original = img.clone();
+                if !save_alpha {
+                    original.pixels.retain(|p| p.color & 0xFF != 0);
+                    original.pixels.iter_mut().for_each(|p| p.color |= 0xFF);
+                }
+                let report = compare_images(&original, &decoded).unwrap();
+                assert!(report.within(max_error));
+                assert!(report.psnr > 30.0 && report.ssim > 0.9);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_progressive_layout() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+
+    for config in get_test_configs() {
+        let encode = |layout| {
+            let config = Config {
+                layout,
+                ..config.clone()
+            };
+            encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap()
+        };
+        let records = encode(Layout::Pixels);
+        let progressive = encode(Layout::Progressive);
+        assert_eq!(
+            read_encoding_config(&progressive).unwrap().layout,
+            Layout::Progressive
+        );
+
+        let a = decode_bytes_to_pixel_vec(&records, false, false, Encoding::Zstd(3)).unwrap();
+        let b = decode_bytes_to_pixel_vec(&progressive, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(a.pixels, b.pixels);
+
+        // one refined preview per pass, the last one is exact
+        let (expected, _) =
+            decode_npng_bytes_to_image_buffer(&progressive, false, Encoding::Zstd(3)).unwrap();
+        let mut decoder = ProgressiveDecoder::new(Encoding::Zstd(3)).unwrap();
+        let mut previews = Vec::new();
+        for part in progressive.chunks(997) {
+            previews.extend(decoder.push(part).unwrap());
+        }
+        assert_eq!(decoder.passes(), 7);
+        assert_eq!(previews.len(), 7);
+        assert!(
+            previews
+                .iter()
+                .all(|p| p.dimensions() == expected.dimensions())
+        );
+        assert_ne!(previews[0], expected);
+        assert_eq!(previews[6], expected);
+        assert_eq!(decoder.metadata().unwrap().width, expected.width());
+        assert_eq!(decoder.finish().unwrap().pixels.len(), b.pixels.len());
+
+        // other layouts decode without previews
+        let mut decoder = ProgressiveDecoder::new(Encoding::Zstd(3)).unwrap();
+        assert!(decoder.push(&records).unwrap().is_empty());
+        assert!(decoder.finish().is_ok());
+    }
+
+    let mut decoder = ProgressiveDecoder::new(Encoding::Zstd(3)).unwrap();
+    assert!(decoder.push(b"not an npng file").is_err());
+
+    // no preview buffer for the declared canvas of a huge file
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    (metadata.width, metadata.height) = (60_000, 60_000);
+    let config = Config {
+        layout: Layout::Progressive,
+        ..Config::default()
+    };
+    let bytes = encode_pixel_vec_with_metadata(
+        vec![Pixel::new(1, 1, 0xFFFFFFFF)],
+        metadata,
+        config,
+        Encoding::Zstd(3),
+    )
+    .unwrap();
+    let mut decoder = ProgressiveDecoder::new(Encoding::Zstd(3)).unwrap();
+    assert!(decoder.push(&bytes).unwrap().is_empty());
+    assert_eq!(decoder.finish().unwrap().pixels.len(), 1);
+
+    // a truncated chunk list waits for more bytes, a corrupt one is an error
+    let end = bytes.windows(6).position(|w| w == [0xFF; 6]).unwrap() + 6;
+    let mut decoder = ProgressiveDecoder::new(Encoding::Zstd(3)).unwrap();
+    assert!(decoder.push(&bytes[..end + 2]).unwrap().is_empty());
+    assert!(decoder.metadata().is_none());
+    let mut corrupt = bytes.clone();
+    corrupt[end] = 0xFF;
+    let mut decoder = ProgressiveDecoder::new(Encoding::Zstd(3)).unwrap();
+    assert!(decoder.push(&corrupt).is_err());
+}
+
+#[test]
+fn test_mipmaps() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+    let (width, height) = (img.metadata.width, img.metadata.height);
+
+    for filter in [MipFilter::Box, MipFilter::Lanczos] {
+        let config = Config {
+            mipmaps: Some(Mipmaps::new(3, filter)),
+            ..Config::default()
+        };
+        let bytes = encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap();
+        assert_eq!(
+            read_encoding_config(&bytes).unwrap().mipmaps,
+            Some(Mipmaps::new(3, filter))
+        );
+
+        let full = decode_bytes_to_wide_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(
+            decode_level(&bytes, 0, false, Encoding::Zstd(3))
+                .unwrap()
+                .pixels,
+            full.pixels
+        );
+        for level in 1..=3 {
+            let decoded = decode_level(&bytes, level, false, Encoding::Zstd(3)).unwrap();
+            assert_eq!(decoded.metadata.width, width.div_ceil(1 << level));
+            assert_eq!(decoded.metadata.height, height.div_ceil(1 << level));
+            assert!(!decoded.pixels.is_empty());
+            assert!(
+                decoded.pixels.len() <= (decoded.metadata.width * decoded.metadata.height) as usize
+            );
+        }
+        assert!(decode_level(&bytes, 4, false, Encoding::Zstd(3)).is_err());
+    }
+
+    // transparent pixels don't darken edges, the pyramid stops at 1x1
+    let pixels = vec![
+        WidePixel::new(0, 0, 0xFF0000FF),
+        WidePixel::new(1, 0, 0x00000000),
+    ];
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.width = 2;
+    metadata.height = 1;
+    for filter in [MipFilter::Box, MipFilter::Lanczos] {
+        let config = Config {
+            mipmaps: Some(Mipmaps::new(8, filter)),
+            ..Config::default()
+        };
+        let bytes = encode_wide_pixel_vec_with_metadata(
+            pixels.clone(),
+            metadata.clone(),
+            config,
+            Encoding::Zstd(3),
+        )
+        .unwrap();
+        assert_eq!(
+            read_encoding_config(&bytes)
+                .unwrap()
+                .mipmaps
+                .unwrap()
+                .levels,
+            1
+        );
+        let level = decode_level(&bytes, 1, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(level.pixels, vec![WidePixel::new(0, 0, 0xFF000080)]);
+    }
+
+    // sparse pixels of a huge canvas are downsampled without a dense buffer
+    let side = 1_000_000;
+    let mut pixels: Vec<WidePixel> = (0..4)
+        .map(|i| WidePixel::new(i % 2, i / 2, 0xFF0000FF))
+        .collect();
+    pixels.push(WidePixel::new(side - 1, side - 1, 0xFF0000FF));
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.width = side;
+    metadata.height = side;
+    let config = Config {
+        mipmaps: Some(Mipmaps::new(2, MipFilter::Box)),
+        thumbnail: Some(64),
+        ..Config::default()
+    };
+    let bytes =
+        encode_wide_pixel_vec_with_metadata(pixels, metadata, config, Encoding::Zstd(3)).unwrap();
+    let level = decode_level(&bytes, 1, false, Encoding::Zstd(3)).unwrap();
+    assert_eq!(
+        (level.metadata.width, level.metadata.height),
+        (side / 2, side / 2)
+    );
+    assert_eq!(
+        level.pixels,
+        vec![
+            WidePixel::new(0, 0, 0xFF0000FF),
+            WidePixel::new(side / 2 - 1, side / 2 - 1, 0xFF000040)
+        ]
+    );
+    assert_eq!(
+        read_thumbnail(&bytes).unwrap().unwrap().dimensions(),
+        (64, 64)
+    );
+    // only the level is read: the body and checksum are not needed
+    let level = decode_level(&bytes[..bytes.len() - 20], 2, true, Encoding::Zstd(3)).unwrap();
+    assert_eq!(level.pixels.len(), 2);
+
+    // files without levels
+    let bytes = encode_img_to_npng_bytes(img, Config::default(), Encoding::Zstd(3)).unwrap();
+    assert_eq!(read_encoding_config(&bytes).unwrap().mipmaps, None);
+    assert!(decode_level(&bytes, 1, false, Encoding::Zstd(3)).is_err());
+}
+
+#[test]
+fn test_thumbnail() {
+    require_in_png();
+    let img = encode_image_to_npng_pixels(
+        "in.png",
+        Metadata::new("TEST", HashMap::<String, String>::new()),
+    )
+    .unwrap();
+    let (width, height) = (img.metadata.width, img.metadata.height);
+
+    for size in [1, 64, MAX_THUMBNAIL_SIZE] {
+        let config = Config {
+            thumbnail: Some(size),
+            ..Config::default()
+        };
+        let bytes = encode_img_to_npng_bytes(img.clone(), config, Encoding::Zstd(3)).unwrap();
+        let thumbnail = read_thumbnail(&bytes).unwrap().unwrap();
+        let (w, h) = thumbnail.dimensions();
+        assert_eq!(w.max(h), (size as u32).min(width.max(height)));
+        assert!(w.abs_diff(h * width / height) <= 1 || w.min(h) == 1);
+        assert_eq!(
+            read_encoding_config(&bytes).unwrap().thumbnail,
+            Some(w.max(h) as u16)
+        );
+
+        // the pixel body is not needed
+        let body_start = bytes.len() / 2;
+        assert_eq!(
+            read_thumbnail(&bytes[..body_start]).unwrap().unwrap(),
+            thumbnail
+        );
+        let plain =
+            encode_img_to_npng_bytes(img.clone(), Config::default(), Encoding::Zstd(3)).unwrap();
+        let a = decode_bytes_to_pixel_vec(&plain, false, false, Encoding::Zstd(3)).unwrap();
+        let b = decode_bytes_to_pixel_vec(&bytes, false, false, Encoding::Zstd(3)).unwrap();
+        assert_eq!(a.pixels, b.pixels);
+    }
+
+    // small images are not enlarged, colors keep straight alpha
+    let pixels = vec![
+        WidePixel::new(0, 0, 0xFF0000FF),
+        WidePixel::new(1, 0, 0x00000000),
+    ];
+    let mut metadata = Metadata::new("TEST", HashMap::<String, String>::new());
+    metadata.width = 2;
+    metadata.height = 1;
+    let config = Config {
+        thumbnail: Some(256),
+        ..Config::default()
+    };
+    let bytes = encode_wide_pixel_vec_with_metadata(
+        pixels.clone(),
+        metadata.clone(),
+        config,
+        Encoding::Zstd(3),
+    )
+    .unwrap();
+    let thumbnail = read_thumbnail(&bytes).unwrap().unwrap();
+    assert_eq!(thumbnail.dimensions(), (2, 1));
+    assert_eq!(thumbnail.get_pixel(0, 0).0, [0xFF, 0, 0, 0xFF]);
+    assert_eq!(thumbnail.get_pixel(1, 0).0, [0, 0, 0, 0]);
+
+    // colors are the decoded ones (alpha and pixel format)
+    let translucent = vec![WidePixel::new(0, 0, 0xFF000080)];
+    let (w, h) = (metadata.width, metadata.height);
+    for (save_alpha, pixel_format, expected) in [
+        (true, PixelFormat::Color, [0xFF, 0, 0, 0x80]),
+        (false, PixelFormat::Color, [0xFF, 0, 0, 0xFF]),
+        (true, PixelFormat::Shape, [0xFF; 4]),
+    ] {
+        let config = Config {
+            save_alpha,
+            pixel_format,
+            thumbnail: Some(256),
+            ..Config::default()
+        };
+        let bytes = encode_wide_pixel_vec_with_metadata(
+            translucent.clone(),
+            metadata.clone(),
+            config,
+            Encoding::Zstd(3),
+        )
+        .unwrap();
+        let thumbnail = read_thumbnail(&bytes).unwrap().unwrap();
+        assert_eq!(thumbnail.dimensions(), (w, h));
+        assert_eq!(thumbnail.get_pixel(0, 0).0, expected);
+    }
+
+    for size in [0, MAX_THUMBNAIL_SIZE + 1] {
+        let config = Config {
+            thumbnail: Some(size),
+            ..Config::default()
+        };
+        assert!(
+            encode_wide_pixel_vec_with_metadata(
+                pixels.clone(),
+                metadata.clone(),
+                config,
+                Encoding::Zstd(3)
+            )
+            .is_err()
+        );
+    }
+    let bytes = encode_img_to_npng_bytes(img, Config::default(), Encoding::Zstd(3)).unwrap();
+    assert_eq!(read_thumbnail(&bytes).unwrap(), None);
+    assert!(read_thumbnail(&bytes[..4]).is_err());
+}
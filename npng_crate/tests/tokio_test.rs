@@ -1,32 +1,34 @@
+#![cfg(feature = "tokio_async")]
 extern crate __tk_rt_private as tokio;
 extern crate npng_crate;
 use std::{collections::HashMap, fs, path::Path};
 
-use image::ImageReader;
 use npng_crate::{compression::CompressMap, tokio::*, types::metadata::Metadata, *};
 
-fn require_in_png() {
-    let p = Path::new("in.png");
-    assert!(p.exists(), "in.png not found");
-}
+mod common;
+use common::require_in_png;
 
 fn get_test_configs() -> Vec<Config> {
     vec![
         Config {
             save_alpha: true,
             varint: true,
+            ..Config::default()
         },
         Config {
             save_alpha: true,
             varint: false,
+            ..Config::default()
         },
         Config {
             save_alpha: false,
             varint: true,
+            ..Config::default()
         },
         Config {
             save_alpha: false,
             varint: false,
+            ..Config::default()
         },
     ]
 }